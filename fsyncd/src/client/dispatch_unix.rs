@@ -1,8 +1,79 @@
 use common::*;
 use either::Either;
 use libc::c_int;
-use std::fs::OpenOptions;
-use std::path::Path;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/*
+    Files written to are kept open, so streaming a large file doesn't cost an
+    open and a close per write. Renaming, removing or truncating a path
+    closes the files at and under it first, otherwise later writes could go
+    to the file that used to be there.
+*/
+const MAX_OPEN_FILES: usize = 64;
+
+lazy_static! {
+    // Least recently used first
+    static ref OPEN_FILES: Mutex<VecDeque<(PathBuf, Arc<File>)>> =
+        Mutex::new(VecDeque::new());
+}
+
+// The file open at path, made the most recently used
+fn cached(
+    files: &mut VecDeque<(PathBuf, Arc<File>)>,
+    path: &Path,
+) -> Option<Arc<File>> {
+    let i = files.iter().position(|(p, _)| p == path)?;
+    let entry = files.remove(i).unwrap();
+    let file = entry.1.clone();
+    files.push_back(entry);
+    Some(file)
+}
+
+// Calls f with path opened for writing, or with the file already open
+fn with_open_file<F: FnOnce(c_int) -> c_int>(path: PathBuf, f: F) -> c_int {
+    let file = cached(&mut OPEN_FILES.lock().unwrap(), &path);
+    let file = match file {
+        Some(file) => file,
+        None => {
+            // Not under the lock, ops on other files go on while it opens
+            let file = match OpenOptions::new().write(true).open(&path) {
+                Ok(file) => Arc::new(file),
+                Err(e) => return -e.raw_os_error().unwrap(),
+            };
+            let mut files = OPEN_FILES.lock().unwrap();
+            match cached(&mut files, &path) {
+                // Opened by another thread meanwhile
+                Some(file) => file,
+                None => {
+                    if files.len() >= MAX_OPEN_FILES {
+                        files.pop_front();
+                    }
+                    files.push_back((path, file.clone()));
+                    file
+                }
+            }
+        }
+    };
+    // Another thread may drop it from the list meanwhile, it stays open
+    // until f is done
+    f(file.as_raw_fd())
+}
+
+fn close_under(path: &Path) {
+    OPEN_FILES
+        .lock()
+        .unwrap()
+        .retain(|(p, _)| !p.starts_with(path));
+}
+
+// The replica may be rewritten before ops are applied again
+pub fn close_files() {
+    OPEN_FILES.lock().unwrap().clear();
+}
 
 pub unsafe fn dispatch(call: &VFSCall, root: &Path) -> c_int {
     use libc::*;
@@ -26,10 +97,12 @@ pub unsafe fn dispatch(call: &VFSCall, root: &Path) -> c_int {
         }
         VFSCall::unlink { path } => {
             let path = translate_path(&path, root);
+            close_under(&path);
             xmp_unlink(path.into_cstring().as_ptr())
         }
         VFSCall::rmdir { path } => {
             let path = translate_path(&path, root);
+            close_under(&path);
             xmp_rmdir(path.into_cstring().as_ptr())
         }
         VFSCall::symlink {
@@ -48,6 +121,8 @@ pub unsafe fn dispatch(call: &VFSCall, root: &Path) -> c_int {
         VFSCall::rename{ from, to, flags } => {
             let from = translate_path(&from, root);
             let to = translate_path(&to, root);
+            close_under(&from);
+            close_under(&to);
             xmp_rename(
                 from.into_cstring().as_ptr(),
                 to.into_cstring().as_ptr(),
@@ -81,47 +156,36 @@ pub unsafe fn dispatch(call: &VFSCall, root: &Path) -> c_int {
         }
         VFSCall::truncate { path, size } => {
             let path = translate_path(&path, root);
+            close_under(&path);
             xmp_truncate(Either::Left(path.into_cstring().as_ptr()), *size)
         }
-        VFSCall::write { path, buf, offset } => with_file(
-            &translate_path(&path, root),
-            OpenOptions::new().write(true),
-            |fd| xmp_write(buf.as_ptr(), buf.len(), *offset, fd),
-        )
-        .map_err(|e| e.raw_os_error().unwrap())
-        .err_or_ok(),
+        VFSCall::write { path, buf, offset } => {
+            with_open_file(translate_path(&path, root), |fd| {
+                xmp_write(buf.as_ptr(), buf.len(), *offset, fd)
+            })
+        }
         VFSCall::truncating_write {
             path, buf, offset ,
             length,
-        } => with_file(
-            &translate_path(&path, root),
-            OpenOptions::new().write(true),
-            |fd| {
-                let res = xmp_write(buf.as_ptr(), buf.len(), *offset, fd);
-                if res < 0 {
-                    return res;
-                }
-                let tres = xmp_truncate(Either::Right(fd), *length);
-                if tres < 0 {
-                    return tres;
-                }
-                res
-            },
-        )
-        .map_err(|e| e.raw_os_error().unwrap())
-        .err_or_ok(),
+        } => with_open_file(translate_path(&path, root), |fd| {
+            let res = xmp_write(buf.as_ptr(), buf.len(), *offset, fd);
+            if res < 0 {
+                return res;
+            }
+            let tres = xmp_truncate(Either::Right(fd), *length);
+            if tres < 0 {
+                return tres;
+            }
+            res
+        }),
         VFSCall::fallocate {
             path,
             mode,
             offset,
             length,
-        } => with_file(
-            &translate_path(&path, root),
-            OpenOptions::new().write(true),
-            |fd| xmp_fallocate(*mode, *offset, *length, fd),
-        )
-        .map_err(|e| e.raw_os_error().unwrap())
-        .err_or_ok(),
+        } => with_open_file(translate_path(&path, root), |fd| {
+            xmp_fallocate(*mode, *offset, *length, fd)
+        }),
         VFSCall::setxattr {
             path,
             name,
@@ -173,13 +237,116 @@ pub unsafe fn dispatch(call: &VFSCall, root: &Path) -> c_int {
                 &ts as *const timespec,
             )
         }
-        VFSCall::fsync{ path, isdatasync } => with_file(
-            &translate_path(&path, root),
-            OpenOptions::new().write(true),
-            |fd| xmp_fsync(*isdatasync, fd),
-        )
-        .map_err(|e| e.raw_os_error().unwrap())
-        .err_or_ok(),
+        VFSCall::fsync{ path, isdatasync } => {
+            with_open_file(translate_path(&path, root), |fd| {
+                xmp_fsync(*isdatasync, fd)
+            })
+        }
         _ => panic!("Not implemented"),
     }
 }
+
+/*
+    Same as dispatch, but an op whose effect is already there succeeds. Used
+    while replaying ops that may have been applied before a crash or
+    disconnect, the end result is the same as applying them once.
+*/
+pub unsafe fn dispatch_idempotent(call: &VFSCall, root: &Path) -> c_int {
+    use libc::{EEXIST, ENOENT};
+    let res = dispatch(call, root);
+    match call {
+        VFSCall::create { .. }
+        | VFSCall::mknod { .. }
+        | VFSCall::mkdir { .. }
+        | VFSCall::symlink { .. }
+        | VFSCall::link { .. } if res == -EEXIST => 0,
+        VFSCall::unlink { .. } | VFSCall::rmdir { .. } if res == -ENOENT => 0,
+        // Already renamed, as long as the destination is there
+        VFSCall::rename { to, .. }
+            if res == -ENOENT
+                && translate_path(&to, root).symlink_metadata().is_ok() => 0,
+        _ => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "fsyncd-dispatch-{}-{}",
+            process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn path(p: &str) -> Cow<'static, Path> {
+        Cow::Owned(PathBuf::from(p))
+    }
+
+    fn write(root: &Path, p: &str, buf: &[u8]) {
+        let call = VFSCall::write {
+            path: path(p),
+            offset: 0,
+            buf: Cow::Owned(buf.to_vec()),
+        };
+        assert_eq!(unsafe { dispatch(&call, root) }, buf.len() as c_int);
+    }
+
+    fn apply(root: &Path, call: VFSCall) {
+        assert_eq!(unsafe { dispatch(&call, root) }, 0);
+    }
+
+    #[test]
+    fn writes_go_to_the_file_now_at_the_path() {
+        let root = scratch_dir("replaced");
+        for name in &["renamed", "unlinked"] {
+            fs::write(root.join(name), b"").unwrap();
+        }
+        write(&root, "/renamed", b"old");
+        write(&root, "/unlinked", b"old");
+        apply(
+            &root,
+            VFSCall::rename {
+                from: path("/renamed"),
+                to: path("/moved"),
+                flags: 0,
+            },
+        );
+        apply(
+            &root,
+            VFSCall::unlink {
+                path: path("/unlinked"),
+            },
+        );
+        for name in &["renamed", "unlinked"] {
+            fs::write(root.join(name), b"").unwrap();
+            write(&root, &format!("/{}", name), b"new");
+            assert_eq!(fs::read(root.join(name)).unwrap(), b"new");
+        }
+        assert_eq!(fs::read(root.join("moved")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn writes_after_a_truncate_start_over() {
+        let root = scratch_dir("truncated");
+        fs::write(root.join("f"), b"").unwrap();
+        write(&root, "/f", b"old data");
+        apply(
+            &root,
+            VFSCall::truncate {
+                path: path("/f"),
+                size: 0,
+            },
+        );
+        write(&root, "/f", b"new");
+        assert_eq!(fs::read(root.join("f")).unwrap(), b"new");
+    }
+}
@@ -245,3 +245,27 @@ pub unsafe fn dispatch(call: &VFSCall, root: &Path) -> c_int {
         _ => panic!("Windows cannot dispatch {:?}", call),
     }
 }
+
+// See dispatch_idempotent in dispatch_unix.rs
+pub unsafe fn dispatch_idempotent(call: &VFSCall, root: &Path) -> c_int {
+    use winapi::shared::winerror::{
+        ERROR_ALREADY_EXISTS, ERROR_FILE_EXISTS, ERROR_FILE_NOT_FOUND,
+        ERROR_PATH_NOT_FOUND,
+    };
+    let res = dispatch(call, root);
+    let creates = is_variant!(call, VFSCall::create, struct)
+        || is_variant!(call, VFSCall::mkdir, struct)
+        || is_variant!(call, VFSCall::symlink, struct)
+        || is_variant!(call, VFSCall::link, struct);
+    let removes = is_variant!(call, VFSCall::unlink, struct)
+        || is_variant!(call, VFSCall::rmdir, struct);
+    match res as u32 {
+        ERROR_ALREADY_EXISTS | ERROR_FILE_EXISTS if creates => {
+            ERROR_SUCCESS as c_int
+        }
+        ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND if removes => {
+            ERROR_SUCCESS as c_int
+        }
+        _ => res,
+    }
+}
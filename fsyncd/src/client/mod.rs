@@ -1,38 +1,82 @@
+mod position;
+mod scheduler;
+
 metablock!(cfg(target_family = "unix") {
     mod dispatch_unix;
-    pub use self::dispatch_unix::dispatch;
+    pub use self::dispatch_unix::{close_files, dispatch, dispatch_idempotent};
+    use std::path::PathBuf;
+    use server;
 });
 
 metablock!(cfg(target_os = "windows") {
     mod dispatch_windows;
-    pub use self::dispatch_windows::dispatch;
+    pub use self::dispatch_windows::{dispatch, dispatch_idempotent};
     extern crate dokan;
     use self::dokan::AddPrivileges;
     use common::ERROR_SUCCESS;
 });
 
-extern crate threadpool;
-use self::threadpool::ThreadPool;
+pub use self::position::Position;
+use self::scheduler::Scheduler;
 use bincode::{deserialize, serialize};
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use clap::ArgMatches;
+use common::auth::{self, AuthConfig, AuthResponse};
+use common::compress::{Compressor, ZstdBlock};
+use common::epoch;
+use common::filter::{FilterSpec, PathFilter};
+use common::metrics;
+use common::net::{
+    self, Closer, MyRead, MyWrite, Role, RECONNECT_MAX_BACKOFF,
+    RECONNECT_MIN_BACKOFF,
+};
+use common::tls::TlsConfig;
 use common::*;
-use dssc::{chunkmap::ChunkMap, other::ZstdBlock, Compressor};
 use error::{Error, FromError};
-use net2::TcpStreamExt;
+use serde_json;
+use server::OpRef;
 use std::io::{self, Read, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::{fs::File, mem::size_of, net::TcpStream, path::Path};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fs::File, path::Path};
 use url::Url;
 
+const POSITION_PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+// Set once this replica serves replicas of its own
+static mut DOWNSTREAM: bool = false;
+// What this replica authenticates as, for the log
+static mut IDENTITY: Option<String> = None;
+// Newest epoch of a server this replica followed
+static NEWEST_EPOCH: AtomicU64 = AtomicU64::new(0);
+
 pub struct ServerConnection<O: Write + Send + 'static> {
     write: Arc<Mutex<O>>,
     read: Box<dyn Read>,
     rcv_buf: Vec<u8>,
     mode: ClientMode,
+    // Ops up to CaughtUp may have been applied already
+    resuming: bool,
+    pub capabilities: Capabilities,
+    // Epoch the server is primary in
+    pub epoch: u64,
+    // Sequence number the next op must have
+    next_seq: u64,
+    closer: Option<Closer>,
     rt_comp: Option<Box<dyn Compressor>>,
 }
 
+// Why the server ended a connection on purpose
+enum Handover {
+    // Take over as primary, once everything up to last has been applied and
+    // id acknowledged
+    Promoted { id: u64, last: Option<u64> },
+    // The primary moved here
+    Redirected(Url),
+}
+
 fn send_msg<W: Write>(mut write: W, msg: FsyncerMsg) -> Result<(), io::Error> {
     //eprintln!("Sending {} {}", header.op_length, hbuf.len() + buf.len());
     let buf =
@@ -41,6 +85,146 @@ fn send_msg<W: Write>(mut write: W, msg: FsyncerMsg) -> Result<(), io::Error> {
     write.flush()
 }
 
+// Init is framed so the server can reject it whole if it can't understand it
+fn send_framed<W: Write>(
+    write: &mut W,
+    msg: FsyncerMsg,
+) -> Result<(), io::Error> {
+    let buf =
+        serialize(&msg).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    write.write_u32::<BigEndian>(buf.len() as u32)?;
+    write.write_all(&buf[..])?;
+    write.flush()
+}
+
+// Only used during the handshake, before any stream compression is set up
+fn read_framed<R: Read>(
+    read: &mut R,
+) -> Result<FsyncerMsg<'static>, io::Error> {
+    let length = read.read_u32::<BigEndian>()? as usize;
+    let mut buf = vec![0; length];
+    read.read_exact(&mut buf)?;
+    deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+fn is_rejected(e: &Error<io::Error>, reason: &RejectReason) -> bool {
+    e.get_ref().and_then(|e| e.downcast_ref::<RejectReason>()) == Some(reason)
+}
+
+fn rejected(reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Server rejected this client: {}", reason),
+    )
+}
+
+// Prints the answer to a control command on stdout, for scripts to use
+pub fn print_control_info(info: &ControlInfo, json: bool) {
+    if json {
+        match serde_json::to_string_pretty(info) {
+            Ok(out) => println!("{}", out),
+            Err(e) => error!("Failed to encode reply {}", e),
+        }
+        return;
+    }
+    match info {
+        ControlInfo::Done => {}
+        ControlInfo::Clients(clients) => {
+            for c in clients {
+                println!(
+                    "{} {} {:?} compress={} iolimit={} lag={} ops/{} bytes \
+                     queued={} bytes {} silent={}s{}",
+                    c.id,
+                    c.label,
+                    c.mode,
+                    c.compress,
+                    c.iolimit_bps,
+                    c.lag_ops,
+                    c.lag_bytes,
+                    c.queued_bytes,
+                    match (c.alive, c.degraded) {
+                        (false, _) => "dead",
+                        (true, true) => "degraded",
+                        (true, false) => "alive",
+                    },
+                    c.silent_secs,
+                    c.downstream
+                        .as_ref()
+                        .map(|d| format!(" downstream={}", d))
+                        .unwrap_or_default()
+                );
+            }
+        }
+        ControlInfo::Journal(None) => println!("No journal"),
+        ControlInfo::Journal(Some(j)) => {
+            println!(
+                "journal {} of {} bytes ({}%)",
+                j.used,
+                j.size,
+                j.used * 100 / j.size.max(1)
+            );
+            println!(
+                "filestore {} of {} bytes",
+                j.filestore_used, j.filestore_size
+            );
+        }
+    }
+}
+
+fn authenticate<R: Read, W: Write>(
+    auth: &AuthConfig,
+    netin: &mut R,
+    netout: &mut W,
+) -> Result<(), Error<io::Error>> {
+    let challenge = match trace!(read_framed(netin)) {
+        FsyncerMsg::AuthChallenge(challenge) => challenge,
+        FsyncerMsg::AuthRejected(reason) => {
+            return Err(trace_err!(rejected(reason)))
+        }
+        FsyncerMsg::InitResponse(InitResponse::Reject(reason)) => {
+            return Err(trace_err!(rejected(reason.to_string())))
+        }
+        msg => {
+            return Err(trace_err!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected authentication challenge, got {:?}", msg)
+            )))
+        }
+    };
+    let own_challenge = auth::nonce();
+    let mac =
+        trace!(auth::client_proof(&auth.secret, &challenge, &auth.identity));
+    trace!(send_msg(
+        &mut *netout,
+        FsyncerMsg::AuthResponse(AuthResponse {
+            identity: auth.identity.clone(),
+            mac,
+            challenge: own_challenge.clone(),
+        })
+    ));
+    match trace!(read_framed(netin)) {
+        FsyncerMsg::AuthAccepted(proof) => {
+            let expected = trace!(auth::server_proof(
+                &auth.secret,
+                &own_challenge,
+                &auth.identity
+            ));
+            if !auth::verify(&expected, &proof) {
+                return Err(trace_err!(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Server failed to prove it knows the shared secret"
+                )));
+            }
+            Ok(())
+        }
+        FsyncerMsg::AuthRejected(reason) => Err(trace_err!(rejected(reason))),
+        msg => Err(trace_err!(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected authentication result, got {:?}", msg)
+        ))),
+    }
+}
+
 pub struct ConnectionBuilder<
     I: Read + Send + 'static,
     O: Write + Send + 'static,
@@ -48,41 +232,22 @@ pub struct ConnectionBuilder<
     netin: I,
     netout: O,
     init_msg: InitMsg,
+    capabilities: Capabilities,
+    epoch: u64,
+    closer: Option<Closer>,
     rsynced: bool,
 }
 
-impl ConnectionBuilder<Box<dyn Read + Send>, Box<dyn Write + Send>> {
+impl ConnectionBuilder<Box<dyn MyRead>, Box<dyn MyWrite>> {
     pub fn with_url(
         url: &Url,
         nodelay: bool,
         buffer_size: usize,
+        tls: Option<&TlsConfig>,
+        auth: Option<&AuthConfig>,
         init_msg: InitMsg,
     ) -> Result<Self, Error<io::Error>> {
         let (netin, netout) = match url.scheme() {
-            "tcp" => {
-                let mut url = url.clone();
-                if url.port().is_none() {
-                    url.set_port(Some(2323)).unwrap();
-                }
-                let stream = trace!(TcpStream::connect(url));
-                if nodelay {
-                    trace!(stream.set_nodelay(true));
-                }
-                trace!(stream.set_recv_buffer_size(buffer_size));
-                (
-                    Box::new(trace!(stream.try_clone())) as _,
-                    Box::new(stream) as _,
-                )
-            }
-            #[cfg(target_family = "unix")]
-            "unix" => {
-                use std::os::unix::net::UnixStream;
-                let stream = trace!(UnixStream::connect(url.path()));
-                (
-                    Box::new(trace!(stream.try_clone())) as _,
-                    Box::new(stream) as _,
-                )
-            }
             "stdio" => {
                 use std::os::unix::io::FromRawFd;
                 unsafe {
@@ -92,9 +257,29 @@ impl ConnectionBuilder<Box<dyn Read + Send>, Box<dyn Write + Send>> {
                     )
                 }
             }
-            otherwise => panic!("Scheme {} is not supported", otherwise),
+            _ => trace!(net::dial(
+                url,
+                Role::Receiver { nodelay },
+                buffer_size,
+                tls
+            )),
         };
-        ConnectionBuilder::with_net(netin, netout, init_msg)
+        ConnectionBuilder::with_stream(netin, netout, auth, init_msg)
+    }
+
+    // Same as with_net, but the connection can also be closed from another
+    // thread once it is established.
+    pub fn with_stream(
+        netin: Box<dyn MyRead>,
+        netout: Box<dyn MyWrite>,
+        auth: Option<&AuthConfig>,
+        init_msg: InitMsg,
+    ) -> Result<Self, Error<io::Error>> {
+        let closer = trace!(netin.closer());
+        let mut builder =
+            trace!(ConnectionBuilder::with_net(netin, netout, auth, init_msg));
+        builder.closer = Some(closer);
+        Ok(builder)
     }
 }
 
@@ -102,15 +287,70 @@ impl<I: Read + Send + 'static, O: Write + Send + 'static>
     ConnectionBuilder<I, O>
 {
     pub fn with_net(
-        netin: I,
+        mut netin: I,
         mut netout: O,
-        init_msg: InitMsg,
+        auth: Option<&AuthConfig>,
+        mut init_msg: InitMsg,
     ) -> Result<Self, Error<io::Error>> {
-        trace!(send_msg(&mut netout, FsyncerMsg::InitMsg(init_msg.clone())));
+        init_msg.identity = auth.map(|a| a.identity.clone());
+        if auth.is_some() {
+            init_msg.capabilities.insert(Capabilities::AUTH);
+        }
+        trace!(send_framed(
+            &mut netout,
+            FsyncerMsg::InitMsg(init_msg.clone())
+        ));
+        if let Some(auth) = auth {
+            trace!(authenticate(auth, &mut netin, &mut netout));
+        }
+        let (capabilities, epoch) = match trace!(read_framed(&mut netin)) {
+            FsyncerMsg::InitResponse(InitResponse::Accept {
+                version,
+                capabilities,
+                epoch,
+            }) => {
+                info!(
+                    "Server accepted protocol version {} with capabilities \
+                     {:?} in epoch {}",
+                    version, capabilities, epoch
+                );
+                (capabilities, epoch)
+            }
+            FsyncerMsg::InitResponse(InitResponse::Reject(reason)) => {
+                return Err(trace_err!(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    reason
+                )))
+            }
+            FsyncerMsg::AuthRejected(reason) => {
+                return Err(trace_err!(rejected(reason)))
+            }
+            msg => {
+                return Err(trace_err!(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Expected init response, got {:?}", msg)
+                )))
+            }
+        };
+        // Servers check this too, one that was failed over may not know to
+        if epoch < init_msg.epoch
+            && !init_msg.capabilities.contains(Capabilities::PEER)
+        {
+            return Err(trace_err!(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                RejectReason::StaleEpoch {
+                    server: epoch,
+                    seen: init_msg.epoch,
+                }
+            )));
+        }
         Ok(ConnectionBuilder {
             netin,
             netout,
             init_msg,
+            capabilities,
+            epoch,
+            closer: None,
             rsynced: false,
         })
     }
@@ -124,9 +364,9 @@ impl<I: Read + Send + 'static, O: Write + Send + 'static>
                  option"
             )
         }
-        eprintln!("Synchronising using rsync..");
+        info!("Synchronising using rsync..");
         let (ni, no) = trace!(rsync::client(self.netin, self.netout, path));
-        eprintln!("Done!");
+        info!("Done!");
         self.netin = ni;
         self.netout = no;
         self.rsynced = true;
@@ -150,9 +390,7 @@ impl<I: Read + Send + 'static, O: Write + Send + 'static>
         };
 
         let rt_comp: Option<Box<dyn Compressor>> =
-            if self.init_msg.compress.contains(CompMode::RT_DSSC_CHUNKED) {
-                Some(Box::new(ChunkMap::new(0.5)))
-            } else if self.init_msg.compress.contains(CompMode::RT_DSSC_ZSTD) {
+            if self.init_msg.compress.contains(CompMode::RT_ZSTD) {
                 Some(Box::new(ZstdBlock::default()))
             } else {
                 None
@@ -163,11 +401,83 @@ impl<I: Read + Send + 'static, O: Write + Send + 'static>
             read: reader,
             rcv_buf: Vec::with_capacity(32 * 1024),
             mode: self.init_msg.mode,
+            resuming: self.init_msg.resume_from.is_some(),
+            capabilities: self.capabilities,
+            epoch: self.epoch,
+            next_seq: 0,
+            closer: self.closer,
             rt_comp,
         })
     }
 }
 
+/* Passes the op on to the replicas served downstream, before it is applied,
+ * same as the server does. Done as ops are received, so they are forwarded
+ * and journaled in that order, and not in whatever order the dispatch threads
+ * get to them. */
+#[cfg(target_family = "unix")]
+fn forward(call: &VFSCall, op_id: u64) -> Option<OpRef> {
+    if unsafe { DOWNSTREAM } {
+        Some(server::forward_op(call, op_id))
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn forward(_: &VFSCall, _: u64) -> Option<OpRef> {
+    None
+}
+
+// Applies the op, forwarded is what forward returned for it
+fn callback(
+    call: &VFSCall,
+    op_id: u64,
+    client_path: &Path,
+    replaying: bool,
+    forwarded: Option<OpRef>,
+) -> i32 {
+    metrics::OPS.inc(call.name());
+    let e = unsafe {
+        if replaying {
+            dispatch_idempotent(call, client_path)
+        } else {
+            dispatch(call, client_path)
+        }
+    };
+    #[cfg(target_family = "unix")]
+    let failed = e < 0;
+    #[cfg(target_os = "windows")]
+    let failed = e as u32 != ERROR_SUCCESS;
+    if failed {
+        metrics::DISPATCH_FAILURES.inc();
+        error!(
+            client = unsafe { IDENTITY.as_ref() },
+            op = call.name(),
+            path = call.target(),
+            op_id = op_id;
+            "Dispatch failed {:?}({})",
+            io::Error::from_raw_os_error(e),
+            e
+        );
+    }
+    if let Some(opref) = forwarded {
+        server::post_op(opref, e);
+    }
+    e
+}
+
+// Replicas served downstream apply what is forwarded to them idempotently
+// while this one replays
+fn set_replaying(replaying: bool) {
+    #[cfg(target_family = "unix")]
+    {
+        if unsafe { DOWNSTREAM } {
+            server::set_replaying(replaying);
+        }
+    }
+}
+
 impl<O: Write + Send + 'static> ServerConnection<O> {
     fn send_msg(&mut self, msg_data: FsyncerMsg) -> Result<(), io::Error> {
         send_msg(&mut *self.write.lock().unwrap(), msg_data)
@@ -190,7 +500,7 @@ impl<O: Write + Send + 'static> ServerConnection<O> {
 
         let mut dbuf = Vec::new();
         let msgbuf = if let Some(ref mut rt_comp) = self.rt_comp {
-            rt_comp.decode(&self.rcv_buf[size_of::<u32>()..length], &mut dbuf);
+            rt_comp.decode(&self.rcv_buf[..length], &mut dbuf);
             &dbuf[..]
         } else {
             &self.rcv_buf[..length]
@@ -202,9 +512,15 @@ impl<O: Write + Send + 'static> ServerConnection<O> {
         self.send_msg(FsyncerMsg::Cork(0))?;
         loop {
             let msg = self.read_msg()?;
-            if let FsyncerMsg::Cork(tid) = msg {
-                eprintln!("Acknowledging cork");
-                return self.send_msg(FsyncerMsg::AckCork(tid));
+            match msg {
+                FsyncerMsg::Cork(tid) => {
+                    info!("Acknowledging cork");
+                    return self.send_msg(FsyncerMsg::AckCork(tid));
+                }
+                FsyncerMsg::AuthRejected(reason) => {
+                    return Err(rejected(reason))
+                }
+                _ => {}
             }
         }
     }
@@ -213,39 +529,167 @@ impl<O: Write + Send + 'static> ServerConnection<O> {
         self.send_msg(FsyncerMsg::Uncork)
     }
 
-    pub fn process_ops(
+    // Asks the server to hand over to the replica serving on url
+    pub fn promote(&mut self, url: &str) -> Result<(), io::Error> {
+        self.send_msg(FsyncerMsg::Promote(url.to_string()))?;
+        loop {
+            match self.read_msg()? {
+                FsyncerMsg::Promoted(Ok(())) => return Ok(()),
+                FsyncerMsg::Promoted(Err(reason)) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, reason))
+                }
+                FsyncerMsg::AuthRejected(reason) => {
+                    return Err(rejected(reason))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn control(
         &mut self,
-        dispatch_threads: usize,
-        path: &Path,
-    ) -> Result<(), io::Error> {
-        fn callback(call: &VFSCall, client_path: &Path) -> i32 {
-            let e = unsafe { dispatch(call, client_path) };
-            #[cfg(target_family = "unix")]
-            let failed = e < 0;
-            #[cfg(target_os = "windows")]
-            let failed = e as u32 != ERROR_SUCCESS;
-            if failed {
-                eprintln!(
-                    "Dispatch {:?} failed {:?}({})",
-                    call,
-                    io::Error::from_raw_os_error(e),
-                    e
-                );
+        cmd: ControlCmd,
+    ) -> Result<ControlInfo, io::Error> {
+        self.send_msg(FsyncerMsg::Control(cmd))?;
+        loop {
+            match self.read_msg()? {
+                FsyncerMsg::ControlReply(res) => {
+                    return res
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                }
+                FsyncerMsg::AuthRejected(reason) => {
+                    return Err(rejected(reason))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Tells the server everything has been applied, then waits for it to
+    // confirm this replica is the primary now
+    fn confirm_handover(&mut self, id: u64) -> Result<bool, io::Error> {
+        self.send_msg(FsyncerMsg::Ack(AckMsg {
+            retcode: ClientAck::Ack,
+            tid: id,
+        }))?;
+        loop {
+            match self.read_msg()? {
+                FsyncerMsg::Promoted(res) => return Ok(res.is_ok()),
+                FsyncerMsg::NOP => {}
+                msg => {
+                    warn!("Expected handover confirmation, got {:?}", msg);
+                    return Ok(false);
+                }
             }
-            e
         }
+    }
 
+    pub fn process_ops(
+        &mut self,
+        dispatch_threads: usize,
+        path: &Path,
+        position: &Arc<Position>,
+        heartbeat: HeartbeatConfig,
+    ) -> Result<Handover, io::Error> {
         let pool = if dispatch_threads > 1 {
-            Some(ThreadPool::new(dispatch_threads))
+            Some(Scheduler::new(dispatch_threads))
         } else {
             None
         };
 
-        let path = path.to_path_buf();
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let check = self.capabilities.contains(Capabilities::HEARTBEAT);
+            let write = self.write.clone();
+            let closer = self.closer.take();
+            let last_seen = last_seen.clone();
+            let stop = stop.clone();
+            // Ops of this connection are counted from here
+            let position = position.clone();
+            let base = position.done();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(heartbeat.interval);
+                    let silent_for = last_seen.lock().unwrap().elapsed();
+                    if check && silent_for > heartbeat.timeout {
+                        warn!(
+                            "Server has been silent for {:?}, disconnecting",
+                            silent_for
+                        );
+                        if let Some(ref closer) = closer {
+                            closer();
+                        }
+                        return;
+                    }
+                    // Doubles as the heartbeat
+                    let applied = FsyncerMsg::Applied(position.done() - base);
+                    if let Err(e) =
+                        send_msg(&mut *write.lock().unwrap(), applied)
+                    {
+                        error!("Failed to report applied ops {}", e);
+                    }
+                }
+            });
+        }
+
+        let res = self.dispatch_ops(pool.as_ref(), path, position, &last_seen);
+        stop.store(true, Ordering::Relaxed);
+        // Everything received must be applied before the position is used
+        if let Some(pool) = pool {
+            pool.join();
+        }
+        #[cfg(target_family = "unix")]
+        close_files();
+        res
+    }
 
+    // Ops are numbered without gaps, one that went missing can't be applied
+    // around, the replica has to resume from what it has instead
+    fn check_seq(&mut self, seq: u64) -> Result<(), io::Error> {
+        if seq != self.next_seq {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Expected op {} of the stream, got {}",
+                    self.next_seq, seq
+                ),
+            ));
+        }
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    fn dispatch_ops(
+        &mut self,
+        pool: Option<&Arc<Scheduler>>,
+        path: &Path,
+        position: &Arc<Position>,
+        last_seen: &Mutex<Instant>,
+    ) -> Result<Handover, io::Error> {
+        let mut replaying = self.resuming;
+        set_replaying(replaying);
+        let peer = self.capabilities.contains(Capabilities::PEER);
         loop {
-            match self.read_msg() {
-                Ok(FsyncerMsg::SyncOp(call, tid)) => {
+            let msg = self.read_msg();
+            if msg.is_ok() {
+                *last_seen.lock().unwrap() = Instant::now();
+            }
+            // A newer primary connected in the meantime, this one was
+            // failed over and nothing more is taken from it
+            let newest = NEWEST_EPOCH.load(Ordering::SeqCst);
+            if !peer && self.epoch < newest {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    RejectReason::StaleEpoch {
+                        server: self.epoch,
+                        seen: newest,
+                    },
+                ));
+            }
+            match msg {
+                Ok(FsyncerMsg::SyncOp(call, tid, op_id, seq)) => {
+                    self.check_seq(seq)?;
                     if self.mode == ClientMode::MODE_SEMISYNC {
                         self.send_msg(FsyncerMsg::Ack(AckMsg {
                             retcode: ClientAck::Ack,
@@ -256,38 +700,106 @@ impl<O: Write + Send + 'static> ServerConnection<O> {
                     let need_ack = self.mode == ClientMode::MODE_SYNC
                         || self.mode == ClientMode::MODE_FLUSHSYNC;
                     let write = self.write.clone();
-                    let path = path.clone();
+                    let path = path.to_path_buf();
+                    let position = position.clone();
+                    let op = position.start(op_id);
+                    let paths = scheduler::paths(&call);
+                    let forwarded = forward(&call, op_id);
                     let f = move || {
-                        let res = (callback)(&call, &path);
+                        let res =
+                            callback(&call, op_id, &path, replaying, forwarded);
+                        position.finish(op);
                         if need_ack {
-                            send_msg(
+                            // Connection may be gone, the op will be resent
+                            if let Err(e) = send_msg(
                                 &mut *write.lock().unwrap(),
                                 FsyncerMsg::Ack(AckMsg {
                                     retcode: ClientAck::RetCode(res),
                                     tid,
                                 }),
-                            )
-                            .expect("Failed to send ack");
+                            ) {
+                                error!("Failed to send ack {}", e);
+                            }
                         }
                     };
-                    if let Some(pool) = pool.as_ref() {
-                        pool.execute(f);
+                    if let Some(pool) = pool {
+                        pool.execute(paths, f);
                     } else {
                         f();
                     }
                 }
-                Ok(FsyncerMsg::AsyncOp(call)) => {
+                Ok(FsyncerMsg::AsyncOp(call, op_id, seq)) => {
+                    self.check_seq(seq)?;
                     // TODO check return status
                     //debug!(call);
-                    let _res = callback(&call, &path);
+                    let op = position.start(op_id);
+                    let forwarded = forward(&call, op_id);
+                    if let Some(pool) = pool {
+                        let paths = scheduler::paths(&call);
+                        let path = path.to_path_buf();
+                        let position = position.clone();
+                        pool.execute(paths, move || {
+                            let _res = callback(
+                                &call, op_id, &path, replaying, forwarded,
+                            );
+                            position.finish(op);
+                        });
+                    } else {
+                        let _res =
+                            callback(&call, op_id, path, replaying, forwarded);
+                        position.finish(op);
+                    }
+                }
+                #[cfg(target_family = "unix")]
+                Ok(FsyncerMsg::PeerOp(call, op_id, version, seq)) => {
+                    self.check_seq(seq)?;
+                    let op = position.start(op_id);
+                    let _res = server::apply_peer_op(&call, &version);
+                    position.finish(op);
+                }
+                Ok(FsyncerMsg::Handover(id, last)) => {
+                    return Ok(Handover::Promoted { id, last })
+                }
+                Ok(FsyncerMsg::Redirect(url)) => match Url::parse(&url) {
+                    Ok(url) => return Ok(Handover::Redirected(url)),
+                    Err(e) => warn!("Ignoring redirect to {} {}", url, e),
+                },
+                Ok(FsyncerMsg::CaughtUp(op_id)) => {
+                    info!("Caught up with server at op {:?}", op_id);
+                    if let Some(pool) = pool {
+                        pool.join();
+                    }
+                    position.caught_up(op_id);
+                    replaying = false;
+                    set_replaying(false);
+                }
+                // Ops the server passes on from its own server, which it
+                // may have passed on before
+                Ok(FsyncerMsg::Replaying(r)) => {
+                    replaying = r;
+                    set_replaying(r);
                 }
                 Ok(FsyncerMsg::Cork(tid)) => {
-                    eprintln!("Received cork request");
+                    info!("Received cork request");
                     self.send_msg(FsyncerMsg::AckCork(tid))?
                 }
                 Ok(FsyncerMsg::NOP) | Ok(FsyncerMsg::Uncork) => {} /* Nothing, safe to ingore */
+                Ok(FsyncerMsg::AuthRejected(reason)) => {
+                    return Err(rejected(reason))
+                }
+                Ok(FsyncerMsg::Diverged(reason)) => {
+                    // Nothing applied may count towards resuming anymore
+                    if let Some(pool) = pool {
+                        pool.join();
+                    }
+                    position.clear(path)?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Replica diverged from the server, {}", reason),
+                    ));
+                }
                 Err(err) => return Err(err),
-                msg => eprintln!(
+                msg => warn!(
                     "Unexpected message for current client state {:?}",
                     msg
                 ),
@@ -309,11 +821,11 @@ fn parse_options(client_matches: &ArgMatches) -> InitMsg {
 
     match client_matches.value_of("stream-compressor").unwrap() {
         "default" | "lz4" => {
-            eprintln!("Using a LZ4 stream compressor");
+            info!("Using a LZ4 stream compressor");
             compress.insert(CompMode::STREAM_LZ4)
         }
         "zstd" => {
-            eprintln!("Using a ZSTD stream compressor");
+            info!("Using a ZSTD stream compressor");
             compress.insert(CompMode::STREAM_ZSTD)
         }
         _ => (),
@@ -321,12 +833,8 @@ fn parse_options(client_matches: &ArgMatches) -> InitMsg {
 
     match client_matches.value_of("rt-compressor").unwrap() {
         "default" | "zstd" => {
-            eprintln!("Using a RT_DSSC_ZSTD realtime compressor");
-            compress.insert(CompMode::RT_DSSC_ZSTD)
-        }
-        "chunked" => {
-            eprintln!("Using a RT_DSSC_CHUNKED realtime compressor");
-            compress.insert(CompMode::RT_DSSC_CHUNKED)
+            info!("Using a RT_ZSTD realtime compressor");
+            compress.insert(CompMode::RT_ZSTD)
         }
         "none" | _ => (),
     }
@@ -342,36 +850,199 @@ fn parse_options(client_matches: &ArgMatches) -> InitMsg {
             .expect("Invalid format for iolimit");
 
     InitMsg {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::HEARTBEAT,
         mode,
         dsthash: 0,
         compress,
         iolimit_bps,
         options,
+        identity: None,
+        resume_from: None,
+        filter: FilterSpec::from_matches(client_matches),
+        downstream: client_matches.value_of("downstream").map(String::from),
+        epoch: 0,
     }
 }
 
-pub fn client_main(matches: ArgMatches) {
-    let url = Url::parse(matches.value_of("url").unwrap())
-        .expect("Invalid url specified");
+fn persist_position(position: &Arc<Position>, path: &Path) {
+    let position = position.clone();
+    let path = path.to_path_buf();
+    thread::spawn(move || loop {
+        thread::sleep(POSITION_PERSIST_INTERVAL);
+        if let Err(e) = position.persist(&path) {
+            error!("Failed to persist replica position {}", e);
+        }
+    });
+}
+
+// Records the epoch of the server root now follows, peers don't have one
+fn see_epoch<O: Write + Send + 'static>(
+    connection: &ServerConnection<O>,
+    root: &Path,
+) -> Result<(), io::Error> {
+    if connection.capabilities.contains(Capabilities::PEER) {
+        return Ok(());
+    }
+    epoch::see(root, connection.epoch)?;
+    NEWEST_EPOCH.fetch_max(connection.epoch, Ordering::SeqCst);
+    Ok(())
+}
+
+/* Follows the server of a peer, what it sends is applied to this node's
+ * backing store through this node's server, so its replicas get it too. Like
+ * a client without --rsync, the two trees have to match when first
+ * connecting, after that it resumes from where it left off. */
+#[cfg(target_family = "unix")]
+pub fn follow_peer(
+    url: Url,
+    tls: Option<TlsConfig>,
+    auth: Option<AuthConfig>,
+    buffer_size: usize,
+    heartbeat: HeartbeatConfig,
+    path: PathBuf,
+    filter: FilterSpec,
+) {
+    let init_msg = InitMsg {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::HEARTBEAT | Capabilities::PEER,
+        mode: ClientMode::MODE_ASYNC,
+        dsthash: 0,
+        compress: CompMode::STREAM_LZ4,
+        iolimit_bps: 0,
+        options: Options::empty(),
+        identity: None,
+        resume_from: None,
+        filter,
+        downstream: None,
+        epoch: 0,
+    };
+    follow(url, init_msg, tls, auth, buffer_size, heartbeat, path)
+}
+
+// Same as follow_peer, for a primary that was demoted, following the new one
+#[cfg(target_family = "unix")]
+pub fn follow_primary(
+    url: Url,
+    tls: Option<TlsConfig>,
+    auth: Option<AuthConfig>,
+    buffer_size: usize,
+    heartbeat: HeartbeatConfig,
+    path: PathBuf,
+) {
+    let init_msg = InitMsg {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::HEARTBEAT,
+        mode: ClientMode::MODE_ASYNC,
+        dsthash: 0,
+        compress: CompMode::STREAM_LZ4,
+        iolimit_bps: 0,
+        options: Options::empty(),
+        identity: None,
+        resume_from: None,
+        filter: FilterSpec::default(),
+        downstream: None,
+        epoch: 0,
+    };
+    follow(url, init_msg, tls, auth, buffer_size, heartbeat, path)
+}
+
+#[cfg(target_family = "unix")]
+fn follow(
+    mut url: Url,
+    init_msg: InitMsg,
+    tls: Option<TlsConfig>,
+    auth: Option<AuthConfig>,
+    buffer_size: usize,
+    heartbeat: HeartbeatConfig,
+    path: PathBuf,
+) {
+    let peer = init_msg.capabilities.contains(Capabilities::PEER);
+    // Peers all write into the same root, each is resumed from on its own
+    let key = if peer { url.to_string() } else { String::new() };
+    let position = Arc::new(
+        Position::load_keyed(&path, &key).expect("Failed to load position"),
+    );
+    persist_position(&position, &path);
+
+    let mut resume = position.applied();
+    let mut backoff = RECONNECT_MIN_BACKOFF;
+    loop {
+        let mut init = init_msg.clone();
+        init.resume_from = resume;
+        if !peer {
+            init.epoch = epoch::newest(&path).expect("Failed to load epoch");
+        }
+        if resume.is_none() {
+            let filter = PathFilter::new(&init.filter).expect("Invalid filter");
+            init.dsthash = hash_metadata(&path, &filter).expect("Hash failed");
+        }
+        let connection = ConnectionBuilder::with_url(
+            &url,
+            false,
+            buffer_size,
+            tls.as_ref(),
+            auth.as_ref(),
+            init,
+        )
+        .and_then(|builder| builder.build());
+        match connection {
+            Ok(ref connection)
+                if peer
+                    && !connection
+                        .capabilities
+                        .contains(Capabilities::PEER) =>
+            {
+                warn!("Peer {} does not accept writes from peers", url)
+            }
+            Ok(mut connection) => {
+                backoff = RECONNECT_MIN_BACKOFF;
+                info!("Following {}, resuming from {:?}", url, resume);
+                if let Err(e) = see_epoch(&connection, &path) {
+                    error!("Failed to record epoch {}", e);
+                }
+                match connection.process_ops(1, &path, &position, heartbeat) {
+                    Ok(Handover::Redirected(to)) => {
+                        info!("Primary moved to {}", to);
+                        url = to;
+                    }
+                    Ok(Handover::Promoted { .. }) => {
+                        warn!("Asked to take over, but not serving replicas")
+                    }
+                    Err(e) => warn!("Lost connection to {} {}", url, e),
+                }
+                resume = position.applied();
+                continue;
+            }
+            Err(ref e) if is_rejected(e, &RejectReason::ResumeUnavailable) => {
+                // Only works out if nothing changed on either side since
+                warn!("{}, checking if the trees still match", **e);
+                resume = None;
+                continue;
+            }
+            Err(e) => error!("Failed to connect to {} {}", url, e),
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
+}
 
+pub fn client_main(matches: ArgMatches) {
     let client_matches = matches.subcommand_matches("client").unwrap();
 
+    let mut url = Url::parse(client_matches.value_of("url").unwrap())
+        .expect("Invalid url specified");
+
     let mut init_msg = parse_options(client_matches);
-    let buffer_size = parse_human_size(matches.value_of("buffer").unwrap())
-        .expect("Buffer size format incorrect");
+    let buffer_size =
+        parse_human_size(client_matches.value_of("buffer").unwrap())
+            .expect("Buffer size format incorrect");
 
     let dispatch_threads = client_matches
         .value_of("threads")
         .map(|v| v.parse().expect("Invalid thread number"))
         .unwrap();
 
-    if dispatch_threads != 1 && init_msg.mode != ClientMode::MODE_SYNC {
-        panic!(
-            "Only synchronous mode is compatible with multiple dispatch \
-             threads"
-        );
-    }
-
     let client_path = canonize_path(Path::new(
         client_matches
             .value_of("mount-path")
@@ -379,10 +1050,19 @@ pub fn client_main(matches: ArgMatches) {
     ))
     .expect("Failed to normalize path");
 
-    eprintln!("Calculating destination hash...");
+    let position = Arc::new(
+        Position::load(&client_path).expect("Failed to load replica position"),
+    );
+    // A replica that was following before can pick up where it left off
+    let mut resume = position.applied();
 
-    init_msg.dsthash = hash_metadata(&client_path).expect("Hash failed");
-    eprintln!("Destinaton hash is {:x}", init_msg.dsthash);
+    if resume.is_none() && !init_msg.options.contains(Options::INITIAL_RSYNC) {
+        info!("Calculating destination hash...");
+        let filter = PathFilter::new(&init_msg.filter).expect("Invalid filter");
+        init_msg.dsthash =
+            hash_metadata(&client_path, &filter).expect("Hash failed");
+        info!("Destinaton hash is {:x}", init_msg.dsthash);
+    }
 
     #[cfg(target_os = "windows")]
     unsafe {
@@ -391,24 +1071,149 @@ pub fn client_main(matches: ArgMatches) {
         }
     }
 
-    let need_rsync = init_msg.options.contains(Options::INITIAL_RSYNC);
-    let mut builder = ConnectionBuilder::with_url(
-        &url,
-        init_msg.mode != ClientMode::MODE_ASYNC,
-        buffer_size,
-        init_msg,
-    )
-    .expect("Failed to connect to client");
-    if need_rsync {
-        builder = builder
-            .rsync(&client_path)
-            .expect("Failed to rsync with server")
-    }
-    let mut client =
-        builder.build().expect("Failed to create server connection");
-
-    eprintln!("Connected to {}", url);
-    client
-        .process_ops(dispatch_threads, &client_path)
-        .expect("Stopped processing ops!");
+    let tls = TlsConfig::from_matches(client_matches);
+    let heartbeat = HeartbeatConfig::from_matches(client_matches);
+    let auth = AuthConfig::from_matches(client_matches)
+        .expect("Failed to load authentication secret");
+    unsafe { IDENTITY = auth.as_ref().map(|a| a.identity.clone()) };
+    if let Some(addr) = client_matches.value_of("metrics") {
+        metrics::serve(addr).expect("Failed to serve metrics");
+    }
+
+    // The server connects to us instead, for when it can't be reached
+    let listener = if client_matches.is_present("listen") {
+        Some(net::bind(&url, tls.as_ref()).expect("Failed to listen"))
+    } else {
+        None
+    };
+
+    let nodelay = init_msg.mode != ClientMode::MODE_ASYNC;
+    let connect = |url: &Url, init_msg: InitMsg| {
+        let need_rsync = init_msg.options.contains(Options::INITIAL_RSYNC);
+        let mut builder = match listener {
+            Some(ref listener) => {
+                info!("Waiting for the server to connect on {}", url);
+                let (accepted, addr) =
+                    trace!(listener
+                        .accept(Role::Receiver { nodelay }, buffer_size));
+                info!("Server connected from {}", addr);
+                let (netin, netout) = trace!(accepted());
+                trace!(ConnectionBuilder::with_stream(
+                    netin,
+                    netout,
+                    auth.as_ref(),
+                    init_msg,
+                ))
+            }
+            None => trace!(ConnectionBuilder::with_url(
+                url,
+                nodelay,
+                buffer_size,
+                tls.as_ref(),
+                auth.as_ref(),
+                init_msg,
+            )),
+        };
+        if need_rsync {
+            trace!(position.clear(&client_path));
+            builder = trace!(builder.rsync(&client_path));
+            #[cfg(target_family = "unix")]
+            {
+                if unsafe { DOWNSTREAM } {
+                    server::drop_clients("replica was resynchronised");
+                }
+            }
+        }
+        builder.build()
+    };
+
+    persist_position(&position, &client_path);
+
+    // Once the replica has been connected, it can't be hash checked anymore,
+    // it either resumes or is resynchronised.
+    let mut resync = false;
+    let mut backoff = RECONNECT_MIN_BACKOFF;
+    loop {
+        let mut init = init_msg.clone();
+        if resume.is_some() {
+            init.options.remove(Options::INITIAL_RSYNC);
+        } else if resync {
+            init.options.insert(Options::INITIAL_RSYNC);
+        }
+        init.resume_from = resume;
+        init.epoch = epoch::newest(&client_path).expect("Failed to load epoch");
+        match connect(&url, init) {
+            Ok(mut client) => {
+                backoff = RECONNECT_MIN_BACKOFF;
+                info!("Connected to {}, resuming from {:?}", url, resume);
+                see_epoch(&client, &client_path)
+                    .expect("Failed to record epoch");
+                #[cfg(target_family = "unix")]
+                {
+                    // Replicas of this one check against the same epoch
+                    server::EPOCH.fetch_max(client.epoch, Ordering::SeqCst);
+                }
+                // Only once there is something worth replicating
+                #[cfg(target_family = "unix")]
+                unsafe {
+                    if !DOWNSTREAM && client_matches.is_present("downstream") {
+                        server::serve_downstream(client_matches, &client_path)
+                            .expect("Failed to serve downstream replicas");
+                        DOWNSTREAM = true;
+                    }
+                }
+                match client.process_ops(
+                    dispatch_threads,
+                    &client_path,
+                    &position,
+                    heartbeat,
+                ) {
+                    Ok(Handover::Promoted { id, last }) => {
+                        match client.confirm_handover(id) {
+                            Ok(true) => {
+                                #[cfg(target_family = "unix")]
+                                {
+                                    server::take_over(&client_path, last)
+                                        .expect("Failed to take over");
+                                    return;
+                                }
+                            }
+                            Ok(false) => info!("Handover was cancelled"),
+                            Err(e) => error!("Handover failed {}", e),
+                        }
+                    }
+                    Ok(Handover::Redirected(_)) if listener.is_some() => {
+                        info!("Primary moved, still waiting for it to connect");
+                    }
+                    Ok(Handover::Redirected(to)) => {
+                        info!("Primary moved to {}", to);
+                        url = to;
+                    }
+                    Err(e) => warn!("Lost connection to server {}", e),
+                }
+            }
+            Err(ref e) if is_rejected(e, &RejectReason::ResumeUnavailable) => {
+                warn!("{}, resynchronising", **e);
+                resume = None;
+                resync = true;
+                continue;
+            }
+            // Reconnecting won't change the tree, someone has to
+            Err(ref e) if is_rejected(e, &RejectReason::HashMismatch) => {
+                error!(
+                    "{}, start the replica with --rsync to overwrite it",
+                    **e
+                );
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Failed to connect to {} {}", url, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                continue;
+            }
+        }
+        resume = position.applied();
+        resync = true;
+    }
 }
@@ -0,0 +1,176 @@
+use common::xattr;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+const POSITION: &str = "fsyncer.position";
+
+/*
+    Tracks the id of the last op this replica has applied, which is where it
+    resumes from after reconnecting. With multiple dispatch threads ops can
+    finish out of order, so an op only counts as applied once every op
+    received before it has been applied too.
+
+    The position is persisted under the replica root (an xattr, or an
+    alternate data stream on Windows), but only after the filesystem has been
+    synced, so it never claims more than what survives a crash. A node that
+    follows several servers into the same root, its peers, keeps a position
+    for each under its own key.
+*/
+pub struct Position {
+    inner: Mutex<PositionInner>,
+    // Held while the persisted position is written, taken before inner
+    store: Mutex<()>,
+    // Appended to the xattr or stream name, empty for the one server
+    key: String,
+}
+
+struct PositionInner {
+    applied: Option<u64>,
+    persisted: Option<u64>,
+    // Ops in the order they were received, with whether they are done
+    pending: VecDeque<(u64, bool)>,
+    next_seq: u64,
+    // Counts clears, a persist that started before one doesn't write
+    generation: u64,
+}
+
+// Ticket for an op being applied, hand it back to Position::finish
+pub struct InFlight(u64);
+
+impl Position {
+    // Starts from the position last persisted under root, if any
+    pub fn load(root: &Path) -> Result<Self, io::Error> {
+        Position::load_keyed(root, "")
+    }
+
+    // Same as load, for one of several servers followed into root
+    pub fn load_keyed(root: &Path, key: &str) -> Result<Self, io::Error> {
+        let key = if key.is_empty() {
+            String::new()
+        } else {
+            format!(".{}", key)
+        };
+        let applied = read_position(root, &key)?;
+        Ok(Position {
+            inner: Mutex::new(PositionInner {
+                applied,
+                persisted: applied,
+                pending: VecDeque::new(),
+                next_seq: 0,
+                generation: 0,
+            }),
+            store: Mutex::new(()),
+            key,
+        })
+    }
+
+    // Flushes root's filesystem, then records the applied position under it
+    pub fn persist(&self, root: &Path) -> Result<(), io::Error> {
+        let (applied, generation) = {
+            let inner = self.inner.lock().unwrap();
+            if inner.applied == inner.persisted {
+                return Ok(());
+            }
+            (inner.applied, inner.generation)
+        };
+        let dir = File::open(root)?;
+        sync_fs(&dir)?;
+        let _store = self.store.lock().unwrap();
+        if self.inner.lock().unwrap().generation != generation {
+            // Cleared since, what was applied no longer means anything
+            return Ok(());
+        }
+        write_position(root, &self.key, applied)?;
+        dir.sync_all()?;
+        self.inner.lock().unwrap().persisted = applied;
+        Ok(())
+    }
+
+    // The replica is about to be overwritten, whatever was applied no longer
+    // means anything
+    pub fn clear(&self, root: &Path) -> Result<(), io::Error> {
+        let _store = self.store.lock().unwrap();
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        write_position(root, &self.key, None)?;
+        inner.applied = None;
+        inner.persisted = None;
+        Ok(())
+    }
+
+    pub fn applied(&self) -> Option<u64> {
+        self.inner.lock().unwrap().applied
+    }
+
+    // Ops received so far that have been applied, counting those before any
+    // still in flight only
+    pub fn done(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.next_seq - inner.pending.len() as u64
+    }
+
+    pub fn start(&self, op_id: u64) -> InFlight {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.pending.push_back((op_id, false));
+        InFlight(seq)
+    }
+
+    pub fn finish(&self, op: InFlight) {
+        let mut inner = self.inner.lock().unwrap();
+        let first = inner.next_seq - inner.pending.len() as u64;
+        inner.pending[(op.0 - first) as usize].1 = true;
+        while let Some(&(op_id, true)) = inner.pending.front() {
+            inner.applied = Some(op_id);
+            inner.pending.pop_front();
+        }
+    }
+
+    // Server says everything up to op_id has been sent, and it all has been
+    // applied by the time this is called.
+    pub fn caught_up(&self, op_id: Option<u64>) {
+        let mut inner = self.inner.lock().unwrap();
+        assert!(inner.pending.is_empty());
+        inner.applied = op_id;
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn sync_fs(dir: &File) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn sync_fs(_: &File) -> Result<(), io::Error> {
+    // Windows has no equivalent for a directory, FlushFileBuffers on the
+    // volume requires administrator and flushes everything anyway.
+    Ok(())
+}
+
+// Name of the position kept for key under the root
+fn name(key: &str) -> String {
+    format!("{}{}", POSITION, key)
+}
+
+fn read_position(root: &Path, key: &str) -> Result<Option<u64>, io::Error> {
+    xattr::get(root, &name(key))
+}
+
+fn write_position(
+    root: &Path,
+    key: &str,
+    op_id: Option<u64>,
+) -> Result<(), io::Error> {
+    match op_id {
+        Some(op_id) => xattr::set(root, &name(key), op_id),
+        None => xattr::remove(root, &name(key)),
+    }
+}
@@ -0,0 +1,246 @@
+extern crate threadpool;
+
+use self::threadpool::ThreadPool;
+use common::VFSCall;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+// Ops received but not applied yet, past it the dispatcher stops reading
+const MAX_UNFINISHED: usize = 1024;
+
+/*
+    Applies ops on a pool of threads while keeping the order where it
+    matters. An op waits for every op received before it that changes the
+    same path, something above it or something under it, including the source
+    of a rename or a link, which covers creating in a directory after making
+    it, removing it after emptying it, and renaming it while its contents are
+    written. Ops on unrelated paths run in parallel.
+
+    Hard links to the same file under unrelated paths are not known here,
+    writes through them may be applied in a different order than they were
+    made.
+*/
+pub struct Scheduler {
+    state: Mutex<State>,
+    cvar: Condvar,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Task {
+    paths: Vec<PathBuf>,
+    // Run once every op it waits for is done
+    job: Option<Job>,
+    waiting_for: usize,
+    // Ops received after this one, that wait for it
+    dependents: Vec<u64>,
+}
+
+struct State {
+    pool: ThreadPool,
+    // Received and not done yet, ids count up in the order received
+    unfinished: HashMap<u64, Task>,
+    next: u64,
+}
+
+impl Scheduler {
+    pub fn new(threads: usize) -> Arc<Self> {
+        Arc::new(Scheduler {
+            state: Mutex::new(State {
+                pool: ThreadPool::new(threads),
+                unfinished: HashMap::new(),
+                next: 0,
+            }),
+            cvar: Condvar::new(),
+        })
+    }
+
+    // Runs f once every earlier op on paths related to these is done, blocks
+    // while too many ops are waiting
+    pub fn execute<F>(self: &Arc<Self>, paths: Vec<PathBuf>, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        while state.unfinished.len() >= MAX_UNFINISHED {
+            state = self.cvar.wait(state).unwrap();
+        }
+        let id = state.next;
+        state.next += 1;
+        let mut waiting_for = 0;
+        for task in state.unfinished.values_mut() {
+            if conflicts(&task.paths, &paths) {
+                task.dependents.push(id);
+                waiting_for += 1;
+            }
+        }
+        state.unfinished.insert(
+            id,
+            Task {
+                paths,
+                job: Some(Box::new(f)),
+                waiting_for,
+                dependents: Vec::new(),
+            },
+        );
+        if waiting_for == 0 {
+            self.start(&mut state, id);
+        }
+    }
+
+    // Waits for every op given so far to be done
+    pub fn join(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.unfinished.is_empty() {
+            state = self.cvar.wait(state).unwrap();
+        }
+    }
+
+    fn start(self: &Arc<Self>, state: &mut State, id: u64) {
+        let job = state
+            .unfinished
+            .get_mut(&id)
+            .and_then(|t| t.job.take())
+            .expect("Op started twice");
+        let done = Done(self.clone(), id);
+        state.pool.execute(move || {
+            // Finished when dropped, even if the job panics
+            let _done = done;
+            job();
+        });
+    }
+
+    fn finish(self: &Arc<Self>, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let task = state.unfinished.remove(&id).expect("Unknown op finished");
+        for dependent in task.dependents {
+            let ready = {
+                let dependent = state.unfinished.get_mut(&dependent).unwrap();
+                dependent.waiting_for -= 1;
+                dependent.waiting_for == 0
+            };
+            if ready {
+                self.start(&mut state, dependent);
+            }
+        }
+        self.cvar.notify_all();
+    }
+}
+
+// Finishes the op once dropped, so one that panics doesn't hold up the ops
+// waiting for it or join
+struct Done(Arc<Scheduler>, u64);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.0.finish(self.1);
+    }
+}
+
+// Paths in the tree call changes, a symlink's from is only its contents
+pub fn paths(call: &VFSCall) -> Vec<PathBuf> {
+    let mut paths = vec![call.target().to_path_buf()];
+    match call {
+        VFSCall::rename { from, .. } | VFSCall::link { from, .. } => {
+            paths.push(from.to_path_buf())
+        }
+        _ => (),
+    }
+    paths
+}
+
+fn conflicts(a: &[PathBuf], b: &[PathBuf]) -> bool {
+    fn related(a: &Path, b: &Path) -> bool {
+        a.starts_with(b) || b.starts_with(a)
+    }
+    a.iter().any(|a| b.iter().any(|b| related(a, b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn list(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn related_paths_conflict() {
+        assert!(conflicts(&list(&["/a"]), &list(&["/a"])));
+        assert!(conflicts(&list(&["/a"]), &list(&["/a/b/c"])));
+        assert!(conflicts(&list(&["/a/b"]), &list(&["/a"])));
+        // The source of a rename
+        assert!(conflicts(&list(&["/b", "/a/x"]), &list(&["/a"])));
+        assert!(!conflicts(&list(&["/a"]), &list(&["/b"])));
+        // Components, not prefixes
+        assert!(!conflicts(&list(&["/a"]), &list(&["/ab"])));
+        assert!(!conflicts(&list(&["/a/b"]), &list(&["/a/c", "/d"])));
+    }
+
+    #[test]
+    fn ops_on_related_paths_run_in_order() {
+        let scheduler = Scheduler::new(4);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (i, path) in ["/d", "/d/f", "/d/f", "/d"].iter().enumerate() {
+            let order = order.clone();
+            scheduler.execute(list(&[path]), move || {
+                // The later ones would overtake it if they didn't wait
+                thread::sleep(Duration::from_millis(10 * (4 - i as u64)));
+                order.lock().unwrap().push(i);
+            });
+        }
+        scheduler.join();
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn ops_on_unrelated_paths_run_in_parallel() {
+        let scheduler = Scheduler::new(2);
+        let (tx, rx) = mpsc::channel();
+        let waited = Arc::new(Mutex::new(None));
+        {
+            let waited = waited.clone();
+            scheduler.execute(list(&["/a"]), move || {
+                let res = rx.recv_timeout(Duration::from_secs(10));
+                *waited.lock().unwrap() = Some(res.is_ok());
+            });
+        }
+        scheduler.execute(list(&["/b"]), move || tx.send(()).unwrap());
+        scheduler.join();
+        assert_eq!(*waited.lock().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn join_waits_for_everything() {
+        let scheduler = Scheduler::new(2);
+        let done = Arc::new(Mutex::new(0));
+        for path in &["/a", "/a/b", "/c"] {
+            let done = done.clone();
+            scheduler.execute(list(&[path]), move || {
+                thread::sleep(Duration::from_millis(10));
+                *done.lock().unwrap() += 1;
+            });
+        }
+        scheduler.join();
+        assert_eq!(*done.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn panicking_ops_still_finish() {
+        let scheduler = Scheduler::new(2);
+        let ran = Arc::new(Mutex::new(false));
+        scheduler.execute(list(&["/a"]), || panic!("Failed to apply"));
+        {
+            let ran = ran.clone();
+            scheduler.execute(list(&["/a"]), move || {
+                *ran.lock().unwrap() = true;
+            });
+        }
+        scheduler.join();
+        assert!(*ran.lock().unwrap());
+    }
+}
@@ -0,0 +1,186 @@
+use clap::ArgMatches;
+use error::{Error, FromError};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+
+pub const NONCE_SIZE: usize = 32;
+
+// Different labels for each direction, so a response can never be reflected
+// back as a valid proof for the other side.
+const CLIENT_LABEL: &[u8] = b"fsyncer-client";
+const SERVER_LABEL: &[u8] = b"fsyncer-server";
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct AuthResponse {
+    pub identity: String,
+    pub mac: Vec<u8>,
+    pub challenge: Vec<u8>,
+}
+
+// Keys the server accepts, either one secret shared by every replica or a
+// secret per replica identity.
+pub enum AuthKeys {
+    Shared(Vec<u8>),
+    PerReplica(HashMap<String, Vec<u8>>),
+}
+
+pub struct AuthConfig {
+    pub identity: String,
+    pub secret: Vec<u8>,
+}
+
+fn read_secret(path: &str) -> Result<Vec<u8>, Error<io::Error>> {
+    let mut secret = trace!(fs::read(path));
+    while secret.last() == Some(&b'\n') || secret.last() == Some(&b'\r') {
+        secret.pop();
+    }
+    if secret.is_empty() {
+        return Err(trace_err!(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Secret in {} is empty", path)
+        )));
+    }
+    Ok(secret)
+}
+
+impl AuthKeys {
+    pub fn from_matches(
+        matches: &ArgMatches,
+    ) -> Result<Option<Self>, Error<io::Error>> {
+        AuthKeys::from_paths(
+            matches.value_of("auth-secret"),
+            matches.value_of("auth-keys"),
+        )
+    }
+
+    // Either a file with the shared secret, or one with per replica keys
+    pub fn from_paths(
+        secret: Option<&str>,
+        keys: Option<&str>,
+    ) -> Result<Option<Self>, Error<io::Error>> {
+        if let Some(path) = secret {
+            return Ok(Some(AuthKeys::Shared(trace!(read_secret(path)))));
+        }
+        let path = match keys {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        // One "<identity> <secret>" per line, # starts a comment
+        let mut keys = HashMap::new();
+        for line in trace!(fs::read_to_string(path)).lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            match (parts.next(), parts.next().map(str::trim)) {
+                (Some(identity), Some(secret)) if !secret.is_empty() => {
+                    keys.insert(
+                        identity.to_string(),
+                        secret.as_bytes().to_vec(),
+                    );
+                }
+                _ => {
+                    return Err(trace_err!(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Malformed line in {}: {}", path, line)
+                    )))
+                }
+            }
+        }
+        Ok(Some(AuthKeys::PerReplica(keys)))
+    }
+
+    pub fn secret_for(&self, identity: &str) -> Option<&[u8]> {
+        match self {
+            AuthKeys::Shared(secret) => Some(secret),
+            AuthKeys::PerReplica(keys) => keys.get(identity).map(|k| &k[..]),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn from_matches(
+        matches: &ArgMatches,
+    ) -> Result<Option<Self>, Error<io::Error>> {
+        let path = match matches.value_of("auth-secret") {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let identity = match matches.value_of("auth-name") {
+            Some(name) => name.to_string(),
+            None => trace!(hostname()),
+        };
+        Ok(Some(AuthConfig {
+            identity,
+            secret: trace!(read_secret(path)),
+        }))
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn hostname() -> Result<String, io::Error> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut _, buf.len()) } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(target_os = "windows")]
+fn hostname() -> Result<String, io::Error> {
+    std::env::var("COMPUTERNAME")
+        .map_err(|e| io::Error::new(ErrorKind::NotFound, e))
+}
+
+pub fn nonce() -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_SIZE];
+    rand_bytes(&mut nonce).expect("Failed to generate nonce");
+    nonce
+}
+
+fn mac(
+    secret: &[u8],
+    label: &[u8],
+    nonce: &[u8],
+    identity: &str,
+) -> Result<Vec<u8>, io::Error> {
+    let inner = || -> Result<Vec<u8>, ErrorStack> {
+        let key = PKey::hmac(secret)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(label)?;
+        signer.update(nonce)?;
+        signer.update(identity.as_bytes())?;
+        signer.sign_to_vec()
+    };
+    inner().map_err(|e| io::Error::new(ErrorKind::Other, e))
+}
+
+pub fn client_proof(
+    secret: &[u8],
+    nonce: &[u8],
+    identity: &str,
+) -> Result<Vec<u8>, io::Error> {
+    mac(secret, CLIENT_LABEL, nonce, identity)
+}
+
+pub fn server_proof(
+    secret: &[u8],
+    nonce: &[u8],
+    identity: &str,
+) -> Result<Vec<u8>, io::Error> {
+    mac(secret, SERVER_LABEL, nonce, identity)
+}
+
+pub fn verify(expected: &[u8], received: &[u8]) -> bool {
+    expected.len() == received.len() && memcmp::eq(expected, received)
+}
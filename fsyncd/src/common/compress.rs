@@ -0,0 +1,78 @@
+use byteorder::{BigEndian, ByteOrder};
+use zstd::block;
+
+/*
+    Realtime compressors compress every message on its own, so each can be
+    decoded as soon as it arrives, unlike the stream compressors which only
+    emit once they have enough or are flushed.
+*/
+pub trait Compressor: Send {
+    fn encode(&mut self, input: &[u8], output: &mut Vec<u8>);
+    // Leaves output empty if input is not something encode produced
+    fn decode(&mut self, input: &[u8], output: &mut Vec<u8>);
+}
+
+// A zstd block per message, after its decompressed size
+pub struct ZstdBlock {
+    compressor: block::Compressor,
+    decompressor: block::Decompressor,
+}
+
+impl Default for ZstdBlock {
+    fn default() -> Self {
+        ZstdBlock {
+            compressor: block::Compressor::new(),
+            decompressor: block::Decompressor::new(),
+        }
+    }
+}
+
+impl Compressor for ZstdBlock {
+    fn encode(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        let mut size = [0; 4];
+        BigEndian::write_u32(&mut size, input.len() as u32);
+        output.extend_from_slice(&size);
+        let compressed = self
+            .compressor
+            .compress(input, 0)
+            .expect("Failed to compress message");
+        output.extend_from_slice(&compressed);
+    }
+
+    fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        if input.len() < 4 {
+            return;
+        }
+        let size = BigEndian::read_u32(&input[..4]) as usize;
+        if let Ok(decompressed) =
+            self.decompressor.decompress(&input[4..], size)
+        {
+            output.extend_from_slice(&decompressed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let mut encoder = ZstdBlock::default();
+        let mut decoder = ZstdBlock::default();
+        for msg in &[&b""[..], b"a", &[7; 100_000][..]] {
+            let mut encoded = Vec::new();
+            encoder.encode(msg, &mut encoded);
+            let mut decoded = Vec::new();
+            decoder.decode(&encoded, &mut decoded);
+            assert_eq!(&decoded[..], *msg);
+        }
+    }
+
+    #[test]
+    fn garbage_decodes_to_nothing() {
+        let mut decoded = Vec::new();
+        ZstdBlock::default().decode(b"\0\0\0\x05junk", &mut decoded);
+        assert!(decoded.is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+use common::xattr;
+use std::io;
+use std::path::Path;
+
+/*
+    Every promotion starts a new epoch. A tree records the epoch it was last
+    served in as primary, and, when it is a replica, the newest epoch of a
+    primary it followed. A primary that was failed over comes back with an
+    older epoch than its replicas have seen, they refuse it and it stops
+    taking writes. Both are kept under the root (an xattr, or an alternate
+    data stream on Windows), like the replica position.
+*/
+
+const PRIMARY: &str = "fsyncer.epoch";
+const SEEN: &str = "fsyncer.seen-epoch";
+
+// Epoch root was last served in as primary, 0 if it never was
+pub fn primary(root: &Path) -> Result<u64, io::Error> {
+    Ok(xattr::get(root, PRIMARY)?.unwrap_or(0))
+}
+
+pub fn set_primary(root: &Path, epoch: u64) -> Result<(), io::Error> {
+    xattr::set(root, PRIMARY, epoch)
+}
+
+// Newest epoch root has been part of, as primary or replica
+pub fn newest(root: &Path) -> Result<u64, io::Error> {
+    let seen = xattr::get(root, SEEN)?.unwrap_or(0);
+    Ok(seen.max(primary(root)?))
+}
+
+// Records a primary's epoch once root follows it, only ever moves forward
+pub fn see(root: &Path, epoch: u64) -> Result<(), io::Error> {
+    if epoch <= newest(root)? {
+        return Ok(());
+    }
+    xattr::set(root, SEEN, epoch)
+}
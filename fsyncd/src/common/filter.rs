@@ -0,0 +1,353 @@
+use clap::ArgMatches;
+use common::VFSCall;
+use regex::{self, Regex};
+use std::borrow::Cow;
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path};
+
+/*
+    Limits what a replica receives. Include patterns are paths from the root
+    ("/projects/foo"), when any are given only those subtrees are replicated,
+    along with the directories leading up to them. Exclude patterns are
+    applied after that, one starting with a / is matched from the root,
+    otherwise at any depth ("*.tmp", ".cache/", "build/out"). A pattern that
+    matches a directory matches everything below it, a trailing / is ignored.
+    * and ? match within a single path component.
+*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct FilterSpec {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl FilterSpec {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let values = |name| {
+            matches
+                .values_of(name)
+                .map(|v| v.map(String::from).collect())
+                .unwrap_or_default()
+        };
+        FilterSpec {
+            include: values("include"),
+            exclude: values("exclude"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PathFilter {
+    spec: FilterSpec,
+    include: Vec<Vec<Regex>>,
+    exclude: Vec<Pattern>,
+}
+
+struct Pattern {
+    anchored: bool,
+    components: Vec<Regex>,
+}
+
+// What a replica behind a filter should get for an op
+#[derive(PartialEq, Debug)]
+pub enum Route {
+    Send,
+    Skip,
+    // A rename moving something out of the filter, it has to be removed
+    Leaving,
+    // A rename or link bringing something into the filter, it has to be
+    // created with its contents
+    Entering,
+}
+
+fn compile_component(glob: &str) -> Result<Regex, io::Error> {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+fn compile(pattern: &str) -> Result<Vec<Regex>, io::Error> {
+    let components = pattern
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .map(compile_component)
+        .collect::<Result<Vec<_>, _>>()?;
+    if components.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Empty filter pattern {:?}", pattern),
+        ));
+    }
+    Ok(components)
+}
+
+fn components(path: &Path) -> Vec<Cow<str>> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn matches_at(pattern: &[Regex], components: &[Cow<str>]) -> bool {
+    components.len() >= pattern.len()
+        && pattern.iter().zip(components).all(|(re, c)| re.is_match(c))
+}
+
+impl Pattern {
+    fn matches(&self, components: &[Cow<str>]) -> bool {
+        if self.anchored {
+            return matches_at(&self.components, components);
+        }
+        (0..components.len())
+            .any(|i| matches_at(&self.components, &components[i..]))
+    }
+}
+
+impl PathFilter {
+    pub fn new(spec: &FilterSpec) -> Result<Self, io::Error> {
+        let mut include = Vec::new();
+        for pattern in &spec.include {
+            if !pattern.starts_with('/') {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Include pattern {:?} must start with /", pattern),
+                ));
+            }
+            include.push(compile(pattern)?);
+        }
+        let mut exclude = Vec::new();
+        for pattern in &spec.exclude {
+            exclude.push(Pattern {
+                anchored: pattern.starts_with('/'),
+                components: compile(pattern)?,
+            });
+        }
+        Ok(PathFilter {
+            spec: spec.clone(),
+            include,
+            exclude,
+        })
+    }
+
+    pub fn spec(&self) -> &FilterSpec {
+        &self.spec
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn allows(&self, path: &Path) -> bool {
+        let components = components(path);
+        // Zipping stops at the shorter one, so this takes in both the
+        // directories leading to an include and everything under it.
+        if !self.include.is_empty()
+            && !self.include.iter().any(|pattern| {
+                pattern
+                    .iter()
+                    .zip(&components)
+                    .all(|(re, c)| re.is_match(c))
+            })
+        {
+            return false;
+        }
+        !self.exclude.iter().any(|p| p.matches(&components))
+    }
+
+    pub fn route(&self, call: &VFSCall) -> Route {
+        if self.is_empty() {
+            return Route::Send;
+        }
+        let (from, to) = match call {
+            VFSCall::rename { from, to, .. } => (Some(from), to),
+            VFSCall::link { from, to, .. } => (Some(from), to),
+            // from is the link's target, not a path in the tree
+            VFSCall::symlink { to, .. } => (None, to),
+            VFSCall::mknod { path, .. }
+            | VFSCall::mkdir { path, .. }
+            | VFSCall::unlink { path }
+            | VFSCall::rmdir { path }
+            | VFSCall::chmod { path, .. }
+            | VFSCall::truncate { path, .. }
+            | VFSCall::write { path, .. }
+            | VFSCall::diff_write { path, .. }
+            | VFSCall::fallocate { path, .. }
+            | VFSCall::setxattr { path, .. }
+            | VFSCall::removexattr { path, .. }
+            | VFSCall::create { path, .. }
+            | VFSCall::utimens { path, .. }
+            | VFSCall::fsync { path, .. }
+            | VFSCall::truncating_write { path, .. }
+            | VFSCall::security { path, .. } => (None, path),
+        };
+        let to_allowed = self.allows(to);
+        let from_allowed = from.map_or(to_allowed, |from| self.allows(from));
+        match (from_allowed, to_allowed) {
+            (true, true) => Route::Send,
+            (false, false) => Route::Skip,
+            (false, true) => Route::Entering,
+            // The source of a link stays where it is
+            (true, false) if is_variant!(call, VFSCall::link, struct) => {
+                Route::Skip
+            }
+            (true, false) => Route::Leaving,
+        }
+    }
+
+    // The same filter for rsync, excludes have to come first as the first
+    // matching rule wins.
+    pub fn rsync_rules(&self) -> Vec<String> {
+        let mut rules: Vec<String> = self
+            .spec
+            .exclude
+            .iter()
+            .map(|p| format!("- {}", p.trim_end_matches('/')))
+            .collect();
+        for pattern in &self.spec.include {
+            let pattern = pattern.trim_end_matches('/');
+            let mut prefix = String::new();
+            for component in pattern.split('/').filter(|c| !c.is_empty()) {
+                if !prefix.is_empty() {
+                    rules.push(format!("+ {}/", prefix));
+                }
+                prefix.push('/');
+                prefix.push_str(component);
+            }
+            rules.push(format!("+ {}", prefix));
+            rules.push(format!("+ {}/***", prefix));
+        }
+        if !self.include.is_empty() {
+            rules.push("- *".into());
+        }
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let strings = |p: &[&str]| p.iter().map(|p| p.to_string()).collect();
+        PathFilter::new(&FilterSpec {
+            include: strings(include),
+            exclude: strings(exclude),
+        })
+        .unwrap()
+    }
+
+    fn allows(filter: &PathFilter, path: &str) -> bool {
+        filter.allows(Path::new(path))
+    }
+
+    fn rename<'a>(from: &'a str, to: &'a str) -> VFSCall<'a> {
+        VFSCall::rename {
+            from: Cow::Borrowed(Path::new(from)),
+            to: Cow::Borrowed(Path::new(to)),
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn globs_match_within_a_component() {
+        let f = filter(&[], &["*.tmp", "?.o"]);
+        assert!(!allows(&f, "/a/b.tmp"));
+        assert!(!allows(&f, "/x.o"));
+        assert!(allows(&f, "/xy.o"));
+        assert!(allows(&f, "/a/b.tmp.keep"));
+        // Everything under a matching directory goes too
+        assert!(!allows(&f, "/a.tmp/b"));
+        // Anything else in a pattern is literal
+        let f = filter(&[], &["a+b", "[x]"]);
+        assert!(allows(&f, "/aab"));
+        assert!(!allows(&f, "/a+b"));
+        assert!(allows(&f, "/x"));
+        assert!(!allows(&f, "/[x]"));
+    }
+
+    #[test]
+    fn excludes_are_anchored_by_a_leading_slash() {
+        let f = filter(&[], &["/build/out", ".cache/"]);
+        assert!(!allows(&f, "/build/out"));
+        assert!(!allows(&f, "/build/out/a"));
+        assert!(allows(&f, "/src/build/out"));
+        assert!(allows(&f, "/build/other"));
+        assert!(!allows(&f, "/home/u/.cache"));
+        assert!(!allows(&f, "/.cache/x"));
+
+        let f = filter(&[], &["build/out"]);
+        assert!(!allows(&f, "/a/build/out/x"));
+        assert!(allows(&f, "/a/build/other"));
+        assert!(allows(&f, "/a/out"));
+    }
+
+    #[test]
+    fn includes_take_in_their_parents() {
+        let f = filter(&["/projects/foo/"], &["*.tmp"]);
+        assert!(allows(&f, "/"));
+        assert!(allows(&f, "/projects"));
+        assert!(allows(&f, "/projects/foo"));
+        assert!(allows(&f, "/projects/foo/a/b"));
+        assert!(!allows(&f, "/projects/bar"));
+        assert!(!allows(&f, "/other"));
+        assert!(!allows(&f, "/projects/foo/a.tmp"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        let spec = |include: &[&str], exclude: &[&str]| FilterSpec {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+        };
+        // Includes are always from the root
+        assert!(PathFilter::new(&spec(&["projects"], &[])).is_err());
+        assert!(PathFilter::new(&spec(&["/"], &[])).is_err());
+        assert!(PathFilter::new(&spec(&[], &["/"])).is_err());
+        assert!(PathFilter::new(&spec(&["/a"], &["b"])).is_ok());
+    }
+
+    #[test]
+    fn routes() {
+        let f = filter(&["/in"], &[]);
+        assert_eq!(f.route(&rename("/in/a", "/in/b")), Route::Send);
+        assert_eq!(f.route(&rename("/out/a", "/out/b")), Route::Skip);
+        assert_eq!(f.route(&rename("/out/a", "/in/a")), Route::Entering);
+        assert_eq!(f.route(&rename("/in/a", "/out/a")), Route::Leaving);
+        let unlink = |path| VFSCall::unlink {
+            path: Cow::Borrowed(Path::new(path)),
+        };
+        assert_eq!(f.route(&unlink("/in/a")), Route::Send);
+        assert_eq!(f.route(&unlink("/out/a")), Route::Skip);
+        let f = filter(&[], &[]);
+        assert_eq!(f.route(&rename("/out/a", "/in/a")), Route::Send);
+    }
+
+    #[test]
+    fn rsync_rules() {
+        let f = filter(&["/projects/foo/", "/etc"], &["*.tmp", "/cache/"]);
+        assert_eq!(
+            f.rsync_rules(),
+            vec![
+                "- *.tmp",
+                "- /cache",
+                "+ /projects/",
+                "+ /projects/foo",
+                "+ /projects/foo/***",
+                "+ /etc",
+                "+ /etc/***",
+                "- *",
+            ]
+        );
+        let f = filter(&[], &[".cache"]);
+        assert_eq!(f.rsync_rules(), vec!["- .cache"]);
+        assert!(filter(&[], &[]).rsync_rules().is_empty());
+    }
+}
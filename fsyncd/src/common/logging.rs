@@ -0,0 +1,193 @@
+extern crate chrono;
+
+use self::chrono::Utc;
+use clap::ArgMatches;
+use std::fmt::{self, Debug, Write as FmtWrite};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/*
+    Levelled logging, through the error!, warn!, info! and debug! macros.
+    Each line has the time, the level, the module it came from and the
+    message, followed by fields given as key = value before the message, for
+    what alerts are keyed on (the client, the op, the path). Levels can be set
+    per module, the most specific one that matches applies.
+*/
+
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+enum Output {
+    Stderr,
+    File(Mutex<File>),
+    #[cfg(target_family = "unix")]
+    Syslog,
+}
+
+struct Config {
+    level: Level,
+    // Module paths without the crate name, like server::client
+    modules: Vec<(String, Level)>,
+    output: Output,
+}
+
+// Until it is configured everything up to info goes to stderr
+static mut CONFIG: Option<Config> = None;
+
+// Takes the level spec, like "info,server::client=debug"
+fn parse_levels(spec: &str) -> Option<(Level, Vec<(String, Level)>)> {
+    let mut level = Level::Info;
+    let mut modules = Vec::new();
+    for part in spec.split(',').filter(|p| !p.is_empty()) {
+        let mut kv = part.splitn(2, '=');
+        let (module, value) = (kv.next()?, kv.next());
+        match value {
+            Some(value) => {
+                modules.push((module.to_string(), Level::parse(value)?))
+            }
+            None => level = Level::parse(module)?,
+        }
+    }
+    Some((level, modules))
+}
+
+// Must run before any other threads are started
+pub fn configure(matches: &ArgMatches) -> Result<(), io::Error> {
+    let (mut level, modules) =
+        parse_levels(matches.value_of("log-level").unwrap()).ok_or_else(
+            || io::Error::new(io::ErrorKind::InvalidInput, "Invalid log level"),
+        )?;
+    if matches.is_present("debug") {
+        level = Level::Debug;
+    }
+    let output = if let Some(path) = matches.value_of("log-file") {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Output::File(Mutex::new(file))
+    } else if matches.is_present("syslog") {
+        syslog()?
+    } else {
+        Output::Stderr
+    };
+    unsafe {
+        CONFIG = Some(Config {
+            level,
+            modules,
+            output,
+        })
+    };
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+fn syslog() -> Result<Output, io::Error> {
+    // journald picks these up as well
+    unsafe {
+        libc::openlog(
+            "fsyncd\0".as_ptr() as *const _,
+            libc::LOG_PID,
+            libc::LOG_DAEMON,
+        )
+    };
+    Ok(Output::Syslog)
+}
+
+#[cfg(target_os = "windows")]
+fn syslog() -> Result<Output, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Logging to syslog is not supported on windows",
+    ))
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    let config = match unsafe { CONFIG.as_ref() } {
+        Some(config) => config,
+        None => return level <= Level::Info,
+    };
+    let module = module.splitn(2, "::").nth(1).unwrap_or("");
+    let within = |prefix: &str| {
+        module == prefix
+            || (module.starts_with(prefix)
+                && module[prefix.len()..].starts_with("::"))
+    };
+    let max = config
+        .modules
+        .iter()
+        .filter(|(prefix, _)| within(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(config.level, |(_, level)| *level);
+    level <= max
+}
+
+pub fn write(
+    level: Level,
+    module: &str,
+    fields: &[(&str, &dyn Debug)],
+    args: fmt::Arguments,
+) {
+    let module = module.splitn(2, "::").nth(1).unwrap_or(module);
+    let mut line = format!("{} {}: {}", level.name(), module, args);
+    for (key, value) in fields {
+        let _ = write!(line, " {}={:?}", key, value);
+    }
+    let output = unsafe { CONFIG.as_ref() }.map(|c| &c.output);
+    match output {
+        None | Some(Output::Stderr) => {
+            eprintln!("{} {}", timestamp(), line);
+        }
+        Some(Output::File(file)) => {
+            let mut file = file.lock().unwrap();
+            // Nowhere left to report this one
+            let _ = writeln!(file, "{} {}", timestamp(), line);
+        }
+        #[cfg(target_family = "unix")]
+        Some(Output::Syslog) => {
+            use std::ffi::CString;
+            let priority = match level {
+                Level::Error => libc::LOG_ERR,
+                Level::Warn => libc::LOG_WARNING,
+                Level::Info => libc::LOG_INFO,
+                Level::Debug => libc::LOG_DEBUG,
+            };
+            let line = CString::new(line.replace('\0', "")).unwrap();
+            unsafe {
+                libc::syslog(
+                    priority,
+                    "%s\0".as_ptr() as *const _,
+                    line.as_ptr(),
+                )
+            };
+        }
+    }
+}
+
+fn timestamp() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
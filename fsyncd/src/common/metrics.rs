@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/*
+    Counters for the Prometheus text format, served over plain HTTP with
+    --metrics. There is one set per process, the server and a client each
+    fill in the ones that apply to them and leave the rest at zero.
+*/
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    fn render(&self, kind: &str, out: &mut String) {
+        header(self.name, self.help, kind, out);
+        let _ = writeln!(
+            out,
+            "{} {}",
+            self.name,
+            self.value.load(Ordering::Relaxed)
+        );
+    }
+}
+
+// Value that goes up and down, rendered like a counter
+pub struct Gauge(Counter);
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Gauge(Counter::new(name, help))
+    }
+
+    pub fn set(&self, n: u64) {
+        self.0.value.store(n, Ordering::Relaxed);
+    }
+}
+
+// A counter per value of one label, values are added as they are first seen
+pub struct LabeledCounter {
+    name: &'static str,
+    help: &'static str,
+    label: &'static str,
+    values: Mutex<BTreeMap<String, Arc<AtomicU64>>>,
+}
+
+impl LabeledCounter {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        label: &'static str,
+    ) -> Self {
+        LabeledCounter {
+            name,
+            help,
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Kept by whoever counts often under the same label
+    pub fn get(&self, value: &str) -> Arc<AtomicU64> {
+        let mut values = self.values.lock().unwrap();
+        if let Some(counter) = values.get(value) {
+            return counter.clone();
+        }
+        let counter = Arc::new(AtomicU64::new(0));
+        values.insert(value.to_string(), counter.clone());
+        counter
+    }
+
+    pub fn inc(&self, value: &str) {
+        self.get(value).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        header(self.name, self.help, "counter", out);
+        for (value, counter) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                self.name,
+                self.label,
+                escape(value),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+// Upper bounds in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    // Not cumulative, they are added up when rendered
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Histogram {
+            name,
+            help,
+            buckets: LATENCY_BUCKETS
+                .iter()
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9;
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&b| secs <= b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        header(self.name, self.help, "histogram", out);
+        let mut total = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            total += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name, bound, total
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            self.name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(out, "{}_count {}", self.name, count);
+    }
+}
+
+fn header(name: &str, help: &str, kind: &str, out: &mut String) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub static ACK_TIMEOUTS: Counter = Counter::new(
+    "fsyncd_ack_timeouts_total",
+    "Acknowledgements from synchronous clients that did not arrive in time",
+);
+pub static JOURNAL_BYTES: Counter = Counter::new(
+    "fsyncd_journal_written_bytes_total",
+    "Bytes written to the journal",
+);
+pub static FILESTORE_BYTES: Gauge = Gauge::new(
+    "fsyncd_filestore_bytes",
+    "Bytes kept in the FileStore for deleted files",
+);
+pub static DISPATCH_FAILURES: Counter = Counter::new(
+    "fsyncd_dispatch_failures_total",
+    "Ops received from the server that failed to apply",
+);
+pub static COALESCED_OPS: Counter = Counter::new(
+    "fsyncd_coalesced_ops_total",
+    "Queued ops merged into or made redundant by later ones, and not sent",
+);
+
+lazy_static! {
+    pub static ref OPS: LabeledCounter = LabeledCounter::new(
+        "fsyncd_ops_total",
+        "Ops made on the server, or applied on a client",
+        "call"
+    );
+    pub static ref SENT_RAW_BYTES: LabeledCounter = LabeledCounter::new(
+        "fsyncd_sent_raw_bytes_total",
+        "Bytes of messages sent to each client, before compression",
+        "client"
+    );
+    pub static ref SENT_BYTES: LabeledCounter = LabeledCounter::new(
+        "fsyncd_sent_bytes_total",
+        "Bytes sent to each client, after compression",
+        "client"
+    );
+    pub static ref POST_OP_WAIT: Histogram = Histogram::new(
+        "fsyncd_post_op_wait_seconds",
+        "Time writes waited for synchronous clients to acknowledge"
+    );
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    OPS.render(&mut out);
+    SENT_RAW_BYTES.render(&mut out);
+    SENT_BYTES.render(&mut out);
+    POST_OP_WAIT.render(&mut out);
+    ACK_TIMEOUTS.render("counter", &mut out);
+    JOURNAL_BYTES.render("counter", &mut out);
+    FILESTORE_BYTES.0.render("gauge", &mut out);
+    DISPATCH_FAILURES.render("counter", &mut out);
+    COALESCED_OPS.render("counter", &mut out);
+    out
+}
+
+// Counts what is written through it, after anything layered on top of it
+pub struct CountingWriter<W> {
+    inner: W,
+    count: Arc<AtomicU64>,
+}
+
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W, count: Arc<AtomicU64>) -> Self {
+        CountingWriter { inner, count }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Serves the metrics on addr until the process exits
+pub fn serve(addr: &str) -> Result<(), io::Error> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving metrics on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.and_then(respond);
+            if let Err(e) = res {
+                error!("Failed to serve metrics {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> Result<(), io::Error> {
+    // A scraper that never finishes its request can't hold up the others
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
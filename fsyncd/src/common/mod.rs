@@ -1,5 +1,15 @@
 #![allow(dead_code)]
+pub mod auth;
+pub mod compress;
+pub mod epoch;
 pub mod file_security;
+pub mod filter;
+pub mod logging;
+pub mod metrics;
+pub mod net;
+pub mod tls;
+pub mod version;
+pub mod xattr;
 
 metablock!(cfg(target_family="unix") {
     mod ops_unix;
@@ -13,18 +23,24 @@ metablock!(cfg(target_family="windows") {
     pub use self::ops_windows::*;
     use common::FILETIME;
     use std::ffi::{OsString, OsStr};
+    use std::fs::OpenOptions;
 });
 
+use self::auth::AuthResponse;
 pub use self::file_security::FileSecurity;
+use self::filter::{FilterSpec, PathFilter};
+use self::version::Version;
+use clap::ArgMatches;
 use libc::*;
 use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
 use std::ffi::{CStr, CString};
-use std::fs::OpenOptions;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::io::Error;
 use std::ops::BitXor;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use walkdir::WalkDir;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Hash)]
@@ -64,6 +80,20 @@ impl Into<timespec> for Timespec {
     }
 }
 
+impl Timespec {
+    // A utimens leaves this time as it is
+    #[cfg(target_family = "unix")]
+    pub fn omitted(&self) -> bool {
+        self.low == UTIME_OMIT
+    }
+
+    // A zero FILETIME isn't changed by SetFileTime
+    #[cfg(target_os = "windows")]
+    pub fn omitted(&self) -> bool {
+        self.high == 0 && self.low == 0
+    }
+}
+
 #[cfg(target_os = "windows")]
 impl From<FILETIME> for Timespec {
     fn from(spec: FILETIME) -> Self {
@@ -87,13 +117,117 @@ impl Into<FILETIME> for Timespec {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum FsyncerMsg<'a> {
     InitMsg(InitMsg),
-    AsyncOp(Cow<'a, VFSCall<'a>>),
-    SyncOp(Cow<'a, VFSCall<'a>>, u64),
+    // Ops carry their id, which replicas resume from after reconnecting, and
+    // last their sequence number on the connection, which has no gaps. The
+    // sequence number is filled in as the op is sent.
+    AsyncOp(Cow<'a, VFSCall<'a>>, u64, u64),
+    SyncOp(Cow<'a, VFSCall<'a>>, u64, u64, u64),
     Ack(AckMsg),
     Cork(u64),
     AckCork(u64),
     Uncork,
     NOP,
+    AuthChallenge(Vec<u8>),
+    AuthResponse(AuthResponse),
+    AuthAccepted(Vec<u8>),
+    AuthRejected(String),
+    InitResponse(InitResponse),
+    // Replica has every op up to and including this id
+    CaughtUp(Option<u64>),
+    // An op for a peer, with the version of the path it changes
+    PeerOp(Cow<'a, VFSCall<'a>>, u64, Version, u64),
+    // From control, promote the replica serving on this url to primary
+    Promote(String),
+    // To the replica being promoted, ack once everything up to the op id has
+    // been applied
+    Handover(u64, Option<u64>),
+    // To the other replicas, the primary is now at this url
+    Redirect(String),
+    // Outcome of Promote, to the control client and to the replica taking
+    // over, which only does so once it gets this
+    Promoted(Result<(), String>),
+    // From replicas, how many ops of this connection have been applied
+    Applied(u64),
+    // To a replica whose result for an op differed, it must not resume
+    Diverged(String),
+    // From control, anything other than corking and promoting
+    Control(ControlCmd),
+    ControlReply(Result<ControlInfo, String>),
+    // From a replica serving downstream, whether the ops that follow are
+    // being replayed from its own server, they may have been applied already
+    Replaying(bool),
+}
+
+impl<'a> FsyncerMsg<'a> {
+    // Sequence number of an op, None for anything else
+    pub fn seq_mut(&mut self) -> Option<&mut u64> {
+        match self {
+            FsyncerMsg::AsyncOp(_, _, seq)
+            | FsyncerMsg::SyncOp(_, _, _, seq)
+            | FsyncerMsg::PeerOp(_, _, _, seq) => Some(seq),
+            _ => None,
+        }
+    }
+
+    // Id the acknowledgement of a request comes back under, None for
+    // messages that aren't acknowledged
+    pub fn request_id_mut(&mut self) -> Option<&mut u64> {
+        match self {
+            FsyncerMsg::SyncOp(_, id, _, _)
+            | FsyncerMsg::Cork(id)
+            | FsyncerMsg::Handover(id, _) => Some(id),
+            _ => None,
+        }
+    }
+
+    // Only ops borrow, for keeping them past the call that made them
+    pub fn into_owned(self) -> FsyncerMsg<'static> {
+        fn own(call: Cow<VFSCall>) -> Cow<'static, VFSCall<'static>> {
+            Cow::Owned(call.into_owned().into_owned())
+        }
+        match self {
+            FsyncerMsg::InitMsg(init) => FsyncerMsg::InitMsg(init),
+            FsyncerMsg::AsyncOp(call, op_id, seq) => {
+                FsyncerMsg::AsyncOp(own(call), op_id, seq)
+            }
+            FsyncerMsg::SyncOp(call, id, op_id, seq) => {
+                FsyncerMsg::SyncOp(own(call), id, op_id, seq)
+            }
+            FsyncerMsg::Ack(ack) => FsyncerMsg::Ack(ack),
+            FsyncerMsg::Cork(id) => FsyncerMsg::Cork(id),
+            FsyncerMsg::AckCork(id) => FsyncerMsg::AckCork(id),
+            FsyncerMsg::Uncork => FsyncerMsg::Uncork,
+            FsyncerMsg::NOP => FsyncerMsg::NOP,
+            FsyncerMsg::AuthChallenge(challenge) => {
+                FsyncerMsg::AuthChallenge(challenge)
+            }
+            FsyncerMsg::AuthResponse(response) => {
+                FsyncerMsg::AuthResponse(response)
+            }
+            FsyncerMsg::AuthAccepted(proof) => FsyncerMsg::AuthAccepted(proof),
+            FsyncerMsg::AuthRejected(reason) => {
+                FsyncerMsg::AuthRejected(reason)
+            }
+            FsyncerMsg::InitResponse(response) => {
+                FsyncerMsg::InitResponse(response)
+            }
+            FsyncerMsg::CaughtUp(op_id) => FsyncerMsg::CaughtUp(op_id),
+            FsyncerMsg::PeerOp(call, op_id, version, seq) => {
+                FsyncerMsg::PeerOp(own(call), op_id, version, seq)
+            }
+            FsyncerMsg::Promote(url) => FsyncerMsg::Promote(url),
+            FsyncerMsg::Handover(id, last) => FsyncerMsg::Handover(id, last),
+            FsyncerMsg::Redirect(url) => FsyncerMsg::Redirect(url),
+            FsyncerMsg::Promoted(res) => FsyncerMsg::Promoted(res),
+            FsyncerMsg::Applied(count) => FsyncerMsg::Applied(count),
+            FsyncerMsg::Diverged(reason) => FsyncerMsg::Diverged(reason),
+            FsyncerMsg::Control(cmd) => FsyncerMsg::Control(cmd),
+            FsyncerMsg::ControlReply(reply) => FsyncerMsg::ControlReply(reply),
+            FsyncerMsg::Replaying(replaying) => {
+                FsyncerMsg::Replaying(replaying)
+            }
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Debug)]
@@ -106,13 +240,76 @@ pub enum ClientMode {
     MODE_CONTROL,
 }
 
+/*
+    Bump PROTOCOL_VERSION whenever the encoding of any FsyncerMsg changes.
+    Nothing picks an encoding by the negotiated version yet, so
+    MIN_PROTOCOL_VERSION has to move along with it, until then only the same
+    version can connect. Optional features that both sides need to agree on
+    should be added as Capabilities instead, so mixed versions can still
+    interoperate.
+*/
+pub const PROTOCOL_VERSION: u32 = 9;
+pub const MIN_PROTOCOL_VERSION: u32 = PROTOCOL_VERSION;
+
+bitflags! {
+    #[derive(Serialize, Deserialize)]
+    pub struct Capabilities: u32 {
+        const AUTH              = 0b000001;
+        // Both sides send a NOP at least every heartbeat interval
+        const HEARTBEAT         = 0b000010;
+        // Both sides accept writes, ops are sent as PeerOp
+        const PEER              = 0b000100;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    // A peer that has been silent for this long is considered dead
+    pub timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let secs = |name| {
+            Duration::from_secs(
+                matches
+                    .value_of(name)
+                    .map(|v| v.parse().expect("Invalid number of seconds"))
+                    .unwrap(),
+            )
+        };
+        let config = HeartbeatConfig {
+            interval: secs("heartbeat-interval"),
+            timeout: secs("heartbeat-timeout"),
+        };
+        if config.timeout <= config.interval {
+            panic!("Heartbeat timeout must be longer than the interval");
+        }
+        config
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct InitMsg {
+    // Must remain the first field, servers check it before decoding the rest
+    pub version: u32,
+    pub capabilities: Capabilities,
     pub mode: ClientMode,
     pub dsthash: u64,
     pub compress: CompMode,
     pub iolimit_bps: usize,
     pub options: Options,
+    pub identity: Option<String>,
+    // Last op applied by the replica, the server sends only what came after
+    pub resume_from: Option<u64>,
+    // Parts of the tree this replica wants, everything when empty
+    pub filter: FilterSpec,
+    // Where this replica serves replicas of its own, only such a replica can
+    // be promoted to primary
+    pub downstream: Option<String>,
+    // Newest epoch the replica has seen, older servers are refused
+    pub epoch: u64,
 }
 
 bitflags! {
@@ -122,6 +319,55 @@ bitflags! {
     }
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum RejectReason {
+    Version { min: u32, max: u32 },
+    HashMismatch,
+    Other(String),
+    ResumeUnavailable,
+    // The server's epoch is older than one the replica has seen
+    StaleEpoch { server: u64, seen: u64 },
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::Version { min, max } => write!(
+                f,
+                "protocol version not supported, server speaks {} to {}",
+                min, max
+            ),
+            RejectReason::HashMismatch => {
+                write!(f, "destination does not match the source")
+            }
+            RejectReason::Other(reason) => write!(f, "{}", reason),
+            RejectReason::ResumeUnavailable => write!(
+                f,
+                "missed ops are no longer in the journal, full resync needed"
+            ),
+            RejectReason::StaleEpoch { server, seen } => write!(
+                f,
+                "server is at epoch {} but epoch {} was seen, another server \
+                 took over",
+                server, seen
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RejectReason {}
+
+// Server's answer to InitMsg, sent once the client has been checked
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum InitResponse {
+    Accept {
+        version: u32,
+        capabilities: Capabilities,
+        epoch: u64,
+    },
+    Reject(RejectReason),
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum ClientAck {
     Ack,
@@ -135,12 +381,56 @@ pub struct AckMsg {
     pub tid: u64,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum ControlCmd {
+    ListClients,
+    // Drops the client with this id
+    Disconnect(u64),
+    // Bytes per second sent to the client with this id, 0 for no limit
+    SetIolimit(u64, usize),
+    FlushJournal,
+    JournalStatus,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum ControlInfo {
+    Done,
+    Clients(Vec<ClientInfo>),
+    // None if the server keeps no journal
+    Journal(Option<JournalInfo>),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub label: String,
+    pub mode: ClientMode,
+    pub compress: String,
+    pub iolimit_bps: usize,
+    // Ops sent that the client has not applied yet, and their size
+    pub lag_ops: u64,
+    pub lag_bytes: u64,
+    pub alive: bool,
+    // Synchronous, but too far behind to be waited for
+    pub degraded: bool,
+    pub silent_secs: u64,
+    pub downstream: Option<String>,
+    // Sent to the send queue, but not to the client yet
+    pub queued_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct JournalInfo {
+    pub used: u64,
+    pub size: u64,
+    pub filestore_used: u64,
+    pub filestore_size: u64,
+}
+
 bitflags! {
     #[derive(Serialize, Deserialize)]
     pub struct CompMode: u32 {
-        const RT_DSSC_ZLIB      = 0b000001;
-        const RT_DSSC_CHUNKED   = 0b000010;
-        const RT_DSSC_ZSTD      = 0b000100;
+        const RT_ZSTD           = 0b000100;
         const RT_MASK           = 0b000111;
         const STREAM_ZSTD       = 0b001000;
         const STREAM_LZ4        = 0b010000;
@@ -149,13 +439,18 @@ bitflags! {
 }
 
 #[cfg(target_family = "unix")]
-pub fn hash_metadata(path: &Path) -> Result<u64, Error> {
+pub fn hash_metadata(path: &Path, filter: &PathFilter) -> Result<u64, Error> {
     use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
     let mut hasher = DefaultHasher::new();
     let empty = Path::new("");
-    for entry in
-        WalkDir::new(path).sort_by(|a, b| a.file_name().cmp(b.file_name()))
+    let root = Path::new("/");
+    for entry in WalkDir::new(path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|e| {
+            filter.allows(&root.join(e.path().strip_prefix(path).unwrap()))
+        })
     {
         let e = entry?;
         let path = e.path().strip_prefix(path).unwrap();
@@ -180,11 +475,14 @@ pub fn hash_metadata(path: &Path) -> Result<u64, Error> {
 }
 
 #[cfg(target_os = "windows")]
-pub fn hash_metadata(path: &Path) -> Result<u64, Error> {
+pub fn hash_metadata(path: &Path, filter: &PathFilter) -> Result<u64, Error> {
     use std::os::windows::fs::MetadataExt;
     let mut hasher = DefaultHasher::new();
     let empty = Path::new("");
-    for entry in WalkDir::new(path) {
+    let root = Path::new("/");
+    for entry in WalkDir::new(path).into_iter().filter_entry(|e| {
+        filter.allows(&root.join(e.path().strip_prefix(path).unwrap()))
+    }) {
         let e = entry?;
         let path = e.path().strip_prefix(path).unwrap();
         if path == empty {
@@ -227,7 +525,7 @@ pub fn zrle(buf: &mut [u8]) -> Option<&mut [u8]> {
             return None;
         }
         saved = saved + run as usize - 2;
-        eprintln!("{} {}", i, saved);
+        debug!(i, saved);
         buf[i - saved - 2] = 0;
         buf[i - saved - 1] = run;
         while i < buf.len() && buf[i] != 0 {
@@ -368,6 +666,204 @@ pub enum VFSCall<'a> {
     }, //chown on linux
 }
 
+impl<'a> VFSCall<'a> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VFSCall::mknod { .. } => "mknod",
+            VFSCall::mkdir { .. } => "mkdir",
+            VFSCall::unlink { .. } => "unlink",
+            VFSCall::rmdir { .. } => "rmdir",
+            VFSCall::symlink { .. } => "symlink",
+            VFSCall::rename { .. } => "rename",
+            VFSCall::link { .. } => "link",
+            VFSCall::chmod { .. } => "chmod",
+            VFSCall::truncate { .. } => "truncate",
+            VFSCall::write { .. } => "write",
+            VFSCall::diff_write { .. } => "diff_write",
+            VFSCall::fallocate { .. } => "fallocate",
+            VFSCall::setxattr { .. } => "setxattr",
+            VFSCall::removexattr { .. } => "removexattr",
+            VFSCall::create { .. } => "create",
+            VFSCall::utimens { .. } => "utimens",
+            VFSCall::fsync { .. } => "fsync",
+            VFSCall::truncating_write { .. } => "truncating_write",
+            VFSCall::security { .. } => "security",
+        }
+    }
+
+    // The path an op creates or changes
+    pub fn target(&self) -> &Path {
+        match self {
+            VFSCall::symlink { to, .. }
+            | VFSCall::rename { to, .. }
+            | VFSCall::link { to, .. } => to,
+            VFSCall::mknod { path, .. }
+            | VFSCall::mkdir { path, .. }
+            | VFSCall::unlink { path }
+            | VFSCall::rmdir { path }
+            | VFSCall::chmod { path, .. }
+            | VFSCall::truncate { path, .. }
+            | VFSCall::write { path, .. }
+            | VFSCall::diff_write { path, .. }
+            | VFSCall::fallocate { path, .. }
+            | VFSCall::setxattr { path, .. }
+            | VFSCall::removexattr { path, .. }
+            | VFSCall::create { path, .. }
+            | VFSCall::utimens { path, .. }
+            | VFSCall::fsync { path, .. }
+            | VFSCall::truncating_write { path, .. }
+            | VFSCall::security { path, .. } => path,
+        }
+    }
+
+    pub fn target_mut(&mut self) -> &mut Cow<'a, Path> {
+        match self {
+            VFSCall::symlink { to, .. }
+            | VFSCall::rename { to, .. }
+            | VFSCall::link { to, .. } => to,
+            VFSCall::mknod { path, .. }
+            | VFSCall::mkdir { path, .. }
+            | VFSCall::unlink { path }
+            | VFSCall::rmdir { path }
+            | VFSCall::chmod { path, .. }
+            | VFSCall::truncate { path, .. }
+            | VFSCall::write { path, .. }
+            | VFSCall::diff_write { path, .. }
+            | VFSCall::fallocate { path, .. }
+            | VFSCall::setxattr { path, .. }
+            | VFSCall::removexattr { path, .. }
+            | VFSCall::create { path, .. }
+            | VFSCall::utimens { path, .. }
+            | VFSCall::fsync { path, .. }
+            | VFSCall::truncating_write { path, .. }
+            | VFSCall::security { path, .. } => path,
+        }
+    }
+
+    pub fn into_owned(self) -> VFSCall<'static> {
+        fn own<T: ToOwned + ?Sized + 'static>(c: Cow<T>) -> Cow<'static, T> {
+            Cow::Owned(c.into_owned())
+        }
+        match self {
+            VFSCall::mknod {
+                path,
+                mode,
+                rdev,
+                security,
+            } => VFSCall::mknod {
+                path: own(path),
+                mode,
+                rdev,
+                security,
+            },
+            VFSCall::mkdir {
+                path,
+                security,
+                mode,
+            } => VFSCall::mkdir {
+                path: own(path),
+                security,
+                mode,
+            },
+            VFSCall::unlink { path } => VFSCall::unlink { path: own(path) },
+            VFSCall::rmdir { path } => VFSCall::rmdir { path: own(path) },
+            VFSCall::symlink { from, to, security } => VFSCall::symlink {
+                from: own(from),
+                to: own(to),
+                security,
+            },
+            VFSCall::rename { from, to, flags } => VFSCall::rename {
+                from: own(from),
+                to: own(to),
+                flags,
+            },
+            VFSCall::link { from, to, security } => VFSCall::link {
+                from: own(from),
+                to: own(to),
+                security,
+            },
+            VFSCall::chmod { path, mode } => VFSCall::chmod {
+                path: own(path),
+                mode,
+            },
+            VFSCall::truncate { path, size } => VFSCall::truncate {
+                path: own(path),
+                size,
+            },
+            VFSCall::write { path, offset, buf } => VFSCall::write {
+                path: own(path),
+                offset,
+                buf: own(buf),
+            },
+            VFSCall::diff_write { path, offset, buf } => VFSCall::diff_write {
+                path: own(path),
+                offset,
+                buf: own(buf),
+            },
+            VFSCall::fallocate {
+                path,
+                mode,
+                offset,
+                length,
+            } => VFSCall::fallocate {
+                path: own(path),
+                mode,
+                offset,
+                length,
+            },
+            VFSCall::setxattr {
+                path,
+                name,
+                value,
+                flags,
+            } => VFSCall::setxattr {
+                path: own(path),
+                name: own(name),
+                value: own(value),
+                flags,
+            },
+            VFSCall::removexattr { path, name } => VFSCall::removexattr {
+                path: own(path),
+                name: own(name),
+            },
+            VFSCall::create {
+                path,
+                flags,
+                security,
+                mode,
+            } => VFSCall::create {
+                path: own(path),
+                flags,
+                security,
+                mode,
+            },
+            VFSCall::utimens { path, timespec } => VFSCall::utimens {
+                path: own(path),
+                timespec,
+            },
+            VFSCall::fsync { path, isdatasync } => VFSCall::fsync {
+                path: own(path),
+                isdatasync,
+            },
+            VFSCall::truncating_write {
+                path,
+                offset,
+                buf,
+                length,
+            } => VFSCall::truncating_write {
+                path: own(path),
+                offset,
+                buf: own(buf),
+                length,
+            },
+            VFSCall::security { path, security } => VFSCall::security {
+                path: own(path),
+                security,
+            },
+        }
+    }
+}
+
 pub fn translate_path(path: &Path, root: &Path) -> PathBuf {
     root.join(if path.starts_with("/") {
         path.strip_prefix("/").unwrap()
@@ -382,19 +878,6 @@ metablock!(cfg(target_family = "unix") {
     pub fn canonize_path(path: &Path) -> Result<PathBuf, Error> {
         path.canonicalize()
     }
-    pub fn with_file<T, F: (FnOnce(i32) -> T)>(
-        path: &Path,
-        options: &OpenOptions,
-        f: F,
-    ) -> Result<T, Error> {
-        use std::fs::File;
-        use std::os::unix::io::{FromRawFd, IntoRawFd};
-        let file = options.open(path)?;
-        let handle = file.into_raw_fd();
-        let res = f(handle);
-        unsafe { File::from_raw_fd(handle) };
-        Ok(res)
-    }
 });
 
 metablock!(cfg(target_os = "windows") {
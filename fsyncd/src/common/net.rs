@@ -0,0 +1,322 @@
+#![allow(clippy::type_complexity)]
+use common::tls::TlsConfig;
+use error::{Error, FromError};
+use net2::TcpStreamExt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::time::Duration;
+use url::Url;
+
+// Only tcp:// is there on windows, unix sockets and TLS (bridged over a unix
+// socket) are not
+metablock!(cfg(target_family = "unix") {
+    use common::tls;
+    use openssl::ssl::SslAcceptor;
+    use std::fs;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::path::Path;
+    use std::sync::Arc;
+});
+
+struct NagleFlush(TcpStream);
+
+#[cfg(target_os = "linux")]
+impl Write for NagleFlush {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        use libc::*;
+        let res = unsafe {
+            send(
+                self.0.as_raw_fd(),
+                buf.as_ptr() as *const _,
+                buf.len(),
+                MSG_MORE,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        use libc::*;
+        use std::mem;
+        let optval = 0;
+        unsafe {
+            setsockopt(
+                self.0.as_raw_fd(),
+                SOL_TCP,
+                TCP_CORK,
+                &optval as *const _ as *const _,
+                mem::size_of::<i32>() as u32,
+            )
+        };
+        Ok(())
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl AsRawFd for NagleFlush {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Write for NagleFlush {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.set_nodelay(true)?;
+        self.0.set_nodelay(false)
+    }
+}
+
+// Closes a connection from another thread, unblocking whoever is reading or
+// writing it
+pub type Closer = Box<dyn Fn() + Send + Sync>;
+
+// rsync is handed the descriptors, which only exist on unix
+#[cfg(target_family = "unix")]
+pub trait MyRead: AsRawFd + Read + Send {
+    fn closer(&self) -> Result<Closer, io::Error>;
+}
+#[cfg(target_family = "unix")]
+pub trait MyWrite: AsRawFd + Write + Send {}
+#[cfg(target_os = "windows")]
+pub trait MyRead: Read + Send {
+    fn closer(&self) -> Result<Closer, io::Error>;
+}
+#[cfg(target_os = "windows")]
+pub trait MyWrite: Write + Send {}
+impl MyRead for TcpStream {
+    fn closer(&self) -> Result<Closer, io::Error> {
+        let stream = self.try_clone()?;
+        Ok(Box::new(move || {
+            let _ = stream.shutdown(Shutdown::Both);
+        }))
+    }
+}
+impl MyWrite for TcpStream {}
+#[cfg(target_family = "unix")]
+impl MyRead for UnixStream {
+    fn closer(&self) -> Result<Closer, io::Error> {
+        let stream = self.try_clone()?;
+        Ok(Box::new(move || {
+            let _ = stream.shutdown(Shutdown::Both);
+        }))
+    }
+}
+#[cfg(target_family = "unix")]
+impl MyWrite for UnixStream {}
+impl MyWrite for NagleFlush {}
+// Pipes can't be shut down, they only end with the process on the other side
+impl MyRead for File {
+    fn closer(&self) -> Result<Closer, io::Error> {
+        Ok(Box::new(|| ()))
+    }
+}
+impl MyWrite for File {}
+//impl MyRead for Deref<Target = MyRead> {}
+
+const DEFAULT_PORT: u16 = 2323;
+pub const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/*
+    Either side can be the one that dials, servers normally listen for
+    replicas but can also connect out to them. Connections are tuned by which
+    way the ops flow rather than by who dialed.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Role {
+    // Streams ops, writes are batched until flushed
+    Sender,
+    // Applies ops, with nodelay acks go out immediately
+    Receiver { nodelay: bool },
+}
+
+fn tune_tcp(
+    stream: TcpStream,
+    role: Role,
+    buffer_size: usize,
+) -> Result<TcpStream, io::Error> {
+    match role {
+        Role::Sender => stream.set_send_buffer_size(buffer_size)?,
+        Role::Receiver { nodelay } => {
+            if nodelay {
+                stream.set_nodelay(true)?;
+            }
+            stream.set_recv_buffer_size(buffer_size)?;
+        }
+    }
+    Ok(stream)
+}
+
+fn split_tcp(
+    stream: TcpStream,
+    role: Role,
+) -> Result<(Box<dyn MyRead>, Box<dyn MyWrite>), io::Error> {
+    let netin = Box::new(stream.try_clone()?) as _;
+    let netout = match role {
+        Role::Sender => Box::new(NagleFlush(stream)) as _,
+        Role::Receiver { .. } => Box::new(stream) as _,
+    };
+    Ok((netin, netout))
+}
+
+fn with_default_port(url: &Url) -> Url {
+    let mut url = url.clone();
+    if url.port().is_none() {
+        url.set_port(Some(DEFAULT_PORT)).unwrap();
+    }
+    url
+}
+
+// TLS settings go unused where only tcp:// is there
+#[cfg_attr(target_os = "windows", allow(unused_variables))]
+pub fn dial(
+    url: &Url,
+    role: Role,
+    buffer_size: usize,
+    tls: Option<&TlsConfig>,
+) -> Result<(Box<dyn MyRead>, Box<dyn MyWrite>), Error<io::Error>> {
+    match url.scheme() {
+        "tcp" => {
+            let stream = trace!(TcpStream::connect(with_default_port(url)));
+            let stream = trace!(tune_tcp(stream, role, buffer_size));
+            Ok(trace!(split_tcp(stream, role)))
+        }
+        #[cfg(target_family = "unix")]
+        "tls" => {
+            let url = with_default_port(url);
+            let stream = trace!(TcpStream::connect(url.clone()));
+            let stream = trace!(tune_tcp(stream, role, buffer_size));
+            let default = TlsConfig::default();
+            let stream = trace!(tls::connect(
+                tls.unwrap_or(&default),
+                url.host_str().unwrap(),
+                stream
+            ));
+            Ok((
+                Box::new(trace!(stream.try_clone())) as _,
+                Box::new(stream) as _,
+            ))
+        }
+        #[cfg(target_family = "unix")]
+        "unix" => {
+            let stream = trace!(UnixStream::connect(url.path()));
+            Ok((
+                Box::new(trace!(stream.try_clone())) as _,
+                Box::new(stream) as _,
+            ))
+        }
+        otherwise => panic!("Scheme {} is not supported", otherwise),
+    }
+}
+
+#[cfg_attr(target_os = "windows", allow(unused_variables))]
+pub fn bind(
+    url: &Url,
+    tls: Option<&TlsConfig>,
+) -> Result<Box<dyn Listener>, Error<io::Error>> {
+    match url.scheme() {
+        "tcp" => {
+            Ok(Box::new(trace!(TcpListener::bind(with_default_port(url)))))
+        }
+        #[cfg(target_family = "unix")]
+        "tls" => {
+            let default = TlsConfig::default();
+            let acceptor = trace!(tls.unwrap_or(&default).acceptor());
+            Ok(Box::new(TlsListener {
+                listener: trace!(TcpListener::bind(with_default_port(url))),
+                acceptor: Arc::new(acceptor),
+            }))
+        }
+        #[cfg(target_family = "unix")]
+        "unix" => {
+            if Path::new(url.path()).exists() {
+                trace!(fs::remove_file(url.path()));
+            }
+            Ok(Box::new(trace!(UnixListener::bind(url.path()))))
+        }
+        otherwise => panic!("Scheme {} is not supported", otherwise),
+    }
+}
+
+// Sets up a connection that was accepted, which can take a while (the TLS
+// handshake), so it is left to the connection's own thread
+pub type Accepted = Box<
+    dyn FnOnce()
+            -> Result<(Box<dyn MyRead>, Box<dyn MyWrite>), Error<io::Error>>
+        + Send,
+>;
+
+pub trait Listener: Send {
+    fn accept(
+        &self,
+        role: Role,
+        buffer_size: usize,
+    ) -> Result<(Accepted, String), io::Error>;
+}
+
+impl Listener for TcpListener {
+    fn accept(
+        &self,
+        role: Role,
+        buffer_size: usize,
+    ) -> Result<(Accepted, String), io::Error> {
+        let (stream, addr) = self.accept()?;
+        let (netin, netout) =
+            split_tcp(tune_tcp(stream, role, buffer_size)?, role)?;
+        Ok((Box::new(move || Ok((netin, netout))), format!("{:?}", addr)))
+    }
+}
+#[cfg(target_family = "unix")]
+impl Listener for UnixListener {
+    fn accept(
+        &self,
+        _role: Role,
+        _buffer_size: usize,
+    ) -> Result<(Accepted, String), io::Error> {
+        let (stream, addr) = self.accept()?;
+        let netin: Box<dyn MyRead> = Box::new(stream.try_clone()?);
+        let netout: Box<dyn MyWrite> = Box::new(stream);
+        Ok((Box::new(move || Ok((netin, netout))), format!("{:?}", addr)))
+    }
+}
+
+#[cfg(target_family = "unix")]
+pub struct TlsListener {
+    pub listener: TcpListener,
+    pub acceptor: Arc<SslAcceptor>,
+}
+
+#[cfg(target_family = "unix")]
+impl Listener for TlsListener {
+    fn accept(
+        &self,
+        role: Role,
+        buffer_size: usize,
+    ) -> Result<(Accepted, String), io::Error> {
+        let (stream, addr) = self.listener.accept()?;
+        let stream = tune_tcp(stream, role, buffer_size)?;
+        let acceptor = self.acceptor.clone();
+        let accepted = move || accept_tls(&acceptor, stream);
+        Ok((Box::new(accepted), format!("{:?}", addr)))
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn accept_tls(
+    acceptor: &SslAcceptor,
+    stream: TcpStream,
+) -> Result<(Box<dyn MyRead>, Box<dyn MyWrite>), Error<io::Error>> {
+    let stream = trace!(tls::accept(acceptor, stream));
+    Ok((
+        Box::new(trace!(stream.try_clone())) as _,
+        Box::new(stream) as _,
+    ))
+}
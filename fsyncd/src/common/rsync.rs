@@ -17,6 +17,7 @@ pub fn server(
     netin: RawFd,
     netout: RawFd,
     src: &Path,
+    rules: &[String],
 ) -> Result<(), Error<io::Error>> {
     let netin = unsafe { libc::dup(netin) };
     let netout = unsafe { libc::dup(netout) };
@@ -40,8 +41,15 @@ pub fn server(
     trace!(trace!(Command::new("rsync")
         .args(&[
             //"rsync".into(),
-            "-avhAX".into(),
-            "--delete".into(),
+            "-avhAX",
+            "--delete",
+            // Replica state kept in xattrs must survive the resync
+            "--filter=-x user.fsyncer.*",
+        ])
+        // Keeps rsync to what the client filters in, anything else on its
+        // side is left alone rather than deleted
+        .args(rules.iter().map(|rule| format!("--filter={}", rule)))
+        .args(&[
             "-e".into(),
             std::ffi::OsString::from(format!(
                 "{} fakeshell {} {}",
@@ -0,0 +1,448 @@
+use clap::ArgMatches;
+use error::{Error, FromError};
+use openssl::ssl::{
+    SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode,
+};
+use std::io::{self, ErrorKind};
+
+// The configuration is needed everywhere, connections are only bridged to
+// plain sockets on unix
+metablock!(cfg(target_family = "unix") {
+    use libc::{poll, pollfd, POLLIN, POLLOUT};
+    use openssl::ssl::{ErrorCode, SslStream};
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+});
+
+#[cfg(target_family = "unix")]
+const TLS_BUFFER_SIZE: usize = 64 * 1024;
+// Don't let a stalled peer hold the connection's thread forever
+#[cfg(target_family = "unix")]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Without a certificate only the server can be verified, which is all a
+// client needs unless the server asks for one. Without a CA the system's
+// trusted ones are used.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub ca: Option<String>,
+}
+
+impl TlsConfig {
+    // None when no TLS options were given
+    pub fn from_matches(matches: &ArgMatches) -> Option<Self> {
+        let config = TlsConfig {
+            cert: matches.value_of("tls-cert").map(|s| s.to_string()),
+            key: matches.value_of("tls-key").map(|s| s.to_string()),
+            ca: matches.value_of("tls-ca").map(|s| s.to_string()),
+        };
+        if config.cert.is_none() && config.ca.is_none() {
+            return None;
+        }
+        Some(config)
+    }
+
+    fn identity(&self) -> Result<(&str, &str), io::Error> {
+        match (self.cert.as_ref(), self.key.as_ref()) {
+            (Some(cert), Some(key)) => Ok((cert, key)),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Accepting tls connections requires --tls-cert and --tls-key",
+            )),
+        }
+    }
+
+    // When a CA is given the peer is required to present a certificate signed
+    // by it, this is what provides mutual authentication.
+    pub fn acceptor(&self) -> Result<SslAcceptor, Error<io::Error>> {
+        let (cert, key) = trace!(self.identity());
+        let mut builder =
+            trace!(SslAcceptor::mozilla_intermediate(SslMethod::tls())
+                .map_err(ssl_error));
+        trace!(builder.set_certificate_chain_file(cert).map_err(ssl_error));
+        trace!(builder
+            .set_private_key_file(key, SslFiletype::PEM)
+            .map_err(ssl_error));
+        trace!(builder.check_private_key().map_err(ssl_error));
+        if let Some(ca) = self.ca.as_ref() {
+            trace!(builder.set_ca_file(ca).map_err(ssl_error));
+            builder.set_verify(
+                SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            );
+        }
+        Ok(builder.build())
+    }
+
+    pub fn connector(&self) -> Result<SslConnector, Error<io::Error>> {
+        let mut builder =
+            trace!(SslConnector::builder(SslMethod::tls()).map_err(ssl_error));
+        // Only needed by servers that require clients to have one
+        if let (Some(cert), Some(key)) = (self.cert.as_ref(), self.key.as_ref())
+        {
+            trace!(builder.set_certificate_chain_file(cert).map_err(ssl_error));
+            trace!(builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .map_err(ssl_error));
+            trace!(builder.check_private_key().map_err(ssl_error));
+        }
+        if let Some(ca) = self.ca.as_ref() {
+            trace!(builder.set_ca_file(ca).map_err(ssl_error));
+        }
+        Ok(builder.build())
+    }
+}
+
+fn ssl_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(ErrorKind::Other, e)
+}
+
+#[cfg(target_family = "unix")]
+pub fn accept(
+    acceptor: &SslAcceptor,
+    stream: TcpStream,
+) -> Result<UnixStream, Error<io::Error>> {
+    trace!(stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)));
+    let tls = trace!(acceptor.accept(stream).map_err(|e| io::Error::new(
+        ErrorKind::PermissionDenied,
+        format!("TLS handshake failed {}", e)
+    )));
+    Ok(trace!(bridge(tls)))
+}
+
+#[cfg(target_family = "unix")]
+pub fn connect(
+    config: &TlsConfig,
+    domain: &str,
+    stream: TcpStream,
+) -> Result<UnixStream, Error<io::Error>> {
+    let connector = trace!(config.connector());
+    let tls = trace!(connector.connect(domain, stream).map_err(|e| {
+        io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("TLS handshake failed {}", e),
+        )
+    }));
+    Ok(trace!(bridge(tls)))
+}
+
+/*
+    An SslStream cannot be split into independent read and write halves, but
+    the rest of fsyncd expects to own them separately (and rsync needs real file
+    descriptors). So the encrypted stream is pumped by a dedicated thread into
+    one end of a socket pair, the other end is handed out as a plain stream.
+*/
+#[cfg(target_family = "unix")]
+fn bridge(tls: SslStream<TcpStream>) -> Result<UnixStream, io::Error> {
+    let (local, remote) = UnixStream::pair()?;
+    tls.get_ref().set_nonblocking(true)?;
+    remote.set_nonblocking(true)?;
+    thread::spawn(move || {
+        if let Err(e) = pump(tls, remote) {
+            error!("TLS channel failed {}", e);
+        }
+    });
+    Ok(local)
+}
+
+#[cfg(target_family = "unix")]
+struct Pending {
+    buf: Vec<u8>,
+    off: usize,
+}
+
+#[cfg(target_family = "unix")]
+impl Pending {
+    fn is_empty(&self) -> bool {
+        self.off == self.buf.len()
+    }
+    fn fill(&mut self, data: &[u8]) {
+        self.buf.clear();
+        self.buf.extend_from_slice(data);
+        self.off = 0;
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn pump(
+    mut tls: SslStream<TcpStream>,
+    mut local: UnixStream,
+) -> Result<(), io::Error> {
+    let mut buf = vec![0; TLS_BUFFER_SIZE];
+    let mut to_local = Pending {
+        buf: Vec::with_capacity(TLS_BUFFER_SIZE),
+        off: 0,
+    };
+    let mut to_tls = Pending {
+        buf: Vec::with_capacity(TLS_BUFFER_SIZE),
+        off: 0,
+    };
+    loop {
+        let mut progress = false;
+        let mut tls_events = 0;
+        let mut local_events = 0;
+
+        if to_local.is_empty() {
+            match tls.ssl_read(&mut buf) {
+                Ok(0) => return local.shutdown(Shutdown::Write),
+                Ok(n) => {
+                    to_local.fill(&buf[..n]);
+                    progress = true;
+                }
+                Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => {
+                    return local.shutdown(Shutdown::Write)
+                }
+                Err(ref e) if e.code() == ErrorCode::WANT_READ => {
+                    tls_events |= POLLIN
+                }
+                Err(ref e) if e.code() == ErrorCode::WANT_WRITE => {
+                    tls_events |= POLLOUT
+                }
+                Err(e) => return Err(ssl_error(e)),
+            }
+        }
+
+        if !to_local.is_empty() {
+            match local.write(&to_local.buf[to_local.off..]) {
+                Ok(n) => {
+                    to_local.off += n;
+                    progress = true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    local_events |= POLLOUT
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if to_tls.is_empty() {
+            match local.read(&mut buf) {
+                Ok(0) => {
+                    // Best effort, the other side may be gone already
+                    let _ = tls.shutdown();
+                    return tls.get_ref().shutdown(Shutdown::Write);
+                }
+                Ok(n) => {
+                    to_tls.fill(&buf[..n]);
+                    progress = true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    local_events |= POLLIN
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !to_tls.is_empty() {
+            match tls.ssl_write(&to_tls.buf[to_tls.off..]) {
+                Ok(n) => {
+                    to_tls.off += n;
+                    progress = true;
+                }
+                Err(ref e) if e.code() == ErrorCode::WANT_READ => {
+                    tls_events |= POLLIN
+                }
+                Err(ref e) if e.code() == ErrorCode::WANT_WRITE => {
+                    tls_events |= POLLOUT
+                }
+                Err(e) => return Err(ssl_error(e)),
+            }
+        }
+
+        if progress {
+            continue;
+        }
+
+        let mut fds = [
+            pollfd {
+                fd: tls.get_ref().as_raw_fd(),
+                events: tls_events,
+                revents: 0,
+            },
+            pollfd {
+                fd: local.as_raw_fd(),
+                events: local_events,
+                revents: 0,
+            },
+        ];
+        if unsafe { poll(fds.as_mut_ptr(), fds.len() as _, -1) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "fsyncd-tls-{}-{}",
+            process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Writes a self-signed certificate for localhost and its key, returns
+    // their paths
+    fn self_signed(dir: &Path, name: &str) -> (String, String) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", "localhost").unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.append_extension(BasicConstraints::new().ca().build().unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (
+            cert_path.to_str().unwrap().to_string(),
+            key_path.to_str().unwrap().to_string(),
+        )
+    }
+
+    // Accepts one connection on loopback and echoes 4 bytes back, returns
+    // what the client got
+    fn echo(
+        server: TlsConfig,
+        client: TlsConfig,
+    ) -> (Result<(), String>, Result<Vec<u8>, String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = server.acceptor().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream =
+                accept(&acceptor, stream).map_err(|e| e.to_string())?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+            stream.write_all(&buf).map_err(|e| e.to_string())
+        });
+        let got = TcpStream::connect(addr)
+            .map_err(|e| e.to_string())
+            .and_then(|stream| {
+                connect(&client, "localhost", stream).map_err(|e| e.to_string())
+            })
+            .and_then(|mut stream| {
+                stream.write_all(b"ping").map_err(|e| e.to_string())?;
+                let mut buf = vec![0; 4];
+                stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+                Ok(buf)
+            });
+        (server.join().unwrap(), got)
+    }
+
+    #[test]
+    fn client_without_certificate() {
+        let dir = scratch_dir("server-only");
+        let (cert, key) = self_signed(&dir, "server");
+        let server = TlsConfig {
+            cert: Some(cert.clone()),
+            key: Some(key),
+            ca: None,
+        };
+        let client = TlsConfig {
+            cert: None,
+            key: None,
+            ca: Some(cert),
+        };
+        let (served, got) = echo(server, client);
+        assert_eq!(served, Ok(()));
+        assert_eq!(got, Ok(b"ping".to_vec()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mutual_authentication() {
+        let dir = scratch_dir("mutual");
+        let (server_cert, server_key) = self_signed(&dir, "server");
+        let (client_cert, client_key) = self_signed(&dir, "client");
+        let server = TlsConfig {
+            cert: Some(server_cert.clone()),
+            key: Some(server_key),
+            ca: Some(client_cert.clone()),
+        };
+        let client = TlsConfig {
+            cert: Some(client_cert),
+            key: Some(client_key),
+            ca: Some(server_cert.clone()),
+        };
+        let (served, got) = echo(server.clone(), client);
+        assert_eq!(served, Ok(()));
+        assert_eq!(got, Ok(b"ping".to_vec()));
+
+        // The server requires a certificate, one without is turned away
+        let anonymous = TlsConfig {
+            cert: None,
+            key: None,
+            ca: Some(server_cert),
+        };
+        let (served, got) = echo(server, anonymous);
+        assert!(served.is_err());
+        assert!(got.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn untrusted_server() {
+        let dir = scratch_dir("untrusted");
+        let (cert, key) = self_signed(&dir, "server");
+        let (other, _) = self_signed(&dir, "other");
+        let server = TlsConfig {
+            cert: Some(cert),
+            key: Some(key),
+            ca: None,
+        };
+        let client = TlsConfig {
+            cert: None,
+            key: None,
+            ca: Some(other),
+        };
+        let (served, got) = echo(server, client);
+        assert!(served.is_err());
+        assert!(got.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
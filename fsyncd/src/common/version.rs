@@ -0,0 +1,89 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+    Version vectors for peers that both accept writes. Every node counts the
+    changes it made to a path, a change made without having seen another one
+    is concurrent with it, which is a conflict. Counters that are 0 are never
+    stored, so equal vectors always compare equal.
+*/
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn bump(&mut self, node: &str) {
+        *self.0.entry(node.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (node, count) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_insert(0);
+            *entry = (*entry).max(*count);
+        }
+    }
+
+    fn get(&self, node: &str) -> u64 {
+        self.0.get(node).cloned().unwrap_or(0)
+    }
+}
+
+// None when the two are concurrent
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &VersionVector) -> Option<Ordering> {
+        let mut less = false;
+        let mut greater = false;
+        for node in self.0.keys().chain(other.0.keys()) {
+            match self.get(node).cmp(&other.get(node)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+// A path's version along with who changed it last and when
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Version {
+    pub vector: VersionVector,
+    pub node: String,
+    // Milliseconds since the epoch on the node that made the change
+    pub time: u64,
+}
+
+impl Version {
+    // The next version after this one, for a change made on node
+    pub fn next(&self, node: &str) -> Version {
+        let mut vector = self.vector.clone();
+        vector.bump(node);
+        Version {
+            vector,
+            node: node.to_string(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+        }
+    }
+
+    // Takes in everything other has seen, keeping whichever change was last
+    pub fn merge(&mut self, other: &Version) {
+        self.vector.merge(&other.vector);
+        if other.is_newer_than(self) {
+            self.node = other.node.clone();
+            self.time = other.time;
+        }
+    }
+
+    // Last writer wins order, the node breaks ties so both sides agree
+    pub fn is_newer_than(&self, other: &Version) -> bool {
+        (self.time, &self.node) > (other.time, &other.node)
+    }
+}
@@ -0,0 +1,127 @@
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(target_family = "unix")]
+use std::ffi::CString;
+#[cfg(target_os = "windows")]
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+/*
+    Numbers fsyncd keeps under a tree's root, in an xattr, or an alternate
+    data stream on Windows, so they stay with the tree wherever it is
+    served from. Names are given without the "user." or ":" in front.
+*/
+
+#[cfg(target_family = "unix")]
+fn xattr_name(name: &str) -> CString {
+    CString::new(format!("user.{}", name))
+        .expect("Attribute name contains a nul")
+}
+
+fn corrupted(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Persisted {} is corrupted", name),
+    )
+}
+
+// None if root doesn't have it
+#[cfg(target_family = "unix")]
+pub fn get(root: &Path, name: &str) -> Result<Option<u64>, io::Error> {
+    use common::ToCString;
+    let path = root.to_path_buf().into_cstring();
+    let xattr = xattr_name(name);
+    let mut buf = [0u8; 8];
+    let res = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            xattr.as_ptr(),
+            buf.as_mut_ptr() as *mut _,
+            buf.len(),
+        )
+    };
+    if res == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENODATA) {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+    if res as usize != buf.len() {
+        return Err(corrupted(name));
+    }
+    Ok(Some(LittleEndian::read_u64(&buf)))
+}
+
+#[cfg(target_family = "unix")]
+pub fn set(root: &Path, name: &str, value: u64) -> Result<(), io::Error> {
+    use common::ToCString;
+    let path = root.to_path_buf().into_cstring();
+    let xattr = xattr_name(name);
+    let mut buf = [0u8; 8];
+    LittleEndian::write_u64(&mut buf, value);
+    let res = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            xattr.as_ptr(),
+            buf.as_ptr() as *const _,
+            buf.len(),
+            0,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Fine if root doesn't have it
+#[cfg(target_family = "unix")]
+pub fn remove(root: &Path, name: &str) -> Result<(), io::Error> {
+    use common::ToCString;
+    let path = root.to_path_buf().into_cstring();
+    let xattr = xattr_name(name);
+    if unsafe { libc::removexattr(path.as_ptr(), xattr.as_ptr()) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ENODATA) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn stream(root: &Path, name: &str) -> OsString {
+    let mut stream = root.as_os_str().to_owned();
+    stream.push(":");
+    stream.push(name);
+    stream
+}
+
+#[cfg(target_os = "windows")]
+pub fn get(root: &Path, name: &str) -> Result<Option<u64>, io::Error> {
+    use std::fs;
+    match fs::read(stream(root, name)) {
+        Ok(ref buf) if buf.len() == 8 => Ok(Some(LittleEndian::read_u64(buf))),
+        Ok(_) => Err(corrupted(name)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn set(root: &Path, name: &str, value: u64) -> Result<(), io::Error> {
+    use std::fs;
+    let mut buf = [0u8; 8];
+    LittleEndian::write_u64(&mut buf, value);
+    fs::write(stream(root, name), &buf)
+}
+
+#[cfg(target_os = "windows")]
+pub fn remove(root: &Path, name: &str) -> Result<(), io::Error> {
+    use std::fs;
+    match fs::remove_file(stream(root, name)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
@@ -211,7 +211,7 @@ pub unsafe extern "C" fn xmp_ioctl(
     flags: c_uint,
     data: *mut c_void,
 ) -> c_int {
-    eprintln!("ioctl at {:?}", CStr::from_ptr(path));
+    info!("ioctl at {:?}", CStr::from_ptr(path));
     0
 }
 */
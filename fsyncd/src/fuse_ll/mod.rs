@@ -264,16 +264,16 @@ impl fuse::FilesystemLL for Fs {
         if e.attr.st_dev != self.src_dev {
             // Mountpoints not supported, not entirely sure why, I guess they
             // are too lazy for lookups?
-            eprintln!(
-                "WARNING: Mountpoints in the source directory tree will be \
+            warn!(
+                "Mountpoints in the source directory tree will be \
                  hidden."
             );
             return FuseReply::err(libc::ENOTSUP);
         }
 
         if e.attr.st_ino == FUSE_ROOT_ID as u64 {
-            eprintln!(
-                "ERROR: Source directory tree must not include inode {}",
+            error!(
+                "Source directory tree must not include inode {}",
                 FUSE_ROOT_ID
             );
             return FuseReply::err(libc::EIO);
@@ -1,3 +1,4 @@
+use common::metrics;
 use common::{FileSecurity, VFSCall};
 use error::{Error, FromError};
 use journal::Journal;
@@ -70,6 +71,7 @@ impl FileStore {
                 0
             };
 
+        metrics::FILESTORE_BYTES.set(current_size);
         Ok(FileStore {
             vfsroot: vfsroot.to_path_buf(),
             current_size,
@@ -108,6 +110,7 @@ impl FileStore {
             format!("{}/.fsyncer-deleted/{}", this.vfsroot.display(), token),
         ));
         this.current_size += size;
+        metrics::FILESTORE_BYTES.set(this.current_size);
         Ok(token)
     }
 
@@ -132,12 +135,17 @@ impl FileStore {
             },
         })
     }
+    // Bytes kept for deleted files, and the most that will be kept
+    pub fn fill(&self) -> (u64, u64) {
+        (self.current_size, self.max_size)
+    }
     pub fn delete(&mut self, token: u64) -> Result<u64, Error<io::Error>> {
         let path =
             format!("{}/.fsyncer-deleted/{}", self.vfsroot.display(), token);
         let size = trace!(fs::symlink_metadata(&path)).len();
         trace!(fs::remove_file(&path));
         self.current_size -= size;
+        metrics::FILESTORE_BYTES.set(self.current_size);
         self.oldest_token += 1;
         Ok(size)
     }
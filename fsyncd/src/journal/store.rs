@@ -9,9 +9,11 @@ use std::{fs::OpenOptions, io::Read};
 
 use bincode::{deserialize, deserialize_from, serialize_into, serialized_size};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use common::metrics;
 use error::{Error, FromError};
 use journal::{crc32, filestore::FileStore, JournalType};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
@@ -36,7 +38,8 @@ lazy_static! {
 struct JournalHeader {
     tail: u64,
     head: u64,
-    trans_ctr: u32,
+    // Ids are never reused, replicas resume from them
+    trans_ctr: u64,
     ty: JournalType,
 }
 
@@ -49,13 +52,13 @@ pub enum EntryContent<T> {
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct StoreEntry<T> {
     fsize: u32,
-    trans_id: u32,
+    trans_id: u64,
     inner: EntryContent<T>,
     crc32: u32,
 }
 
 impl<T> StoreEntry<T> {
-    pub fn trans_id(&self) -> u32 {
+    pub fn trans_id(&self) -> u64 {
         self.trans_id
     }
     pub fn contents(&self) -> &EntryContent<T> {
@@ -82,6 +85,9 @@ pub struct Journal {
     sbuf: Vec<u8>,
     last_time: SystemTime,
     fstore: FileStore,
+    // Id and offset of the first entry in each block, entries never span
+    // blocks so an entry is found by reading at most one
+    index: VecDeque<(u64, u64)>,
 }
 
 pub trait Direction: Sized {}
@@ -164,6 +170,13 @@ where
     }
 }
 
+impl<'a, T> JournalIterator<'a, Forward, T> {
+    // Where the next entry will be read from, see Journal::read_forward_from
+    pub fn offset(&self) -> u64 {
+        self.header.head
+    }
+}
+
 impl<'a, T: Debug + Sized> Iterator for JournalIterator<'a, Reverse, T>
 where
     for<'de> T: Deserialize<'de>,
@@ -273,6 +286,7 @@ impl Journal {
             sync: c.sync,
             last_time: SystemTime::now(),
             fstore: trace!(FileStore::new(&c.vfsroot, c.filestore_size)),
+            index: VecDeque::new(),
         })
     }
     pub fn open(
@@ -302,15 +316,17 @@ impl Journal {
             sync: c.sync,
             last_time: SystemTime::now(),
             fstore: trace!(FileStore::new(&c.vfsroot, c.filestore_size)),
+            index: VecDeque::new(),
         };
 
-        eprintln!("Traversing the journal {:?}", j.header);
+        info!("Traversing the journal {:?}", j.header);
 
-        let mut tx_max = j.header.trans_ctr as i64 - 1; // Because the ctr has been advanced before flush
+        // The ctr has been advanced before flush
+        let mut next = j.header.trans_ctr;
         let mut new_tail = j.header.tail;
         loop {
             if new_tail > align_up_always(j.header.tail, BLOCK_SIZE) {
-                eprintln!("Traversing past block boundary");
+                info!("Traversing past block boundary");
                 break;
             }
             if align_up_always(new_tail, BLOCK_SIZE) - new_tail < 4 {
@@ -325,24 +341,39 @@ impl Journal {
             new_tail += fsize as u64;
             // FIXME next_tx is not neccessarily correct, it may be leftover
             // data from the previous block, I need to validate this entry.
-            let next_tx = trace!(j.file.read_u32::<LittleEndian>());
-            //eprintln!("Next tx {} old tx {}", next_tx, tx_max);
-            // Allows for overflow to happen
-            if next_tx != (tx_max + 1) as u32 {
-                debug!(next_tx, tx_max + 1);
+            let next_tx = trace!(j.file.read_u64::<LittleEndian>());
+            //eprintln!("Next tx {} expected tx {}", next_tx, next);
+            if next_tx != next {
+                debug!(next_tx, next);
                 break;
             }
-            tx_max = next_tx as i64;
+            next = next_tx + 1;
         }
 
         j.header.tail = new_tail;
-        j.header.trans_ctr = (tx_max + 1) as u32;
+        j.header.trans_ctr = next;
+        trace!(j.build_index());
 
         debug!(j.header);
 
         Ok(j)
     }
 
+    // Reads the id of the first entry of every block that has entries
+    fn build_index(&mut self) -> Result<(), Error<io::Error>> {
+        let mut block = self.header.head;
+        while block < self.header.tail {
+            trace!(self.seek(block));
+            let fsize = trace!(self.file.read_u32::<LittleEndian>());
+            if fsize != 0 {
+                let trans_id = trace!(self.file.read_u64::<LittleEndian>());
+                self.index.push_back((trans_id, block));
+            }
+            block = align_up_always(block, BLOCK_SIZE);
+        }
+        Ok(())
+    }
+
     fn write_inner<T: Serialize>(
         &mut self,
         mut e: StoreEntry<T>,
@@ -382,9 +413,16 @@ impl Journal {
             // Journal is full, will move head
             if self.header.head + self.size == self.header.tail {
                 self.header.head += BLOCK_SIZE as u64;
+                let head = self.header.head;
+                while self.index.front().map_or(false, |&(_, o)| o < head) {
+                    self.index.pop_front();
+                }
             }
             trace!(self.write_header());
         }
+        if self.header.tail % BLOCK_SIZE == 0 {
+            self.index.push_back((e.trans_id, self.header.tail));
+        }
 
         if self.sbuf.capacity() < esize as usize {
             self.sbuf = Vec::with_capacity(esize as usize);
@@ -406,6 +444,7 @@ impl Journal {
             .write_all_at(&self.sbuf, self.file_off(self.header.tail)));
         self.header.tail += esize;
         self.header.trans_ctr += 1;
+        metrics::JOURNAL_BYTES.add(esize);
 
         if self.sync {
             trace!(self.flush());
@@ -424,7 +463,7 @@ impl Journal {
             .expect("System clock anomaly")
             > Duration::from_secs(1)
         {
-            let ctr = self.header.trans_ctr as u32;
+            let ctr = self.header.trans_ctr;
             trace!(self.write_inner::<T>(StoreEntry {
                 fsize: 0,
                 trans_id: ctr,
@@ -435,7 +474,7 @@ impl Journal {
         }
         let e = StoreEntry {
             fsize: 0,
-            trans_id: self.header.trans_ctr as u32,
+            trans_id: self.header.trans_ctr,
             inner: EntryContent::Payload(entry),
             crc32: 0,
         };
@@ -463,6 +502,13 @@ impl Journal {
         Ok(())
     }
 
+    // Everything written so far survives a crash without being traversed
+    pub fn sync(&mut self) -> Result<(), Error<io::Error>> {
+        trace!(self.write_header());
+        trace!(self.file.sync_data());
+        Ok(())
+    }
+
     fn write_header(&mut self) -> Result<(), Error<io::Error>> {
         let mut buf = Vec::with_capacity(*HEADER_SIZE as usize);
         trace!(serialize_into(&mut buf, &self.header)
@@ -480,6 +526,53 @@ impl Journal {
             block_buffer: Vec::new(),
         }
     }
+    // Resumes reading from JournalIterator::offset, the journal may be
+    // written in between. None if the entries there have been overwritten.
+    pub fn read_forward_from<T>(
+        &mut self,
+        offset: u64,
+    ) -> Option<JournalIterator<Forward, T>> {
+        if offset < self.header.head || offset > self.header.tail {
+            return None;
+        }
+        let mut header = self.header.clone();
+        header.head = offset;
+        Some(JournalIterator {
+            direction: PhantomData,
+            inner_t: PhantomData,
+            header,
+            journal: self,
+            block_buffer: Vec::new(),
+        })
+    }
+    // Offset to resume reading from after the entry trans_id, None if the
+    // journal no longer has it
+    pub fn offset_after<T: Debug>(
+        &mut self,
+        trans_id: u64,
+    ) -> Result<Option<u64>, Error<io::Error>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let block = match self.index.binary_search_by_key(&trans_id, |b| b.0) {
+            Ok(i) => self.index[i].1,
+            Err(0) => return Ok(None),
+            Err(i) => self.index[i - 1].1,
+        };
+        let mut entries = match self.read_forward_from::<T>(block) {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+        while let Some(entry) = entries.next() {
+            let id = trace!(entry).trans_id();
+            if id == trans_id {
+                return Ok(Some(entries.offset()));
+            } else if id > trans_id {
+                break;
+            }
+        }
+        Ok(None)
+    }
     pub fn read_reverse<T>(&mut self) -> JournalIterator<Reverse, T> {
         JournalIterator {
             direction: PhantomData,
@@ -492,6 +585,14 @@ impl Journal {
     pub fn journal_type(&self) -> JournalType {
         self.header.ty
     }
+    // Id that the next entry will be written with
+    pub fn trans_ctr(&self) -> u64 {
+        self.header.trans_ctr
+    }
+    // Bytes taken up by entries, and how many the journal can hold
+    pub fn fill(&self) -> (u64, u64) {
+        (self.header.tail - self.header.head, self.size)
+    }
 }
 
 // #[test]
@@ -543,3 +644,96 @@ impl Journal {
 //     let mut j = inner().unwrap();
 //     b.iter(|| j.write_entry(&buf[..]).expect("Write failed"));
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // 3 fit in a block
+    const ENTRY: [u8; 40000] = [0; 40000];
+
+    fn config(dir: &PathBuf) -> JournalConfig {
+        JournalConfig {
+            sync: false,
+            journal_size: 0,
+            filestore_size: 0,
+            vfsroot: dir.clone(),
+            journal_type: JournalType::Forward,
+        }
+    }
+
+    fn open(dir: &PathBuf) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.join("journal"))
+            .unwrap()
+    }
+
+    // A journal of blocks with entries written to it, and its directory
+    fn journal(name: &str, blocks: u64, entries: usize) -> (Journal, PathBuf) {
+        let dir = env::temp_dir().join(format!(
+            "fsyncd-journal-{}-{}",
+            process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let file = open(&dir);
+        file.set_len(*HEADER_SIZE + blocks * BLOCK_SIZE).unwrap();
+        let mut j = Journal::new(file, config(&dir)).unwrap();
+        for _ in 0..entries {
+            j.write_entry(&ENTRY[..]).unwrap();
+        }
+        (j, dir)
+    }
+
+    // Id of the entry read after the one trans_id, None if it was the last
+    fn id_after(j: &mut Journal, trans_id: u64) -> Option<Option<u64>> {
+        let offset = j.offset_after::<Vec<u8>>(trans_id).unwrap()?;
+        let mut entries = j.read_forward_from::<Vec<u8>>(offset).unwrap();
+        Some(entries.next().map(|e| e.unwrap().trans_id()))
+    }
+
+    #[test]
+    fn offset_after_finds_entries_in_every_block() {
+        let (mut j, _) = journal("blocks", 4, 10);
+        assert_eq!(j.index.len(), 4);
+        let last = j.trans_ctr() - 1;
+        // First block, the middle of one, and the last entry
+        assert_eq!(id_after(&mut j, 0), Some(Some(1)));
+        assert_eq!(id_after(&mut j, 4), Some(Some(5)));
+        assert_eq!(id_after(&mut j, last), Some(None));
+        assert_eq!(j.offset_after::<Vec<u8>>(last + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn offset_after_trimmed_entries_is_none() {
+        let (mut j, _) = journal("trimmed", 4, 15);
+        let (first, _) = j.index[0];
+        assert!(first > 0);
+        assert_eq!(j.offset_after::<Vec<u8>>(0).unwrap(), None);
+        assert_eq!(j.offset_after::<Vec<u8>>(first - 1).unwrap(), None);
+        assert_eq!(id_after(&mut j, first), Some(Some(first + 1)));
+    }
+
+    #[test]
+    fn index_is_rebuilt_on_reopen() {
+        let (mut j, dir) = journal("reopen", 4, 15);
+        j.sync().unwrap();
+        let index = j.index.clone();
+        let fill = j.fill();
+        let last = j.trans_ctr() - 1;
+        drop(j);
+        let mut j = Journal::open(open(&dir), config(&dir)).unwrap();
+        assert_eq!(j.index, index);
+        assert_eq!(j.fill(), fill);
+        assert_eq!(j.trans_ctr(), last + 1);
+        let (first, _) = j.index[0];
+        assert_eq!(id_after(&mut j, first), Some(Some(first + 1)));
+        assert_eq!(id_after(&mut j, last), Some(None));
+    }
+}
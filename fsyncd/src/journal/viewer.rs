@@ -151,7 +151,7 @@ pub fn viewer_main(matches: ArgMatches) {
         Some("view") => match j.journal_type() {
             JournalType::Forward => {
                 if journal_matches.is_present("reverse") {
-                    eprintln!(
+                    warn!(
                         "You are viewing a forward-only journal in reverse, \
                          it cannot be replayed in this direction!"
                     )
@@ -160,7 +160,7 @@ pub fn viewer_main(matches: ArgMatches) {
             }
             JournalType::Undo => {
                 if !journal_matches.is_present("reverse") {
-                    eprintln!(
+                    warn!(
                         "You are viewing a undo-only journal forward, it \
                          cannot be replayed in this direction!"
                     )
@@ -21,15 +21,16 @@ extern crate bincode;
 extern crate byteorder;
 extern crate clap;
 extern crate crc;
-extern crate dssc;
 extern crate either;
 extern crate errno;
 extern crate fuse;
 extern crate libc;
 extern crate lz4;
 extern crate net2;
+extern crate openssl;
 extern crate regex;
 extern crate serde;
+extern crate serde_json;
 extern crate url;
 extern crate walkdir;
 extern crate zstd;
@@ -54,20 +55,62 @@ macro_rules! flagset {
     };
 }
 
+// Fields go before the message, log!(Level::Info, client = label; "...")
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if ::common::logging::enabled($level, module_path!()) {
+            ::common::logging::write(
+                $level,
+                module_path!(),
+                &[$((stringify!($key), &$value as &dyn std::fmt::Debug)),+],
+                format_args!($($arg)+),
+            )
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if ::common::logging::enabled($level, module_path!()) {
+            ::common::logging::write(
+                $level,
+                module_path!(),
+                &[],
+                format_args!($($arg)+),
+            )
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { log!(::common::logging::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { log!(::common::logging::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { log!(::common::logging::Level::Info, $($arg)+) };
+}
+
+// Logs the values of expressions, debug!(head, tail)
 #[macro_export]
 macro_rules! debug {
     ($($e:expr),+) => {
-        //#[cfg(debug_assertions)]
-        #[allow(unused_unsafe)]
-        {
-            if unsafe {::DEBUG } {
-                $(
-                    eprint!(concat!(stringify!($e), "={:?} "), $e);
-                )*
-                eprintln!();
-            }
+        if ::common::logging::enabled(
+            ::common::logging::Level::Debug,
+            module_path!(),
+        ) {
+            ::common::logging::write(
+                ::common::logging::Level::Debug,
+                module_path!(),
+                &[$((stringify!($e), &$e as &dyn std::fmt::Debug)),+],
+                format_args!(""),
+            )
         }
-    }
+    };
 }
 
 #[macro_export]
@@ -114,9 +157,14 @@ mod server;
 
 use std::process::exit;
 
-use clap::{App, AppSettings, Arg, ArgGroup, ErrorKind, SubCommand};
-use client::{client_main, ConnectionBuilder};
-use common::{parse_human_size, ClientMode, CompMode, InitMsg, Options};
+use clap::{App, AppSettings, Arg, ErrorKind, SubCommand};
+use client::{client_main, print_control_info, ConnectionBuilder};
+use common::auth::AuthConfig;
+use common::tls::TlsConfig;
+use common::{
+    parse_human_size, Capabilities, ClientMode, CompMode, ControlCmd, InitMsg,
+    Options, PROTOCOL_VERSION,
+};
 use server::server_main;
 use std::path::Path;
 
@@ -138,7 +186,7 @@ metablock!(cfg(target_os = "windows") {
 extern "C" fn stop_profiler(_: i32) {
     use cpuprofiler::PROFILER;
     PROFILER.lock().unwrap().stop().unwrap();
-    eprintln!("Stopped profiler");
+    info!("Stopped profiler");
     exit(0);
 }
 
@@ -148,7 +196,7 @@ fn start_profiler() {
     use nix::sys::signal;
     PROFILER.lock().unwrap().start("./fsyncd.profile").unwrap();
 
-    eprintln!("Started profiler");
+    info!("Started profiler");
 
     let sig_action = signal::SigAction::new(
         signal::SigHandler::Handler(stop_profiler),
@@ -161,8 +209,6 @@ fn start_profiler() {
     };
 }
 
-pub static mut DEBUG: bool = false;
-
 const VERSION: &str = env!("VERSION");
 
 fn main() {
@@ -175,6 +221,13 @@ fn main() {
             "Do initial replication using rsync, NOTE: rsync must be present \
              in path",
         ),
+        Arg::with_name("metrics")
+            .long("metrics")
+            .help(
+                "Serve Prometheus metrics over HTTP on this address, at \
+                 /metrics",
+            )
+            .takes_value(true),
     ];
     let net_args = &[
         Arg::with_name("url")
@@ -182,8 +235,9 @@ fn main() {
             .takes_value(true)
             .default_value("tcp://localhost:2323")
             .help(
-                "Can be tcp://<host>:<port>, unix:<path>, stdio:, server \
-                 binds on this address, client connects",
+                "Can be tcp://<host>:<port>, tls://<host>:<port>, \
+                 unix:<path>, stdio:, server binds on this address, client \
+                 connects (or binds with --listen)",
             ),
         Arg::with_name("buffer")
             .long("buffer")
@@ -191,10 +245,264 @@ fn main() {
             .help("TX/RX buffer size")
             .takes_value(true),
     ];
+    let tls_args = &[
+        Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help(
+                "PEM certificate (chain) presented to the other side, \
+                 required to listen, for a client only when the server \
+                 requires one",
+            )
+            .requires("tls-key")
+            .takes_value(true),
+        Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("PEM private key for --tls-cert")
+            .requires("tls-cert")
+            .takes_value(true),
+        Arg::with_name("tls-ca")
+            .long("tls-ca")
+            .help(
+                "PEM CA bundle used to verify the other side, when given to \
+                 the server clients must present a certificate signed by it, \
+                 the system's trusted CAs are used otherwise",
+            )
+            .takes_value(true),
+    ];
+    let heartbeat_args = &[
+        Arg::with_name("heartbeat-interval")
+            .long("heartbeat-interval")
+            .default_value("1")
+            .help("Interval in seconds between heartbeats sent to the peer")
+            .takes_value(true),
+        Arg::with_name("heartbeat-timeout")
+            .long("heartbeat-timeout")
+            .default_value("10")
+            .help(
+                "Seconds without hearing from the peer before it is \
+                 considered dead, must be longer than the peer's heartbeat \
+                 interval",
+            )
+            .takes_value(true),
+    ];
+    let filter_args = &[
+        Arg::with_name("include")
+            .long("include")
+            .help(
+                "Only replicate this subtree (a path from the root, * and ? \
+                 match within a component), can be given more than once",
+            )
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .help(
+                "Don't replicate paths matching this pattern, matched at any \
+                 depth unless it starts with /, can be given more than once",
+            )
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+    ];
+    let auth_args = &[
+        Arg::with_name("auth-secret")
+            .long("auth-secret")
+            .help(
+                "File containing the secret used to authenticate with the \
+                 server",
+            )
+            .takes_value(true),
+        Arg::with_name("auth-name")
+            .long("auth-name")
+            .help("Identity presented to the server, defaults to hostname")
+            .requires("auth-secret")
+            .takes_value(true),
+    ];
 
     let server = SubCommand::with_name("server")
         .args(client_and_server_args)
         .args(net_args)
+        .args(tls_args)
+        .args(heartbeat_args)
+        .arg(
+            Arg::with_name("response-timeout")
+                .long("response-timeout")
+                .default_value("10")
+                .help(
+                    "Seconds to wait for a synchronous client to acknowledge \
+                     an operation",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fallback-timeout")
+                .long("fallback-timeout")
+                .help(
+                    "Milliseconds a synchronous client has to acknowledge a \
+                     write, one that misses it is treated as asynchronous \
+                     until it catches up",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .help(
+                    "Never journal or replicate paths matching this pattern, \
+                     matched at any depth unless it starts with /, can be \
+                     given more than once",
+                )
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude-from")
+                .long("exclude-from")
+                .help(
+                    "File with more patterns for --exclude, one per line, \
+                     lines starting with # are ignored",
+                )
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("quorum")
+                .long("quorum")
+                .help(
+                    "Return a write once this many synchronous clients have \
+                     acknowledged it instead of all of them, the rest are \
+                     still checked as they come in. Writes fewer of them \
+                     acknowledge fail with EIO, after they were made. While \
+                     fewer are connected writes fail with EIO up front",
+                )
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("new-epoch").long("new-epoch").help(
+            "Start a new epoch as primary, for a replica taking over from \
+             a primary that is gone for good. Without it a tree that \
+             followed a newer primary than it served as refuses writes",
+        ))
+        .arg(
+            Arg::with_name("auth-secret")
+                .long("auth-secret")
+                .help(
+                    "File containing the secret shared by all replicas, \
+                     replicas that can't prove they know it are rejected",
+                )
+                .conflicts_with("auth-keys")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auth-keys")
+                .long("auth-keys")
+                .help(
+                    "File with one \"<identity> <secret>\" per line, \
+                     replicas are only accepted with the secret for their \
+                     identity",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replica")
+                .long("replica")
+                .help(
+                    "Connect out to a replica running client --listen at this \
+                     url, reconnecting whenever the connection is lost",
+                )
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("peer")
+                .long("peer")
+                .help(
+                    "Server of another node that also accepts writes, the two \
+                     replicate to each other, both need --node-id",
+                )
+                .requires("node-id")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("node-id")
+                .long("node-id")
+                .help(
+                    "Name of this node, must be unique among peers, accepts \
+                     writes from peers when given",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("conflict")
+                .long("conflict")
+                .help(
+                    "What to do with a change from a peer to a path that was \
+                     also changed here, keep the last one, keep both with the \
+                     peer's in a .conflict-<node> copy, or drop the peer's",
+                )
+                .takes_value(true)
+                .default_value("lww")
+                .possible_values(&["lww", "keep-both", "reject"]),
+        )
+        .arg(
+            Arg::with_name("on-mismatch")
+                .long("on-mismatch")
+                .help(
+                    "What to do when a replica's result for an op differs \
+                     from the server's, only log it, disconnect the replica \
+                     until it resynchronises, or send it the affected paths \
+                     again, disconnecting it if the server no longer has one",
+                )
+                .takes_value(true)
+                .default_value("log")
+                .possible_values(&["log", "disconnect", "resync"]),
+        )
+        .arg(
+            Arg::with_name("divergence-log")
+                .long("divergence-log")
+                .help(
+                    "Directory to record ops a replica's result differed on, \
+                     in a file per replica",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("send-queue")
+                .long("send-queue")
+                .help(
+                    "Memory each asynchronous client can have queued to be \
+                     sent, so writes don't wait on its connection, 0 sends \
+                     from the writing thread instead",
+                )
+                .takes_value(true)
+                .default_value("64M"),
+        )
+        .arg(
+            Arg::with_name("queue-full")
+                .long("queue-full")
+                .help(
+                    "What to do when a send queue is full, wait for it to \
+                     drain, spill what doesn't fit to disk, or disconnect \
+                     the client so it resumes from the journal",
+                )
+                .takes_value(true)
+                .default_value("block")
+                .possible_values(&["block", "spill", "disconnect"]),
+        )
+        .arg(
+            Arg::with_name("spill-dir")
+                .long("spill-dir")
+                .help(
+                    "Directory send queues spill to, the system's temporary \
+                     directory by default",
+                )
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("journal")
                 .long("journal")
@@ -250,10 +558,42 @@ fn main() {
     let client = SubCommand::with_name("client")
         .args(client_and_server_args)
         .args(net_args)
+        .args(tls_args)
+        .args(auth_args)
+        .args(heartbeat_args)
+        .args(filter_args)
+        .arg(Arg::with_name("listen").long("listen").help(
+            "Bind on the url and wait for the server to connect, see \
+                 --replica on the server",
+        ))
+        .arg(
+            Arg::with_name("downstream")
+                .long("downstream")
+                .help(
+                    "Also serve replicas of this replica on this url, \
+                     everything applied here is forwarded to them",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("downstream-auth-secret")
+                .long("downstream-auth-secret")
+                .help("Same as the server's --auth-secret, for --downstream")
+                .requires("downstream")
+                .conflicts_with("downstream-auth-keys")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("downstream-auth-keys")
+                .long("downstream-auth-keys")
+                .help("Same as the server's --auth-keys, for --downstream")
+                .requires("downstream")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("rt-compressor")
                 .long("rt-compressor")
-                .possible_values(&["default", "chunked", "zstd", "none"])
+                .possible_values(&["default", "zstd", "none"])
                 .default_value("none")
                 .help("Discrete compression method to use")
                 .takes_value(true),
@@ -282,7 +622,10 @@ fn main() {
                 .long("threads")
                 .takes_value(true)
                 .default_value("1")
-                .help("Sets number of dispatch threads"),
+                .help(
+                    "Sets number of dispatch threads, ops on unrelated paths \
+                     are applied in parallel",
+                ),
         )
         .arg(
             Arg::with_name("iolimit")
@@ -316,7 +659,31 @@ fn main() {
         .arg(
             Arg::with_name("debug")
                 .long("debug")
-                .help("Enables debug output"),
+                .help("Enables debug output, same as --log-level debug"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .default_value("info")
+                .help(
+                    "error, warn, info or debug, optionally followed by \
+                     levels for modules, like info,server::client=debug",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .help("Append the log to this file instead of stderr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("syslog")
+                .long("syslog")
+                .conflicts_with("log-file")
+                .help(
+                    "Send the log to syslog (and journald) instead of stderr",
+                ),
         )
         .subcommand(client)
         .subcommand(server)
@@ -369,25 +736,79 @@ fn main() {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("checksum").arg(
-                Arg::with_name("mount-path")
-                    .help("Path to compute checksum of")
-                    .required(true)
-                    .multiple(true)
-                    .takes_value(true),
-            ),
+            SubCommand::with_name("checksum")
+                .arg(
+                    Arg::with_name("mount-path")
+                        .help("Path to compute checksum of")
+                        .required(true)
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .args(filter_args),
         )
         .subcommand(
             SubCommand::with_name("control")
-                .group(ArgGroup::with_name("cmd").required(true))
+                .arg(Arg::with_name("cmd").required(true).possible_values(&[
+                    "cork",
+                    "uncork",
+                    "promote",
+                    "clients",
+                    "disconnect",
+                    "iolimit",
+                    "flush-journal",
+                    "journal",
+                ]))
                 .arg(
-                    Arg::with_name("host")
-                        .required(true)
+                    Arg::with_name("replica")
+                        .long("replica")
+                        .takes_value(true)
+                        .required_if("cmd", "promote")
+                        .help(
+                            "Downstream url of the replica to promote, the \
+                             other replicas are pointed to it",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("client")
+                        .long("client")
+                        .takes_value(true)
+                        .required_ifs(&[
+                            ("cmd", "disconnect"),
+                            ("cmd", "iolimit"),
+                        ])
+                        .help("Id of the client, as listed by clients"),
+                )
+                .arg(
+                    Arg::with_name("rate")
+                        .long("rate")
+                        .takes_value(true)
+                        .required_if("cmd", "iolimit")
+                        .help(
+                            "Bytes per second to send the client, 0 for no \
+                             limit",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print what the server reports as JSON"),
+                )
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
                         .takes_value(true)
-                        .default_value("localhost"),
+                        .default_value("tcp://localhost:2323")
+                        .help("Address of the server to control"),
+                )
+                .arg(
+                    Arg::with_name("buffer")
+                        .long("buffer")
+                        .default_value("1M")
+                        .help("TX/RX buffer size")
+                        .takes_value(true),
                 )
-                .arg(Arg::with_name("cork").group("cmd"))
-                .arg(Arg::with_name("uncork").group("cmd")),
+                .args(tls_args)
+                .args(auth_args),
         )
         .subcommand(
             SubCommand::with_name("fakeshell")
@@ -411,9 +832,7 @@ fn main() {
             _ => e.exit(),
         });
 
-    if matches.is_present("debug") {
-        unsafe { DEBUG = true };
-    }
+    common::logging::configure(&matches).expect("Invalid logging options");
 
     #[cfg(feature = "profile")]
     start_profiler();
@@ -426,53 +845,105 @@ fn main() {
         #[cfg(target_family = "unix")]
         Some("journal") => viewer_main(matches),
         Some("checksum") => {
+            use common::filter::{FilterSpec, PathFilter};
             use common::hash_metadata;
             let matches = matches.subcommand_matches("checksum").unwrap();
-            let hash = hash_metadata(Path::new(
-                matches
-                    .value_of("mount-path")
-                    .expect("No destination specified"),
-            ))
+            let filter = PathFilter::new(&FilterSpec::from_matches(matches))
+                .expect("Invalid filter");
+            let hash = hash_metadata(
+                Path::new(
+                    matches
+                        .value_of("mount-path")
+                        .expect("No destination specified"),
+                ),
+                &filter,
+            )
             .expect("Hash failed");
             eprintln!("{:x}", hash);
         }
         Some("control") => {
+            use common::filter::FilterSpec;
             use url::Url;
             let control_matches =
                 matches.subcommand_matches("control").unwrap();
             let buffer_size =
-                parse_human_size(matches.value_of("buffer").unwrap())
+                parse_human_size(control_matches.value_of("buffer").unwrap())
                     .expect("Buffer size format incorrect");
-            let url = Url::parse(matches.value_of("url").unwrap())
+            let url = Url::parse(control_matches.value_of("url").unwrap())
                 .expect("Invalid url specified");
+            let tls = TlsConfig::from_matches(control_matches);
+            let auth = AuthConfig::from_matches(control_matches)
+                .expect("Failed to load authentication secret");
 
-            eprintln!("cmd {:?}", control_matches.value_of("cmd"));
+            debug!(control_matches.value_of("cmd"));
 
             let mut client = ConnectionBuilder::with_url(
                 &url,
                 true,
                 buffer_size,
+                tls.as_ref(),
+                auth.as_ref(),
                 InitMsg {
+                    version: PROTOCOL_VERSION,
+                    capabilities: Capabilities::empty(),
                     mode: ClientMode::MODE_CONTROL,
                     compress: CompMode::empty(),
                     dsthash: 0,
                     iolimit_bps: 0,
                     options: Options::empty(),
+                    identity: None,
+                    resume_from: None,
+                    filter: FilterSpec::default(),
+                    downstream: None,
+                    epoch: 0,
                 },
             )
             .expect("Failed to initialize client")
             .build()
             .expect("Failed to create server connection");
 
+            let client_id = || {
+                control_matches
+                    .value_of("client")
+                    .unwrap()
+                    .parse()
+                    .expect("Invalid client id")
+            };
+            let json = control_matches.is_present("json");
+            let cmd = match control_matches.value_of("cmd").unwrap() {
+                "clients" => Some(ControlCmd::ListClients),
+                "disconnect" => Some(ControlCmd::Disconnect(client_id())),
+                "iolimit" => Some(ControlCmd::SetIolimit(
+                    client_id(),
+                    parse_human_size(control_matches.value_of("rate").unwrap())
+                        .expect("Invalid format for rate"),
+                )),
+                "flush-journal" => Some(ControlCmd::FlushJournal),
+                "journal" => Some(ControlCmd::JournalStatus),
+                _ => None,
+            };
+            if let Some(cmd) = cmd {
+                let info = client
+                    .control(cmd)
+                    .expect("Failed to execute command server");
+                print_control_info(&info, json);
+                return;
+            }
+
             match control_matches.value_of("cmd").unwrap() {
                 "cork" => {
-                    eprintln!("Corking");
+                    info!("Corking");
                     client.cork_server()
                 }
                 "uncork" => {
-                    eprintln!("Uncorking");
+                    info!("Uncorking");
                     client.uncork_server()
                 }
+                "promote" => {
+                    let replica = control_matches.value_of("replica").unwrap();
+                    info!("Promoting {}", replica);
+                    client.promote(replica)
+                }
                 _ => unreachable!(),
             }
             .expect("Failed to execute command server");
@@ -0,0 +1,169 @@
+use common::filter::{PathFilter, Route};
+use common::{translate_path, FileSecurity, VFSCall};
+use libc::{O_CREAT, O_TRUNC, O_WRONLY};
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// File contents are sent in writes of this size
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/*
+    A replica behind a filter never saw what is on the other side of it, so a
+    rename or link across the boundary can't be sent as is. Leaving, what the
+    replica has under the source is removed, entering, the source is recreated
+    under the destination from the backing store. This runs before the op is
+    applied, so the source is still where the op says it is.
+*/
+pub fn expand<F: FnMut(VFSCall<'static>)>(
+    call: &VFSCall,
+    route: &Route,
+    filter: &PathFilter,
+    root: &Path,
+    mut send: F,
+) -> Result<(), io::Error> {
+    let (from, to) = match call {
+        VFSCall::rename { from, to, .. } => (from, to),
+        VFSCall::link { from, to, .. } => (from, to),
+        _ => return Ok(()),
+    };
+    let src = translate_path(from, root);
+    match route {
+        Route::Leaving => {
+            // Contents first, directories have to be empty to be removed
+            for entry in WalkDir::new(&src).contents_first(true) {
+                let entry = entry?;
+                let path =
+                    under(from, entry.path().strip_prefix(&src).unwrap());
+                if !filter.allows(&path) {
+                    continue;
+                }
+                let path = Cow::Owned(path);
+                send(if entry.file_type().is_dir() {
+                    VFSCall::rmdir { path }
+                } else {
+                    VFSCall::unlink { path }
+                });
+            }
+        }
+        Route::Entering => recreate_tree(&src, to, filter, &mut send)?,
+        Route::Send | Route::Skip => {}
+    }
+    Ok(())
+}
+
+// Sends path as the backing store has it now, to a replica that diverged.
+// Anything the replica has under it that the server doesn't stays there.
+// False when the server no longer has path, what the replica has there is
+// not known, a directory with anything in it couldn't be removed.
+pub fn resync<F: FnMut(VFSCall<'static>)>(
+    path: &Path,
+    filter: &PathFilter,
+    root: &Path,
+    mut send: F,
+) -> Result<bool, io::Error> {
+    if !filter.allows(path) {
+        return Ok(true);
+    }
+    let real = translate_path(path, root);
+    match fs::symlink_metadata(&real) {
+        Ok(_) => recreate_tree(&real, path, filter, &mut send).map(|_| true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Recreates src and everything under it at to, as far as filter allows
+fn recreate_tree<F: FnMut(VFSCall<'static>)>(
+    src: &Path,
+    to: &Path,
+    filter: &PathFilter,
+    send: &mut F,
+) -> Result<(), io::Error> {
+    let mut entries = WalkDir::new(src).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let path = under(to, entry.path().strip_prefix(src).unwrap());
+        if !filter.allows(&path) {
+            if entry.file_type().is_dir() {
+                entries.skip_current_dir();
+            }
+            continue;
+        }
+        recreate(entry.path(), path, send)?;
+    }
+    Ok(())
+}
+
+fn under(base: &Path, rel: &Path) -> PathBuf {
+    // Joining an empty path would leave a trailing /
+    if rel.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(rel)
+    }
+}
+
+fn recreate<F: FnMut(VFSCall<'static>)>(
+    real: &Path,
+    path: PathBuf,
+    send: &mut F,
+) -> Result<(), io::Error> {
+    let stat = fs::symlink_metadata(real)?;
+    let security = FileSecurity::Unix {
+        uid: stat.uid(),
+        gid: stat.gid(),
+    };
+    let file_type = stat.file_type();
+    if file_type.is_dir() {
+        send(VFSCall::mkdir {
+            path: Cow::Owned(path),
+            security,
+            mode: stat.mode() & 0o7777,
+        });
+    } else if file_type.is_symlink() {
+        send(VFSCall::symlink {
+            from: Cow::Owned(fs::read_link(real)?),
+            to: Cow::Owned(path),
+            security,
+        });
+    } else if file_type.is_file() {
+        send(VFSCall::create {
+            path: Cow::Owned(path.clone()),
+            flags: O_CREAT | O_WRONLY | O_TRUNC,
+            security,
+            mode: stat.mode() & 0o7777,
+        });
+        let mut file = File::open(real)?;
+        let mut offset = 0;
+        loop {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            buf.truncate(n);
+            send(VFSCall::write {
+                path: Cow::Owned(path.clone()),
+                offset,
+                buf: Cow::Owned(buf),
+            });
+            offset += n as i64;
+        }
+    } else if file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_block_device()
+        || file_type.is_char_device()
+    {
+        send(VFSCall::mknod {
+            path: Cow::Owned(path),
+            mode: stat.mode(),
+            rdev: stat.rdev(),
+            security,
+        });
+    }
+    Ok(())
+}
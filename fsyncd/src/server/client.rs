@@ -1,26 +1,39 @@
 extern crate iolimit;
 
 use self::iolimit::LimitWriter;
-use bincode::{deserialize_from, serialize, serialize_into, serialized_size};
-use byteorder::{BigEndian, WriteBytesExt};
+use bincode::{
+    deserialize, deserialize_from, serialize, serialize_into, serialized_size,
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use common::auth::{self, AuthKeys};
+use common::compress::{Compressor, ZstdBlock};
+use common::filter::PathFilter;
+use common::metrics::{self, CountingWriter};
+use common::net::{Closer, MyRead, MyWrite};
 use common::*;
-use dssc::{chunkmap::ChunkMap, other::ZstdBlock, Compressor};
 use error::{Error, FromError};
-use server::net::{MyRead, MyWrite};
-use server::{cork_server, uncork_server, SERVER_PATH};
-use std::collections::HashMap;
+use server::control::control;
+use server::queue::{self, SendQueue};
+use server::{
+    cork_server, find_resume_offset, last_op_id, peering, promote,
+    uncork_server, AUTH_KEYS, DEMOTED, EPOCH, EXCLUDE, FALLBACK_TIMEOUT,
+    RESPONSE_TIMEOUT, SERVER_PATH,
+};
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
-use std::{mem::transmute, ops::Deref, thread, time::Duration};
+use std::time::{Duration, Instant};
+use std::{ops::Deref, thread};
 use {lz4, zstd};
 
-const CLIENT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+// Anything bigger than this is not an InitMsg from any version of fsyncd
+const MAX_INIT_SIZE: usize = 64 * 1024;
 
 static NOP_MSG: FsyncerMsg = FsyncerMsg::NOP;
 
 lazy_static! {
     static ref ENCODED_NOP: Vec<u8> = serialize(&NOP_MSG).unwrap();
-    static ref NOP_SIZE: usize = serialized_size(&NOP_MSG).unwrap() as usize;
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -29,108 +42,497 @@ pub enum ClientStatus {
     ALIVE,
 }
 
-struct ClientNetwork {
+#[derive(Clone)]
+pub struct ClientWatch(Arc<Mutex<ClientNetwork>>);
+
+impl ClientWatch {
+    pub fn status(&self) -> ClientStatus {
+        self.0.lock().unwrap().status
+    }
+
+    pub fn label(&self) -> String {
+        self.0.lock().unwrap().label.clone()
+    }
+
+    // Whether the acknowledgement to id is still outstanding
+    pub fn waiting(&self, id: u64) -> bool {
+        self.0.lock().unwrap().is_parked(id)
+    }
+
+    // The client has not acknowledged id in time, it is no longer waited for
+    // until it catches up.
+    pub fn missed(&self, id: u64) {
+        let mut net = self.0.lock().unwrap();
+        if !net.degraded && net.is_parked(id) {
+            warn!(
+                client = net.label;
+                "Client missed its acknowledgement deadline, treating it as \
+                 asynchronous"
+            );
+            net.degraded = true;
+        }
+    }
+}
+
+// An acknowledgement, along with which client sent it
+#[derive(PartialEq, Debug)]
+pub struct Reply {
+    pub client: u64,
+    pub ack: ClientAck,
+}
+
+// Requests a client can leave unanswered, one with more than that is not
+// keeping up and gets dropped
+const MAX_PENDING: usize = 4096;
+
+struct Parked {
+    id: u64,
+    response: Arc<ClientResponse<Reply>>,
+    sent: Instant,
+}
+
+// Frames and compresses messages onto the connection
+struct Writer {
     write: Box<dyn Write + Send>,
     rt_comp: Option<Box<dyn Compressor>>,
-    // TODO remvoe this hashmap and use an array
-    parked: HashMap<u64, Arc<ClientResponse<ClientAck>>>,
+    // Stream compressors need a message after a flush to flush themselves
+    stream_comp: bool,
+}
+
+impl Writer {
+    fn send(
+        &mut self,
+        serbuf: &[u8],
+        flush: bool,
+    ) -> Result<(), Error<io::Error>> {
+        let mut nbuf = Vec::new();
+
+        let buf = if let Some(ref mut rt_comp) = self.rt_comp {
+            rt_comp.encode(serbuf, &mut nbuf);
+            &nbuf[..]
+        } else {
+            serbuf
+        };
+
+        trace!(self.write.write_u32::<BigEndian>(buf.len() as u32));
+        trace!(self.write.write_all(buf));
+        if flush {
+            trace!(self.write.flush());
+            // Without the nop message compression algorithms dont flush
+            // immediately.
+            if self.stream_comp {
+                trace!(self.send(&ENCODED_NOP[..], false));
+                trace!(self.write.flush());
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Output {
+    // Written to by whoever sends
+    Direct(Writer),
+    // Written to by the client's sender thread, for asynchronous clients
+    Queued(Arc<SendQueue>),
+}
+
+struct ClientNetwork {
+    out: Output,
+    // Requests waiting on an acknowledgement, in the slot of their id
+    parked: Vec<Option<Parked>>,
+    // Id of the next request sent
+    next_request: u64,
     status: ClientStatus,
+    // Synchronous client that fell behind, writes don't wait for it
+    degraded: bool,
+    id: u64,
+    label: String,
+    // Sequence number of the next op sent
+    next_seq: u64,
+    // Ops the client reported as applied
+    applied: u64,
+    // Sizes of the ops sent but not applied yet, oldest first
+    unapplied: VecDeque<usize>,
+    unapplied_bytes: u64,
+    // Bytes of messages sent, before they are compressed
+    sent_raw: Arc<AtomicU64>,
+}
+
+impl ClientNetwork {
+    // Unblocks all threads that could be waiting on this client
+    fn mark_dead(&mut self) {
+        self.status = ClientStatus::DEAD;
+        if let Output::Queued(ref queue) = self.out {
+            queue.close();
+        }
+        for parked in self.parked.iter_mut().filter_map(Option::take) {
+            parked.response.notify(Reply {
+                client: self.id,
+                ack: ClientAck::Dead,
+            });
+        }
+    }
+
+    // Allocates the id of a request whose acknowledgement goes to response,
+    // None if the client has too many unanswered already
+    fn park(&mut self, response: &Arc<ClientResponse<Reply>>) -> Option<u64> {
+        let id = self.next_request;
+        let slot = &mut self.parked[id as usize % MAX_PENDING];
+        if slot.is_some() {
+            return None;
+        }
+        *slot = Some(Parked {
+            id,
+            response: response.clone(),
+            sent: Instant::now(),
+        });
+        self.next_request += 1;
+        Some(id)
+    }
+
+    // Ids come from the client, anything not sent to it is ignored
+    fn unpark(&mut self, id: u64) -> Option<Parked> {
+        if !self.is_parked(id) {
+            return None;
+        }
+        self.parked[id as usize % MAX_PENDING].take()
+    }
+
+    fn is_parked(&self, id: u64) -> bool {
+        match self.parked[id as usize % MAX_PENDING] {
+            Some(ref parked) => parked.id == id,
+            None => false,
+        }
+    }
+
+    // Numbers msg if it is an op and serializes it. An op counts as unapplied
+    // from here, if sending it fails the client is dropped anyway.
+    fn serialize(
+        &mut self,
+        msg: &mut FsyncerMsg,
+    ) -> Result<Vec<u8>, Error<io::Error>> {
+        let size = trace!(serialized_size(&*msg)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+            as usize;
+
+        // Numbered under the lock, so they go out in order
+        let is_op = match msg.seq_mut() {
+            Some(seq) => {
+                *seq = self.next_seq;
+                true
+            }
+            None => false,
+        };
+
+        let mut serbuf = Vec::with_capacity(size);
+        trace!(serialize_into(&mut serbuf, &*msg)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+        self.sent_raw.fetch_add(size as u64, Ordering::Relaxed);
+        if is_op {
+            self.next_seq += 1;
+            self.unapplied.push_back(size);
+            self.unapplied_bytes += size as u64;
+        }
+        Ok(serbuf)
+    }
+
+    // The client has applied the first count ops sent to it
+    fn applied(&mut self, count: u64) {
+        if count > self.next_seq {
+            warn!(
+                client = self.label;
+                "Client applied {} ops, but was sent {}",
+                count,
+                self.next_seq
+            );
+            return;
+        }
+        while self.applied < count {
+            let size = self.unapplied.pop_front().unwrap_or(0);
+            self.unapplied_bytes -= size as u64;
+            self.applied += 1;
+        }
+    }
+
+    fn acknowledged(&mut self, id: u64, ack: ClientAck) {
+        let parked = match self.unpark(id) {
+            Some(parked) => parked,
+            None => {
+                warn!(client = self.label; "Unexpected acknowledgement {}", id);
+                return;
+            }
+        };
+        // Acks come in order, one that is on time means the client has
+        // worked through its backlog.
+        if self.degraded {
+            let on_time = unsafe { FALLBACK_TIMEOUT }
+                .map_or(true, |timeout| parked.sent.elapsed() < timeout);
+            if on_time {
+                info!(
+                    client = self.label;
+                    "Client caught up, treating it as synchronous again"
+                );
+                self.degraded = false;
+            }
+        }
+        parked.response.notify(Reply {
+            client: self.id,
+            ack,
+        });
+    }
 }
 
 pub struct Client {
+    // Unique for as long as the server runs, unlike fds
+    pub id: u64,
     pub mode: ClientMode,
+    pub capabilities: Capabilities,
+    // Journal offset this client still needs to be sent ops from
+    pub catch_up_from: Option<u64>,
+    // Last op the client had when it connected, if it is resuming
+    pub resume_from: Option<u64>,
+    // Where the client serves its own replicas
+    pub downstream: Option<String>,
+    // Which paths this client wants to receive
+    pub filter: PathFilter,
     comp: CompMode,
+    // Bytes per second sent to the client, can be changed while connected
+    iolimit: Arc<AtomicUsize>,
     net: Arc<Mutex<ClientNetwork>>,
+    // Updated by the reader on anything received
+    last_seen: Arc<Mutex<Instant>>,
+    closer: Closer,
 }
 
+// Collects the responses to one message, which may have been sent to several
+// clients, each under an id of its own.
 pub struct ClientResponse<T> {
-    data: Mutex<Option<T>>,
+    data: Mutex<Vec<T>>,
     cvar: Condvar,
 }
 
 impl<T> ClientResponse<T> {
     pub fn new() -> Self {
         ClientResponse {
-            data: Mutex::new(None),
+            data: Mutex::new(Vec::new()),
             cvar: Condvar::new(),
         }
     }
+
     pub fn wait(&self) -> Option<T> {
-        let mut lock = self.data.lock().unwrap();
+        self.wait_until(unsafe { RESPONSE_TIMEOUT }, |responses| {
+            !responses.is_empty()
+        })
+        .pop()
+    }
 
-        while lock.is_none() {
-            let (ll, timeout) = self
-                .cvar
-                .wait_timeout(lock, CLIENT_RESPONSE_TIMEOUT)
-                .unwrap();
-            lock = ll;
-            if timeout.timed_out() {
-                return None;
+    // Waits until done is happy with what has arrived, or for the timeout.
+    // Whatever has arrived is taken out either way.
+    pub fn wait_until<F: Fn(&[T]) -> bool>(
+        &self,
+        timeout: Duration,
+        done: F,
+    ) -> Vec<T> {
+        let deadline = Instant::now() + timeout;
+        let mut lock = self.data.lock().unwrap();
+        while !done(&lock) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
             }
+            lock = self.cvar.wait_timeout(lock, deadline - now).unwrap().0;
         }
-        Some(lock.take().unwrap())
+        lock.drain(..).collect()
+    }
+
+    // Takes out whatever has arrived without waiting
+    pub fn take(&self) -> Vec<T> {
+        self.data.lock().unwrap().drain(..).collect()
     }
 
     pub fn notify(&self, data: T) {
-        *self.data.lock().unwrap() = Some(data);
-        self.cvar.notify_one()
+        self.data.lock().unwrap().push(data);
+        self.cvar.notify_all()
     }
 }
 
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Used before the writer is set up, so bypasses compression and iolimit
+fn send_framed<W: Write>(
+    write: &mut W,
+    msg: &FsyncerMsg,
+) -> Result<(), Error<io::Error>> {
+    let buf = trace!(
+        serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    );
+    trace!(write.write_u32::<BigEndian>(buf.len() as u32));
+    trace!(write.write_all(&buf));
+    trace!(write.flush());
+    Ok(())
+}
+
 impl Client {
     pub fn from_stream(
         mut netin: Box<dyn MyRead>,
-        netout: Box<dyn MyWrite>,
+        mut netout: Box<dyn MyWrite>,
         dontcheck: bool,
     ) -> Result<Self, Error<io::Error>> {
-        let init = match Client::read_msg(&mut netin) {
-            Ok(FsyncerMsg::InitMsg(msg)) => msg,
-            Err(e) => return Err(trace_err!(e)),
-            otherwise => panic!(
-                "Expected init message from client, received {:?}",
-                otherwise
-            ),
-        };
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let init = trace!(Client::read_init(&mut netin, &mut netout));
+        let label = init
+            .identity
+            .clone()
+            .unwrap_or_else(|| format!("client {}", id));
+
+        // Nothing else may happen before the peer has proven who it is.
+        match unsafe { AUTH_KEYS.as_ref() } {
+            Some(keys) => trace!(Client::authenticate(
+                keys,
+                &init,
+                &mut netin,
+                &mut netout
+            )),
+            None if init.identity.is_some() => {
+                return Err(Client::reject(
+                    &mut netout,
+                    "Server does not have authentication configured",
+                ))
+            }
+            None => {}
+        }
+
+        if DEMOTED.load(Ordering::SeqCst) {
+            return Err(Client::reject_init(
+                &mut netout,
+                RejectReason::Other("Server is no longer the primary".into()),
+            ));
+        }
+
+        // The replica followed a newer primary, this one was failed over and
+        // must not take writes anymore. Peers are all primaries.
+        let epoch = EPOCH.load(Ordering::SeqCst);
+        if init.epoch > epoch && !init.capabilities.contains(Capabilities::PEER)
+        {
+            warn!(
+                client = label;
+                "Replica has seen epoch {}, this server is at {}, refusing \
+                 writes",
+                init.epoch,
+                epoch
+            );
+            DEMOTED.store(true, Ordering::SeqCst);
+            return Err(Client::reject_init(
+                &mut netout,
+                RejectReason::StaleEpoch {
+                    server: epoch,
+                    seen: init.epoch,
+                },
+            ));
+        }
 
         let storage_path = unsafe { SERVER_PATH.as_ref().unwrap() };
 
+        // What the server never replicates is left out for everyone
+        let mut spec = init.filter.clone();
+        if let Some(exclude) = unsafe { EXCLUDE.as_ref() } {
+            spec.exclude.extend_from_slice(&exclude.spec().exclude);
+        }
+        let filter = match PathFilter::new(&spec) {
+            Ok(filter) => filter,
+            Err(e) => {
+                warn!(client = label; "Client sent an invalid filter: {}", e);
+                return Err(Client::reject_init(
+                    &mut netout,
+                    RejectReason::Other(e.to_string()),
+                ));
+            }
+        };
+
+        // A resuming client only needs what it missed, no need to compare
+        let catch_up_from = match init.resume_from {
+            // Nothing was missed, add_client checks that is still the case
+            Some(op_id) if Some(op_id) == last_op_id() => None,
+            Some(op_id) => match find_resume_offset(
+                op_id,
+                init.capabilities.contains(Capabilities::PEER) && peering(),
+            ) {
+                Some(offset) => Some(offset),
+                None => {
+                    warn!(client = label; "Client can't resume from op {}", op_id);
+                    return Err(Client::reject_init(
+                        &mut netout,
+                        RejectReason::ResumeUnavailable,
+                    ));
+                }
+            },
+            None => None,
+        };
+
         if !(init.mode == ClientMode::MODE_CONTROL
             || dontcheck
+            || init.resume_from.is_some()
             || init.options.contains(Options::INITIAL_RSYNC))
         {
-            eprintln!("Calculating source hash...");
-            let srchash =
-                hash_metadata(&storage_path).expect("Hash check failed");
-            eprintln!("Source hash is {:x}", srchash);
+            info!("Calculating source hash...");
+            let srchash = hash_metadata(&storage_path, &filter)
+                .expect("Hash check failed");
+            info!("Source hash is {:x}", srchash);
             if init.dsthash != srchash {
-                eprintln!(
-                    "{:x} != {:x} client's hash does not match!",
-                    init.dsthash, srchash
+                error!(
+                    client = label;
+                    "Client's hash {:x} does not match, dropping it",
+                    init.dsthash
                 );
-                eprintln!("Dropping this client!");
-                drop(netin);
-                drop(netout);
-                return Err(trace_err!(io::Error::new(
-                    io::ErrorKind::Other,
-                    "Hash mismatch",
-                )));
+                return Err(Client::reject_init(
+                    &mut netout,
+                    RejectReason::HashMismatch,
+                ));
             }
         }
 
+        let version = init.version.min(PROTOCOL_VERSION);
+        let mut capabilities = init.capabilities & Capabilities::all();
+        if unsafe { AUTH_KEYS.is_none() } {
+            capabilities.remove(Capabilities::AUTH);
+        }
+        if !peering() {
+            capabilities.remove(Capabilities::PEER);
+        }
+        trace!(send_framed(
+            &mut netout,
+            &FsyncerMsg::InitResponse(InitResponse::Accept {
+                version,
+                capabilities,
+                epoch,
+            })
+        ));
+        info!(
+            client = label, mode = init.mode;
+            "Negotiated protocol version {} with capabilities {:?}",
+            version,
+            capabilities
+        );
+
         if init.options.contains(Options::INITIAL_RSYNC) {
             //trace!(stream.set_nodelay(true));
-            eprintln!("Syncrhonising using rsync...");
+            info!("Syncrhonising using rsync...");
             trace!(rsync::server(
                 netin.as_raw_fd(),
                 netout.as_raw_fd(),
-                storage_path
+                storage_path,
+                &filter.rsync_rules()
             ));
-            eprintln!("Done!");
+            info!("Done!");
         }
 
+        let netout =
+            CountingWriter::new(netout, metrics::SENT_BYTES.get(&label));
         let limiter = LimitWriter::new(netout, init.iolimit_bps);
+        let iolimit = limiter.limit();
 
-        let writer = if init.compress.contains(CompMode::STREAM_ZSTD) {
+        let write = if init.compress.contains(CompMode::STREAM_ZSTD) {
             Box::new(trace!(zstd::stream::Encoder::new(limiter, 0))) as _
         } else if init.compress.contains(CompMode::STREAM_LZ4) {
             Box::new(trace!(lz4::EncoderBuilder::new().build(limiter))) as _
@@ -139,46 +541,224 @@ impl Client {
         };
 
         let rt_comp: Option<Box<dyn Compressor>> =
-            if init.compress.contains(CompMode::RT_DSSC_CHUNKED) {
-                Some(Box::new(ChunkMap::new(0.5)))
-            } else if init.compress.contains(CompMode::RT_DSSC_ZSTD) {
+            if init.compress.contains(CompMode::RT_ZSTD) {
                 Some(Box::new(ZstdBlock::default()))
             } else {
                 None
             };
 
-        let net = Arc::new(Mutex::new(ClientNetwork {
-            write: writer,
+        let writer = Writer {
+            write,
             rt_comp,
-            parked: HashMap::new(),
+            stream_comp: init.compress.intersects(CompMode::STREAM_MASK),
+        };
+
+        // Only asynchronous clients have nothing waiting on what they are
+        // sent, anything else goes out right away.
+        let (out, sender) = match queue::limit() {
+            Some(limit) if init.mode == ClientMode::MODE_ASYNC => {
+                let queue = Arc::new(SendQueue::new(limit));
+                (Output::Queued(queue.clone()), Some((queue, writer)))
+            }
+            _ => (Output::Direct(writer), None),
+        };
+
+        let net = Arc::new(Mutex::new(ClientNetwork {
+            out,
+            parked: (0..MAX_PENDING).map(|_| None).collect(),
+            next_request: 0,
             status: ClientStatus::ALIVE,
+            degraded: false,
+            id,
+            next_seq: 0,
+            applied: 0,
+            unapplied: VecDeque::new(),
+            unapplied_bytes: 0,
+            sent_raw: metrics::SENT_RAW_BYTES.get(&label),
+            label: label.clone(),
         }));
         let net_clone = net.clone();
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let last_seen_clone = last_seen.clone();
+        let closer = trace!(netin.closer());
 
-        thread::spawn(move || Client::reader(netin, net_clone));
+        let mode = init.mode;
+        thread::spawn(move || {
+            Client::reader(netin, net_clone, last_seen_clone, mode, id)
+        });
+        if let Some((queue, writer)) = sender {
+            let net = net.clone();
+            thread::spawn(move || Client::sender(&queue, writer, &net));
+        }
 
-        eprintln!("Client connected!");
+        info!(client = label, mode = init.mode; "Client connected");
 
         Ok(Client {
+            id,
             mode: init.mode,
+            capabilities,
+            catch_up_from,
+            resume_from: init.resume_from,
+            downstream: init.downstream,
+            filter,
             comp: init.compress,
+            iolimit,
             net,
+            last_seen,
+            closer,
         })
     }
 
+    /*
+        InitMsg is framed so that it can be read whole, even when it comes
+        from a build with a different layout. The version is checked before
+        decoding the rest, so mismatched builds get a clear rejection rather
+        than garbage.
+    */
+    fn read_init<R: Read, W: Write>(
+        netin: &mut R,
+        netout: &mut W,
+    ) -> Result<InitMsg, Error<io::Error>> {
+        let length = trace!(netin.read_u32::<BigEndian>()) as usize;
+        if length > MAX_INIT_SIZE {
+            return Err(Client::reject_init(
+                netout,
+                RejectReason::Other("Expected init message".into()),
+            ));
+        }
+        let mut buf = vec![0; length];
+        trace!(netin.read_exact(&mut buf));
+
+        // FsyncerMsg::InitMsg is the first variant, version its first field
+        match deserialize::<(u32, u32)>(&buf) {
+            Ok((0, version))
+                if version >= MIN_PROTOCOL_VERSION
+                    && version <= PROTOCOL_VERSION => {}
+            Ok((0, version)) => {
+                warn!("Client speaks unsupported protocol {}", version);
+                return Err(Client::reject_init(
+                    netout,
+                    RejectReason::Version {
+                        min: MIN_PROTOCOL_VERSION,
+                        max: PROTOCOL_VERSION,
+                    },
+                ));
+            }
+            _ => {
+                return Err(Client::reject_init(
+                    netout,
+                    RejectReason::Other("Expected init message".into()),
+                ))
+            }
+        }
+
+        match deserialize(&buf) {
+            Ok(FsyncerMsg::InitMsg(init)) => Ok(init),
+            otherwise => {
+                warn!("Malformed init message {:?}", otherwise);
+                Err(Client::reject_init(
+                    netout,
+                    RejectReason::Other("Malformed init message".into()),
+                ))
+            }
+        }
+    }
+
+    // Same as reject, for failures outside of the authentication exchange
+    fn reject_init<W: Write>(
+        netout: &mut W,
+        reason: RejectReason,
+    ) -> Error<io::Error> {
+        let error = io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Rejected client, {}", reason),
+        );
+        let _ = send_framed(
+            netout,
+            &FsyncerMsg::InitResponse(InitResponse::Reject(reason)),
+        );
+        trace_err!(error)
+    }
+
+    fn authenticate<R: Read, W: Write>(
+        keys: &AuthKeys,
+        init: &InitMsg,
+        netin: &mut R,
+        netout: &mut W,
+    ) -> Result<(), Error<io::Error>> {
+        let identity = match init.identity.as_ref() {
+            Some(identity) => identity,
+            None => {
+                return Err(Client::reject(netout, "Authentication required"))
+            }
+        };
+        let challenge = auth::nonce();
+        trace!(send_framed(
+            netout,
+            &FsyncerMsg::AuthChallenge(challenge.clone())
+        ));
+        let response = match trace!(Client::read_msg(netin)) {
+            FsyncerMsg::AuthResponse(response) => response,
+            msg => {
+                warn!("Expected authentication response, got {:?}", msg);
+                return Err(Client::reject(
+                    netout,
+                    "Expected authentication response",
+                ));
+            }
+        };
+        let secret = match keys.secret_for(identity) {
+            Some(secret) if response.identity == *identity => secret,
+            _ => {
+                warn!(client = identity; "Unknown client identity");
+                return Err(Client::reject(netout, "Authentication failed"));
+            }
+        };
+        let expected = trace!(auth::client_proof(secret, &challenge, identity));
+        if !auth::verify(&expected, &response.mac)
+            || response.challenge.len() != auth::NONCE_SIZE
+        {
+            warn!(client = identity; "Client failed to authenticate");
+            return Err(Client::reject(netout, "Authentication failed"));
+        }
+        // Prove to the client that we know the secret too
+        let proof =
+            trace!(auth::server_proof(secret, &response.challenge, identity));
+        trace!(send_framed(netout, &FsyncerMsg::AuthAccepted(proof)));
+        info!(client = identity; "Client authenticated");
+        Ok(())
+    }
+
+    // Tells the client why it is being dropped, returns the error to propagate
+    fn reject<W: Write>(netout: &mut W, reason: &str) -> Error<io::Error> {
+        // The client may already be gone, the rejection is best effort
+        let _ = send_framed(netout, &FsyncerMsg::AuthRejected(reason.into()));
+        trace_err!(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+    }
+
     // Send a cork to this client, and block until it acknowledges
     pub fn cork(&self) -> Result<(), Error<io::Error>> {
-        let current_thread = thread::current();
-        let tid =
-            unsafe { transmute::<thread::ThreadId, u64>(current_thread.id()) };
-        let wait = trace!(self.response_msg(
-            FsyncerMsg::Cork(tid),
-            true,
-            self.mode != ClientMode::MODE_CONTROL
-        ));
         // Cannot park on control as it will block its reader thread
-        if self.mode != ClientMode::MODE_CONTROL {
-            wait.unwrap().wait().expect("Client did not respond");
+        if self.mode == ClientMode::MODE_CONTROL {
+            return self.send_msg(FsyncerMsg::Cork(0), true);
+        }
+        let response = Arc::new(ClientResponse::new());
+        trace!(self.response_msg(FsyncerMsg::Cork(0), true, Some(&response)));
+        match response.wait() {
+            Some(Reply {
+                ack: ClientAck::Ack,
+                ..
+            }) => {}
+            _ => {
+                error!(
+                    client = self.label(), mode = self.mode;
+                    "Client did not respond to cork"
+                );
+                return Err(trace_err!(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Client did not respond"
+                )));
+            }
         }
         Ok(())
     }
@@ -187,6 +767,15 @@ impl Client {
         self.net.lock().unwrap().status
     }
 
+    pub fn degraded(&self) -> bool {
+        self.net.lock().unwrap().degraded
+    }
+
+    // Outlives the client, for whoever needs to know when it is gone
+    pub fn watch(&self) -> ClientWatch {
+        ClientWatch(self.net.clone())
+    }
+
     pub fn uncork(&self) -> Result<(), Error<io::Error>> {
         trace!(self.send_msg(FsyncerMsg::Uncork, false));
         Ok(())
@@ -197,106 +786,200 @@ impl Client {
             .map_err(|e| trace_err!(io::Error::new(io::ErrorKind::Other, e)))
     }
 
-    fn reader<R: Read>(mut read: R, net: Arc<Mutex<ClientNetwork>>) {
+    fn reader<R: Read>(
+        mut read: R,
+        net: Arc<Mutex<ClientNetwork>>,
+        last_seen: Arc<Mutex<Instant>>,
+        mode: ClientMode,
+        id: u64,
+    ) {
         let net = net.deref();
         loop {
-            match Client::read_msg(&mut read) {
-                Ok(FsyncerMsg::AckCork(tid)) => {
-                    let netlock = net.lock().unwrap();
-                    if let Some(t) = netlock.parked.get(&tid) {
-                        assert!(Arc::strong_count(t) <= 2);
-                        t.notify(ClientAck::Ack)
-                    }
+            let msg = Client::read_msg(&mut read);
+            if msg.is_ok() {
+                *last_seen.lock().unwrap() = Instant::now();
+            }
+            match msg {
+                Ok(FsyncerMsg::NOP) => {} // Heartbeat
+                Ok(FsyncerMsg::AckCork(id)) => {
+                    net.lock().unwrap().acknowledged(id, ClientAck::Ack)
                 }
-                Ok(FsyncerMsg::Ack(AckMsg { retcode: code, tid })) => {
-                    let mut netlock = net.lock().unwrap();
-                    // I shouldn't randomly insert whatever the client sends,
-                    // but oh well...
-                    let cond = netlock
-                        .parked
-                        .entry(tid)
-                        .or_insert_with(|| Arc::new(ClientResponse::new()));
-                    assert!(Arc::strong_count(cond) <= 2);
-                    cond.notify(code);
+                Ok(FsyncerMsg::Ack(AckMsg {
+                    retcode: code,
+                    tid: id,
+                })) => net.lock().unwrap().acknowledged(id, code),
+                Ok(FsyncerMsg::Applied(count)) => {
+                    net.lock().unwrap().applied(count)
+                }
+                Ok(FsyncerMsg::Cork(_)) | Ok(FsyncerMsg::Uncork)
+                    if mode != ClientMode::MODE_CONTROL =>
+                {
+                    warn!(
+                        client = net.lock().unwrap().label;
+                        "Ignoring cork request from a replica"
+                    )
                 }
                 Ok(FsyncerMsg::Cork(_)) => cork_server(),
                 Ok(FsyncerMsg::Uncork) => uncork_server(),
+                Ok(FsyncerMsg::Promote(url))
+                    if mode == ClientMode::MODE_CONTROL =>
+                {
+                    // Takes a while, heartbeats still need to be read
+                    thread::spawn(move || promote(url, id));
+                }
+                Ok(FsyncerMsg::Control(cmd))
+                    if mode == ClientMode::MODE_CONTROL =>
+                {
+                    control(cmd, id)
+                }
                 Err(e) => {
                     let mut netlock = net.lock().unwrap();
-                    netlock.status = ClientStatus::DEAD;
+                    netlock.mark_dead();
                     // Will kill this thread
-                    eprintln!("Failed to read from client {}", e);
+                    error!(client = netlock.label; "Failed to read from client {}", e);
                     return;
                 }
-                msg => eprintln!("Unexpected message from client {:?}", msg),
+                msg => warn!(
+                    client = net.lock().unwrap().label;
+                    "Unexpected message from client {:?}",
+                    msg
+                ),
             }
         }
     }
 
-    pub fn flush(&self) -> Result<(), Error<io::Error>> {
-        //Without the nop message compression algorithms dont flush
-        // immediately.
-        if self.comp.intersects(CompMode::STREAM_MASK) {
-            trace!(self.send_msg(FsyncerMsg::NOP, true))
-        } else {
-            trace!(self.net.lock().unwrap().write.flush())
+    // Writes out what is queued for the client until it is dead. Ops are
+    // numbered as they are sent, what was coalesced away never was.
+    fn sender(
+        queue: &SendQueue,
+        mut writer: Writer,
+        net: &Mutex<ClientNetwork>,
+    ) {
+        loop {
+            let mut entry = match queue.pop() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return,
+                Err(e) => {
+                    let mut netlock = net.lock().unwrap();
+                    netlock.mark_dead();
+                    error!(
+                        client = netlock.label;
+                        "Failed to read back the spilled queue {}",
+                        e
+                    );
+                    return;
+                }
+            };
+            // Not held while writing, acks would wait on the network again
+            let serbuf = net.lock().unwrap().serialize(&mut entry.msg);
+            let res = serbuf.and_then(|buf| writer.send(&buf, entry.flush));
+            if let Err(e) = res {
+                let mut netlock = net.lock().unwrap();
+                netlock.mark_dead();
+                error!(client = netlock.label; "Failed to send to client {}", e);
+                return;
+            }
         }
-        Ok(())
     }
 
-    pub fn response_msg(
-        &self,
-        msg_data: FsyncerMsg,
-        flush: bool,
-        want_response: bool,
-    ) -> Result<Option<Arc<ClientResponse<ClientAck>>>, Error<io::Error>> {
-        fn inner(
-            serbuf: &[u8],
-            mut size: usize,
-            net: &mut ClientNetwork,
-            flush: bool,
-            comp: bool,
-        ) -> Result<(), Error<io::Error>> {
-            let mut nbuf = Vec::new();
-
-            let buf = if let Some(ref mut rt_comp) = net.rt_comp {
-                rt_comp.encode(&serbuf[..], &mut nbuf);
-                size = nbuf.len();
-                &nbuf[..]
-            } else {
-                &serbuf[..]
-            };
+    // Ops from here on are made by the filesystem, a full send queue is
+    // handled as configured rather than waited on.
+    pub fn caught_up(&self) {
+        if let Output::Queued(ref queue) = self.net.lock().unwrap().out {
+            queue.caught_up();
+        }
+    }
 
-            trace!(net.write.write_u32::<BigEndian>(size as u32));
-            trace!(net.write.write_all(&buf));
-            if flush {
-                trace!(net.write.flush());
-                // Without the nop message compression algorithms dont flush
-                // immediately.
-                if comp {
-                    trace!(inner(
-                        &ENCODED_NOP[..],
-                        *NOP_SIZE,
-                        net,
-                        false,
-                        comp
-                    ));
-                    trace!(net.write.flush());
-                }
-            }
-            Ok(())
+    pub fn label(&self) -> String {
+        self.net.lock().unwrap().label.clone()
+    }
+
+    // Ops sent that the client has not applied yet, and their size
+    pub fn lag(&self) -> (u64, u64) {
+        let net = self.net.lock().unwrap();
+        (net.next_seq - net.applied, net.unapplied_bytes)
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        self.last_seen.lock().unwrap().elapsed()
+    }
+
+    pub fn set_iolimit(&self, bps: usize) {
+        self.iolimit.store(bps, Ordering::Relaxed);
+    }
+
+    // What control is told about this client
+    pub fn info(&self) -> ClientInfo {
+        let (lag_ops, lag_bytes) = self.lag();
+        let net = self.net.lock().unwrap();
+        ClientInfo {
+            id: self.id,
+            label: net.label.clone(),
+            mode: self.mode,
+            compress: format!("{:?}", self.comp),
+            iolimit_bps: self.iolimit.load(Ordering::Relaxed),
+            lag_ops,
+            lag_bytes,
+            alive: net.status == ClientStatus::ALIVE,
+            degraded: net.degraded,
+            silent_secs: self.silent_for().as_secs(),
+            downstream: self.downstream.clone(),
+            queued_bytes: match net.out {
+                Output::Queued(ref queue) => queue.queued(),
+                Output::Direct(_) => 0,
+            },
         }
+    }
 
-        let size = trace!(serialized_size(&msg_data)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
-            as usize;
+    // Gives up on this client, anything waiting on it is released
+    pub fn kill(&self, reason: &str) {
+        warn!(
+            client = self.label(), mode = self.mode;
+            "Dropping client, {}",
+            reason
+        );
+        // A writer stuck on a half-open connection holds the network lock,
+        // shutting the socket down is what gets it to let go.
+        (self.closer)();
+        self.net.lock().unwrap().mark_dead();
+    }
 
-        let mut serbuf = Vec::with_capacity(size);
+    pub fn heartbeat(&self) -> Result<(), Error<io::Error>> {
+        // Whoever holds the lock is sending, as is the sender while there
+        // is anything queued, that keeps the client alive as well. Don't get
+        // stuck behind them.
+        match self.net.try_lock() {
+            Err(_) => return Ok(()),
+            Ok(ref net) => match net.out {
+                Output::Queued(ref queue) if queue.queued() != 0 => {
+                    return Ok(())
+                }
+                _ => {}
+            },
+        }
+        self.send_msg(FsyncerMsg::NOP, true)
+    }
 
-        trace!(serialize_into(&mut serbuf, &msg_data)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    pub fn flush(&self) -> Result<(), Error<io::Error>> {
+        if let Output::Direct(ref mut writer) = self.net.lock().unwrap().out {
+            if !writer.stream_comp {
+                trace!(writer.write.flush());
+                return Ok(());
+            }
+        }
+        // Without the nop message compression algorithms dont flush
+        // immediately, and a queue is only flushed after what it sends.
+        trace!(self.send_msg(FsyncerMsg::NOP, true));
+        Ok(())
+    }
 
-        //eprintln!("Sending {} {}", header.op_length, hbuf.len() + buf.len());
+    // The acknowledgement for id, if any, is delivered to response
+    pub fn response_msg(
+        &self,
+        mut msg_data: FsyncerMsg,
+        flush: bool,
+        response: Option<&Arc<ClientResponse<Reply>>>,
+    ) -> Result<Option<u64>, Error<io::Error>> {
         let mut net = self.net.lock().unwrap();
 
         if net.status == ClientStatus::DEAD {
@@ -307,31 +990,61 @@ impl Client {
             )));
         }
 
-        let resp = if want_response {
-            let tid = unsafe {
-                transmute::<thread::ThreadId, u64>(thread::current().id())
-            };
-            Some(
-                net.parked
-                    .entry(tid)
-                    .or_insert_with(|| Arc::new(ClientResponse::new()))
-                    .clone(),
-            )
-        } else {
-            None
+        let request = match response {
+            Some(response) => match net.park(response) {
+                Some(id) => {
+                    *msg_data
+                        .request_id_mut()
+                        .expect("Message is not acknowledged") = id;
+                    Some(id)
+                }
+                None => {
+                    warn!(
+                        client = net.label, mode = self.mode;
+                        "Dropping client, too many unanswered requests"
+                    );
+                    net.mark_dead();
+                    return Err(trace_err!(io::Error::new(
+                        io::ErrorKind::Other,
+                        "Client is not answering requests"
+                    )));
+                }
+            },
+            None => None,
         };
 
-        let res = inner(
-            &serbuf[..],
-            size,
-            &mut *net,
-            flush,
-            self.comp.intersects(CompMode::STREAM_MASK),
-        );
+        let queue = match net.out {
+            Output::Queued(ref queue) => Some(queue.clone()),
+            Output::Direct(_) => None,
+        };
+        let res = match queue {
+            Some(queue) => {
+                // The sender needs the lock to number what it sends, and
+                // a full queue may be waited on. Ops are made one at a time
+                // under CORK, so they are still queued in order.
+                drop(net);
+                let res = queue.push(msg_data.into_owned(), flush);
+                net = self.net.lock().unwrap();
+                res.map_err(|e| trace_err!(e))
+            }
+            None => {
+                let serbuf = net.serialize(&mut msg_data);
+                match net.out {
+                    Output::Direct(ref mut writer) => {
+                        serbuf.and_then(|buf| writer.send(&buf, flush))
+                    }
+                    Output::Queued(_) => unreachable!("Client is direct"),
+                }
+            }
+        };
         if res.is_err() {
-            net.status = ClientStatus::DEAD;
+            // The caller sees the error, nothing will be waiting for this one
+            if let Some(id) = request {
+                net.unpark(id);
+            }
+            net.mark_dead();
         }
-        res.map(|_| resp)
+        res.map(|_| request)
     }
 
     pub fn send_msg(
@@ -339,17 +1052,12 @@ impl Client {
         msg_data: FsyncerMsg,
         flush: bool,
     ) -> Result<(), Error<io::Error>> {
-        self.response_msg(msg_data, flush, false)?;
-        Ok(())
+        self.response_msg(msg_data, flush, None).map(|_| ())
     }
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
-        // Unblock all threads that could be waiting on this client
-        for (_, thread) in self.net.lock().unwrap().parked.iter() {
-            assert!(Arc::strong_count(thread) <= 2);
-            thread.notify(ClientAck::Dead);
-        }
+        self.net.lock().unwrap().mark_dead();
     }
 }
@@ -0,0 +1,91 @@
+use common::*;
+use server::SYNC_LIST;
+
+metablock!(cfg(target_family = "unix") {
+    use server::JOURNAL;
+});
+
+// Carries out a request from the control client control_id and replies to it
+pub fn control(cmd: ControlCmd, control_id: u64) {
+    info!("Control requested {:?}", cmd);
+    let res = match cmd {
+        ControlCmd::ListClients => Ok(ControlInfo::Clients(
+            SYNC_LIST
+                .read()
+                .expect("Failed to lock SYNC_LIST")
+                .iter()
+                .map(|c| c.info())
+                .collect(),
+        )),
+        ControlCmd::Disconnect(id) => disconnect(id),
+        ControlCmd::SetIolimit(id, bps) => set_iolimit(id, bps),
+        ControlCmd::FlushJournal => flush_journal(),
+        ControlCmd::JournalStatus => Ok(ControlInfo::Journal(journal_status())),
+    };
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    if let Some(control) = list.iter().find(|c| c.id == control_id) {
+        if let Err(e) = control.send_msg(FsyncerMsg::ControlReply(res), true) {
+            error!("Failed to reply to control {}", e);
+        }
+    }
+}
+
+fn disconnect(id: u64) -> Result<ControlInfo, String> {
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    let client = list
+        .iter()
+        .find(|c| c.id == id)
+        .ok_or_else(|| format!("No client {} is connected", id))?;
+    client.kill("disconnected by control");
+    Ok(ControlInfo::Done)
+}
+
+fn set_iolimit(id: u64, bps: usize) -> Result<ControlInfo, String> {
+    // The limiter works in KB/s steps
+    if bps != 0 && bps < 1000 {
+        return Err("iolimit has to be at least 1K".into());
+    }
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    let client = list
+        .iter()
+        .find(|c| c.id == id)
+        .ok_or_else(|| format!("No client {} is connected", id))?;
+    info!("Limiting client {} to {} bytes/s", client.label(), bps);
+    client.set_iolimit(bps);
+    Ok(ControlInfo::Done)
+}
+
+#[cfg(target_family = "unix")]
+fn flush_journal() -> Result<ControlInfo, String> {
+    let journal = unsafe { JOURNAL.as_ref() }
+        .ok_or_else(|| String::from("Server keeps no journal"))?;
+    journal
+        .lock()
+        .unwrap()
+        .sync()
+        .map_err(|e| format!("Failed to flush the journal {}", e))?;
+    Ok(ControlInfo::Done)
+}
+
+#[cfg(target_os = "windows")]
+fn flush_journal() -> Result<ControlInfo, String> {
+    Err("Server keeps no journal".into())
+}
+
+#[cfg(target_family = "unix")]
+fn journal_status() -> Option<JournalInfo> {
+    let mut journal = unsafe { JOURNAL.as_ref() }?.lock().unwrap();
+    let (used, size) = journal.fill();
+    let (filestore_used, filestore_size) = journal.fstore().fill();
+    Some(JournalInfo {
+        used,
+        size,
+        filestore_used,
+        filestore_size,
+    })
+}
+
+#[cfg(target_os = "windows")]
+fn journal_status() -> Option<JournalInfo> {
+    None
+}
@@ -0,0 +1,227 @@
+use clap::ArgMatches;
+use common::*;
+use error::{Error, FromError};
+use server::SYNC_LIST;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+metablock!(cfg(target_family = "unix") {
+    use common::version::Version;
+    use server::client::Client;
+    use server::{async_msg, boundary, cork_server, last_op_id, uncork_server};
+    use server::SERVER_PATH;
+    use std::borrow::Cow;
+});
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MismatchPolicy {
+    // Report it and carry on
+    Log,
+    // The replica has to resynchronise before it is sent anything again
+    Disconnect,
+    // The paths the op changed are sent again as the backing store has them
+    Resync,
+}
+
+impl MismatchPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "log" => Some(MismatchPolicy::Log),
+            "disconnect" => Some(MismatchPolicy::Disconnect),
+            "resync" => Some(MismatchPolicy::Resync),
+            _ => None,
+        }
+    }
+}
+
+static mut POLICY: MismatchPolicy = MismatchPolicy::Log;
+// Directory with a file per replica of the ops it diverged on
+static mut LOG_DIR: Option<PathBuf> = None;
+
+pub fn configure(matches: &ArgMatches) -> Result<(), Error<io::Error>> {
+    let policy = matches.value_of("on-mismatch").unwrap();
+    let policy =
+        MismatchPolicy::parse(policy).expect("Invalid mismatch policy");
+    let log_dir = match matches.value_of("divergence-log") {
+        Some(dir) => {
+            trace!(fs::create_dir_all(dir));
+            Some(PathBuf::from(dir))
+        }
+        None => None,
+    };
+    unsafe {
+        POLICY = policy;
+        LOG_DIR = log_dir;
+    }
+    Ok(())
+}
+
+// What is kept of an op that replicas acknowledge, to report and repair a
+// mismatch without holding on to its data
+#[derive(Clone, Debug)]
+pub struct OpSummary {
+    pub name: &'static str,
+    pub paths: Vec<PathBuf>,
+}
+
+impl OpSummary {
+    pub fn new(call: &VFSCall) -> Self {
+        let mut paths = vec![call.target().to_path_buf()];
+        if let VFSCall::rename { from, .. } = call {
+            paths.push(from.to_path_buf());
+        }
+        OpSummary {
+            name: call.name(),
+            paths,
+        }
+    }
+}
+
+// The replica's result for op differs from the server's, it no longer has
+// what the server has.
+pub fn mismatch(client: u64, op: &OpSummary, ret: i32, code: i32) {
+    let label = SYNC_LIST
+        .read()
+        .expect("Failed to lock SYNC_LIST")
+        .iter()
+        .find(|c| c.id == client)
+        .map(|c| c.label())
+        .unwrap_or_else(|| format!("{} (disconnected)", client));
+    warn!(
+        client = label, op = op.name, path = op.paths;
+        "Response from client is {}, server's is {}",
+        code,
+        ret
+    );
+    if let Some(dir) = unsafe { LOG_DIR.as_ref() } {
+        if let Err(e) = record(dir, &label, op, ret, code) {
+            error!(client = label; "Failed to record divergence {}", e);
+        }
+    }
+    match unsafe { POLICY } {
+        MismatchPolicy::Log => {}
+        MismatchPolicy::Disconnect => disconnect(client),
+        MismatchPolicy::Resync => {
+            let op = op.clone();
+            thread::spawn(move || resync(client, &op));
+        }
+    }
+}
+
+fn record(
+    dir: &Path,
+    label: &str,
+    op: &OpSummary,
+    ret: i32,
+    code: i32,
+) -> Result<(), io::Error> {
+    let name: String = label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("{}.log", name)))?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    writeln!(
+        log,
+        "{} {} {:?} server {} replica {}",
+        time, op.name, op.paths, ret, code
+    )
+}
+
+fn disconnect(client: u64) {
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    if let Some(client) = list.iter().find(|c| c.id == client) {
+        // Tells it not to resume, what it has can't be built on
+        let msg = FsyncerMsg::Diverged("result of an op differed".into());
+        if let Err(e) = client.send_msg(msg, true) {
+            error!(client = client.label(); "Failed to tell client it diverged {}", e);
+        }
+        client.kill("diverged from the server");
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn resync(client: u64, op: &OpSummary) {
+    // Nothing may change the paths while they are read
+    cork_server();
+    let repaired = {
+        let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+        match list.iter().find(|c| c.id == client) {
+            Some(client) => resync_paths(client, op),
+            None => true,
+        }
+    };
+    uncork_server();
+    if !repaired {
+        disconnect(client);
+    }
+}
+
+/* The paths are sent under the id of the last op, the server is corked so
+ * every op up to it was queued to the client before these. The replica's
+ * position only moves past an op once it applied everything received before
+ * it, so by the time it counts these it has everything up to that id and it
+ * is where it would resume from anyway. False if a path couldn't be. */
+#[cfg(target_family = "unix")]
+fn resync_paths(client: &Client, op: &OpSummary) -> bool {
+    let root = unsafe { SERVER_PATH.as_ref().unwrap() };
+    // The op that diverged was sent, so there is one
+    let op_id = match last_op_id() {
+        Some(op_id) => op_id,
+        None => return true,
+    };
+    let version = Version::default();
+    let mut repaired = true;
+    for path in &op.paths {
+        info!(client = client.label(), path = path; "Resynchronising");
+        let res = boundary::resync(path, &client.filter, root, |call| {
+            let msg = async_msg(client, Cow::Owned(call), op_id, &version);
+            if let Err(e) = client.response_msg(msg, false, None) {
+                error!(
+                    client = client.label();
+                    "Failed sending op to client {}",
+                    e
+                );
+            }
+        });
+        match res {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(
+                    client = client.label(), path = path;
+                    "Server no longer has the path, can't resynchronise it"
+                );
+                repaired = false;
+            }
+            Err(e) => {
+                error!(
+                    client = client.label(), path = path;
+                    "Failed to resynchronise {}",
+                    e
+                );
+                repaired = false;
+            }
+        }
+    }
+    if let Err(e) = client.flush() {
+        error!(client = client.label(); "Failed to flush to client {}", e);
+    }
+    repaired
+}
+
+#[cfg(target_os = "windows")]
+fn resync(_client: u64, op: &OpSummary) {
+    warn!(
+        "Not resynchronising {:?}, not supported on windows",
+        op.paths
+    );
+}
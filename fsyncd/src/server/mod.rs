@@ -1,11 +1,17 @@
 metablock!(cfg(target_family = "unix") {
     use fuse_hl::start_fuse;
-    use journal::{BilogEntry, Journal, JournalConfig, JournalType};
+    use journal::{BilogEntry, EntryContent, Journal, JournalConfig, JournalType};
     use std::env;
     use std::fs::OpenOptions;
     static mut JOURNAL: Option<Mutex<Journal>> = None;
     static mut JOURNAL_TYPE: JournalType = JournalType::Invalid;
-    use std::os::unix::net::UnixListener;
+    use common::auth::AuthConfig;
+    use client::{follow_peer, follow_primary, Position};
+    mod peer;
+    use self::peer::{ConflictPolicy, PeerConfig};
+    // Set when this node accepts writes from a peer
+    pub static mut PEER: Option<PeerConfig> = None;
+    static mut MOUNT_PATH: Option<PathBuf> = None;
 });
 
 metablock!(cfg(target_os = "windows") {
@@ -26,40 +32,99 @@ metablock!(cfg(target_os = "windows") {
     pub static mut TRANSLATE_SIDS: bool = true;
 });
 
+#[cfg(target_family = "unix")]
+mod boundary;
 mod client;
-pub mod net;
+mod control;
+mod divergence;
+mod queue;
 
-use self::client::{Client, ClientResponse, ClientStatus};
+use self::client::{Client, ClientResponse, ClientStatus, ClientWatch, Reply};
+use self::divergence::OpSummary;
 use clap::ArgMatches;
+use common::auth::AuthKeys;
+use common::epoch;
 use common::file_security::copy_security;
+use common::filter::{FilterSpec, PathFilter, Route};
+use common::metrics;
+use common::tls::TlsConfig;
+use common::version::Version;
 use common::*;
 use error::{Error, FromError};
 use libc::c_int;
 use std::fs;
 use std::io::{self, ErrorKind};
-use std::net::TcpListener;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::{
-    borrow::Cow, mem::transmute, ops::Deref, process::Command, thread,
-    time::Duration,
+    borrow::Cow,
+    ops::Deref,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
 };
+use url::Url;
 
 pub static mut SERVER_PATH: Option<PathBuf> = None;
+pub static mut AUTH_KEYS: Option<AuthKeys> = None;
 pub static mut DIFF_WRITES: bool = false;
+pub static mut RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+// Synchronous clients a write waits for, all of them when not set. A write
+// fewer of them acknowledge fails with QUORUM_FAILED after it was applied,
+// while fewer are connected writes are refused with it before.
+static mut QUORUM: Option<usize> = None;
+#[cfg(target_family = "unix")]
+const QUORUM_FAILED: i32 = -libc::EIO;
+#[cfg(target_os = "windows")]
+const QUORUM_FAILED: i32 = winapi::shared::winerror::ERROR_IO_DEVICE as i32;
+// Scratch paths that are only ever applied locally, never journaled or sent
+pub static mut EXCLUDE: Option<PathFilter> = None;
+// Synchronous clients that take longer than this are not waited for, until
+// they catch up.
+pub static mut FALLBACK_TIMEOUT: Option<Duration> = None;
+static mut HEARTBEAT: HeartbeatConfig = HeartbeatConfig {
+    interval: Duration::from_secs(1),
+    timeout: Duration::from_secs(10),
+};
+// Op ids when there is no forward journal to take them from
+static NEXT_OP_ID: AtomicU64 = AtomicU64::new(0);
+// Set once a replica took over, writes are refused from then on
+pub static DEMOTED: AtomicBool = AtomicBool::new(false);
+// Ops passed on downstream are being replayed, see set_replaying
+static REPLAYING: AtomicBool = AtomicBool::new(false);
+// Epoch this server is primary in, or a replica serving downstream follows
+pub static EPOCH: AtomicU64 = AtomicU64::new(0);
+// Flush interval in seconds for asynchronous downstream replicas
+#[cfg(target_family = "unix")]
+const DOWNSTREAM_FLUSH_INTERVAL: u64 = 1;
+// How often replicas that are behind are reported
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+// Journal entries read at a time when catching up a client
+#[cfg(target_family = "unix")]
+const CATCHUP_CHUNK: usize = 1024;
+// Catching up is finished holding up writes once it is down to this many
+#[cfg(target_family = "unix")]
+const CATCHUP_FINAL: usize = 64;
+#[cfg(target_family = "unix")]
+const CATCHUP_ROUNDS: usize = 8;
 
 lazy_static! {
     static ref SYNC_LIST: RwLock<Vec<Client>> = RwLock::new(Vec::new());
     static ref CORK_VAR: Condvar = Condvar::new();
     static ref CORK: Mutex<bool> = Mutex::new(false);
+    static ref LATE_ACKS: Mutex<Vec<LateAcks>> = Mutex::new(Vec::new());
+    // Primary to follow once this server is demoted, and the last op it had
+    static ref NEW_PRIMARY: Mutex<Option<(Url, Option<u64>)>> =
+        Mutex::new(None);
 }
 
 fn flush_thread(interval: u64) {
     loop {
         let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
         for client in list.iter().filter(|c| c.mode == ClientMode::MODE_ASYNC) {
-            if client.flush().is_err() {
-                eprintln!("Failed to flush to client");
+            if let Err(e) = client.flush() {
+                error!(client = client.label(); "Failed to flush to client {}", e);
             }
         }
         drop(list);
@@ -88,149 +153,1048 @@ fn harvester_thread() {
     }
 }
 
+// Keeps idle clients alive and drops those that went silent, a half-open
+// connection would otherwise only be noticed when writing to it fails.
+fn heartbeat_thread() {
+    let config = unsafe { HEARTBEAT };
+    loop {
+        let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+        for client in list
+            .iter()
+            .filter(|c| c.capabilities.contains(Capabilities::HEARTBEAT))
+        {
+            let silent = client.silent_for();
+            if silent > config.timeout {
+                client.kill(&format!("silent for {:?}", silent));
+            } else if let Err(e) = client.heartbeat() {
+                error!(
+                    client = client.label();
+                    "Failed to send heartbeat to client {}",
+                    e
+                );
+            }
+        }
+        drop(list);
+        thread::sleep(config.interval);
+    }
+}
+
+// Replicas report what they have applied, so it is known how far behind
+// each one is before relying on it.
+fn lag_thread() {
+    loop {
+        thread::sleep(LAG_REPORT_INTERVAL);
+        let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+        for client in list.iter() {
+            let (ops, bytes) = client.lag();
+            if ops != 0 {
+                info!(
+                    client = client.label();
+                    "Client is {} ops ({} bytes) behind",
+                    ops,
+                    bytes
+                );
+            }
+        }
+    }
+}
+
 pub fn cork_server() {
-    eprintln!("Corking");
+    info!("Corking");
     *CORK.lock().unwrap() = true;
     // Cork the individual clients
     let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
     for client in list.deref() {
         if let Err(e) = client.cork() {
-            eprintln!("Failed to cork client {}", e);
+            error!(client = client.label(); "Failed to cork client {}", e);
         }
     }
-    eprintln!("Cork done");
+    info!("Cork done");
 }
 
 pub fn uncork_server() {
-    eprintln!("Uncorking");
+    info!("Uncorking");
     let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
     for client in list.deref() {
         if let Err(e) = client.uncork() {
-            eprintln!("Failed to uncork client {}", e);
+            error!(client = client.label(); "Failed to uncork client {}", e);
         }
     }
     drop(list);
     *CORK.lock().unwrap() = false;
     CORK_VAR.notify_all();
-    eprintln!("Uncork done");
+    info!("Uncork done");
+}
+
+// Id of the last op sent to replicas, None if there were none yet
+fn last_op_id() -> Option<u64> {
+    #[cfg(target_family = "unix")]
+    unsafe {
+        if JOURNAL.is_some() && JOURNAL_TYPE == JournalType::Forward {
+            let ctr = JOURNAL.as_ref().unwrap().lock().unwrap().trans_ctr();
+            return ctr.checked_sub(1);
+        }
+    }
+    NEXT_OP_ID.load(Ordering::SeqCst).checked_sub(1)
+}
+
+// Journal offset of the op after op_id, if the journal still has it, and
+// for a peer the versions of what follows
+#[cfg(target_family = "unix")]
+pub fn find_resume_offset(op_id: u64, peer: bool) -> Option<u64> {
+    if unsafe { JOURNAL.is_none() || JOURNAL_TYPE != JournalType::Forward } {
+        return None;
+    }
+    if peer && last_op_id() != Some(op_id) && !peer::knows_after(op_id) {
+        return None;
+    }
+    let mut j = unsafe { JOURNAL.as_ref().unwrap() }.lock().unwrap();
+    match j.offset_after::<VFSCall>(op_id) {
+        Ok(offset) => offset,
+        Err(e) => {
+            error!("Failed to read journal {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn find_resume_offset(_: u64, _: bool) -> Option<u64> {
+    None
+}
+
+// Whether this node takes writes from peers
+pub fn peering() -> bool {
+    #[cfg(target_family = "unix")]
+    unsafe {
+        PEER.is_some()
+    }
+    #[cfg(target_os = "windows")]
+    false
+}
+
+// Streams journaled ops from offset until the end of the journal, advancing
+// offset as it goes. Returns how many there were. Renames across the client's
+// filter are expanded against the tree as it is now, like they are live.
+#[cfg(target_family = "unix")]
+fn catch_up(
+    client: &Client,
+    offset: &mut u64,
+) -> Result<usize, Error<io::Error>> {
+    let peer = client.capabilities.contains(Capabilities::PEER);
+    let mut sent = 0;
+    loop {
+        let mut ops = Vec::with_capacity(CATCHUP_CHUNK);
+        {
+            // Don't hold the journal while sending, it would stall the
+            // filesystem.
+            let mut j = unsafe { JOURNAL.as_ref().unwrap() }.lock().unwrap();
+            let mut entries = match j.read_forward_from::<VFSCall>(*offset) {
+                Some(entries) => entries,
+                None => {
+                    return Err(trace_err!(io::Error::new(
+                        ErrorKind::NotFound,
+                        "Journal was overwritten during catch up",
+                    )))
+                }
+            };
+            while ops.len() < CATCHUP_CHUNK {
+                match entries.next() {
+                    Some(entry) => ops.push(trace!(entry)),
+                    None => break,
+                }
+            }
+            *offset = entries.offset();
+        }
+        let done = ops.len() < CATCHUP_CHUNK;
+        sent += ops.len();
+        for entry in ops {
+            let op_id = entry.trans_id();
+            if let EntryContent::Payload(call) = entry.take_content() {
+                // Same as send_op, peers get the op's version and not what
+                // came from a peer
+                let version = if peer {
+                    match trace!(peer::journaled_version(op_id)) {
+                        Some(version) => version,
+                        None => continue,
+                    }
+                } else {
+                    Version::default()
+                };
+                let call = match client.filter.route(&call) {
+                    Route::Send => call,
+                    Route::Skip => continue,
+                    route => match send_expansion(
+                        client, &call, &route, op_id, &version,
+                    ) {
+                        Some(last) => last,
+                        None => continue,
+                    },
+                };
+                let msg = async_msg(client, Cow::Owned(call), op_id, &version);
+                trace!(client.send_msg(msg, false));
+            }
+        }
+        if done {
+            return Ok(sent);
+        }
+    }
+}
+
+/* Adds a newly connected client to SYNC_LIST, on the thread of its
+ * connection. If it is resuming, the ops it missed are streamed from the
+ * journal first. That happens while the filesystem is live, in rounds until
+ * what was written meanwhile is short, only that last stretch is done holding
+ * SYNC_LIST, so no op can fall in between the journal and what the client
+ * receives live. */
+fn add_client(client: Client) {
+    #[cfg(target_family = "unix")]
+    let mut offset = client.catch_up_from;
+    #[cfg(target_family = "unix")]
+    {
+        if let Some(ref mut offset) = offset {
+            info!(client = client.label(); "Catching up client from the journal");
+            for _ in 0..CATCHUP_ROUNDS {
+                match catch_up(&client, offset) {
+                    Ok(sent) if sent <= CATCHUP_FINAL => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!(
+                            client = client.label();
+                            "Failed to catch up client {:?}",
+                            e
+                        );
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut list = SYNC_LIST.write().expect("Failed to lock SYNC_LIST");
+    #[cfg(target_family = "unix")]
+    {
+        if let Some(ref mut offset) = offset {
+            if let Err(e) = catch_up(&client, offset) {
+                error!(client = client.label(); "Failed to catch up client {:?}", e);
+                return;
+            }
+        }
+    }
+    let caught_up = last_op_id();
+    // Had nothing to catch up on when it connected, but may have by now
+    if client.catch_up_from.is_none()
+        && client.resume_from.is_some()
+        && client.resume_from != caught_up
+    {
+        client.kill("missed ops while connecting");
+        return;
+    }
+    if let Err(e) = client.send_msg(FsyncerMsg::CaughtUp(caught_up), true) {
+        error!(client = client.label(); "Failed handling client {:?}", e);
+        return;
+    }
+    info!(client = client.label(); "Client is up to date with op {:?}", caught_up);
+    if REPLAYING.load(Ordering::SeqCst)
+        && client.mode != ClientMode::MODE_CONTROL
+    {
+        if let Err(e) = client.send_msg(FsyncerMsg::Replaying(true), false) {
+            error!(client = client.label(); "Failed handling client {:?}", e);
+            return;
+        }
+    }
+    client.caught_up();
+    list.push(client);
+}
+
+// Keeps a replica that can't dial in connected, redialling whenever the
+// connection is lost.
+#[cfg(target_family = "unix")]
+fn replica_thread(
+    url: Url,
+    tls: Option<TlsConfig>,
+    buffer_size: usize,
+    dont_check: bool,
+) {
+    use common::net::{
+        self, Role, RECONNECT_MAX_BACKOFF, RECONNECT_MIN_BACKOFF,
+    };
+    let mut backoff = RECONNECT_MIN_BACKOFF;
+    loop {
+        let client = net::dial(&url, Role::Sender, buffer_size, tls.as_ref())
+            .and_then(|(netin, netout)| {
+                Client::from_stream(netin, netout, dont_check)
+            });
+        match client {
+            Ok(client) => {
+                info!("Connected to replica {}", url);
+                backoff = RECONNECT_MIN_BACKOFF;
+                let watch = client.watch();
+                add_client(client);
+                while watch.status() != ClientStatus::DEAD {
+                    thread::sleep(Duration::from_secs(1));
+                }
+                warn!("Lost connection to replica {}", url);
+            }
+            Err(e) => {
+                error!("Failed to connect to replica {} {:?}", url, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+// Accepts replicas on url, and starts the threads that look after them
+fn serve(
+    url: &Url,
+    tls: Option<&TlsConfig>,
+    flush_interval: u64,
+    buffer_size: usize,
+    dont_check: bool,
+) -> Result<(), Error<io::Error>> {
+    use common::net::{self, Role};
+
+    if flush_interval != 0 {
+        thread::spawn(move || flush_thread(flush_interval));
+    }
+
+    thread::spawn(harvester_thread);
+    thread::spawn(heartbeat_thread);
+    thread::spawn(late_ack_thread);
+    thread::spawn(lag_thread);
+
+    match url.scheme() {
+        "tcp" | "tls" | "unix" => {
+            let listener = trace!(net::bind(url, tls));
+            thread::spawn(move || {
+                while let Ok((accepted, addr)) =
+                    listener.accept(Role::Sender, buffer_size)
+                {
+                    info!("Received connection from client {:?}", addr);
+                    // A client that is slow to shake hands or catch up
+                    // doesn't hold up the ones connecting after it
+                    thread::spawn(move || {
+                        let client = accepted().and_then(|(netin, netout)| {
+                            Client::from_stream(netin, netout, dont_check)
+                        });
+                        match client {
+                            Ok(client) => add_client(client),
+                            Err(e) => error!(
+                                "Failed handling client {} {:?}",
+                                addr, e
+                            ),
+                        }
+                    });
+                }
+            });
+        }
+        "stdio" => {
+            use std::fs::File;
+            use std::os::unix::io::FromRawFd;
+            thread::spawn(move || {
+                match Client::from_stream(
+                    Box::new(unsafe { File::from_raw_fd(0) }) as _,
+                    Box::new(unsafe { File::from_raw_fd(1) }) as _,
+                    dont_check,
+                ) {
+                    Ok(client) => add_client(client),
+                    Err(e) => error!("Failed handling client {:?}", e),
+                }
+            });
+        }
+        otherwise => panic!("Scheme {} is not supported", otherwise),
+    };
+    Ok(())
+}
+
+/*
+    A replica can republish what it applies to replicas of its own (client
+    --downstream), so a write crosses a slow link once and is fanned out on
+    the other side. The replica's tree is served just like a server's backing
+    store, ops it applies go through pre_op and post_op like they would on a
+    server.
+*/
+#[cfg(target_family = "unix")]
+pub fn serve_downstream(
+    client_matches: &ArgMatches,
+    path: &Path,
+) -> Result<(), Error<io::Error>> {
+    let url = Url::parse(client_matches.value_of("downstream").unwrap())
+        .expect("Invalid downstream url specified");
+    let buffer_size =
+        parse_human_size(client_matches.value_of("buffer").unwrap())
+            .expect("Buffer format incorrect");
+    unsafe {
+        SERVER_PATH = Some(path.to_path_buf());
+        AUTH_KEYS = trace!(AuthKeys::from_paths(
+            client_matches.value_of("downstream-auth-secret"),
+            client_matches.value_of("downstream-auth-keys"),
+        ));
+        if AUTH_KEYS.is_none() {
+            warn!(
+                "downstream authentication is disabled, anyone who \
+                 can connect can replicate from this replica"
+            );
+        }
+        HEARTBEAT = HeartbeatConfig::from_matches(client_matches);
+    }
+    info!("Serving downstream replicas on {}", url);
+    let tls = TlsConfig::from_matches(client_matches);
+    serve(
+        &url,
+        tls.as_ref(),
+        DOWNSTREAM_FLUSH_INTERVAL,
+        buffer_size,
+        false,
+    )
+}
+
+// Disconnects everyone, they have to reconnect and check against whatever
+// the tree is now.
+pub fn drop_clients(reason: &str) {
+    for client in SYNC_LIST.read().expect("Failed to lock SYNC_LIST").iter() {
+        client.kill(reason);
+    }
+}
+
+/*
+    Planned failover. The server is corked, so nothing changes while the
+    replica applies what it was sent, once it acknowledges the last op it
+    takes over and the other replicas are pointed at it. They resume from the
+    op id they were at, which the promoted replica kept using for what it
+    forwarded. This server stops serving the filesystem and follows the new
+    primary like any other replica.
+*/
+#[cfg(target_family = "unix")]
+fn promote(target: String, control_id: u64) {
+    cork_server();
+    let res = hand_over(&target);
+    uncork_server();
+    if res.is_ok() {
+        info!("Handed over to {}, unmounting", target);
+        let mount_path = unsafe { MOUNT_PATH.as_ref().unwrap() };
+        let unmounted = Command::new("fusermount")
+            .arg("-u")
+            .arg("-z")
+            .arg(mount_path)
+            .status();
+        if !unmounted.map(|s| s.success()).unwrap_or(false) {
+            error!("Failed to unmount {:?}", mount_path);
+        }
+    }
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    if let Some(control) = list.iter().find(|c| c.id == control_id) {
+        if let Err(e) = control.send_msg(FsyncerMsg::Promoted(res), true) {
+            error!("Failed to reply to control {}", e);
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn promote(_: String, control_id: u64) {
+    let res = Err("Handing over is not supported on windows".into());
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    if let Some(control) = list.iter().find(|c| c.id == control_id) {
+        if let Err(e) = control.send_msg(FsyncerMsg::Promoted(res), true) {
+            error!("Failed to reply to control {}", e);
+        }
+    }
+}
+
+// Waits for the replica serving on target to have everything, then hands
+// the filesystem over to it. The server must be corked.
+#[cfg(target_family = "unix")]
+fn hand_over(target: &str) -> Result<(), String> {
+    let url = Url::parse(target).map_err(|e| e.to_string())?;
+    /* send_op numbers, journals and sends an op under the read lock, with
+     * the write lock none is half way through, and corked, none starts */
+    let last = {
+        let _list = SYNC_LIST.write().expect("Failed to lock SYNC_LIST");
+        last_op_id()
+    };
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    let replica = list
+        .iter()
+        .find(|c| c.downstream.as_ref().map(String::as_str) == Some(target))
+        .ok_or_else(|| {
+            format!("No replica serving on {} is connected", target)
+        })?;
+    if !replica.filter.is_empty() {
+        return Err("A replica that filters paths can't take over".into());
+    }
+
+    let response = Arc::new(ClientResponse::new());
+    let msg = FsyncerMsg::Handover(0, last);
+    if let Err(e) = replica.response_msg(msg, true, Some(&response)) {
+        return Err(format!("Failed to start handover {}", e));
+    }
+    info!("Waiting for {} to apply up to op {:?}", target, last);
+    let acks = response
+        .wait_until(unsafe { RESPONSE_TIMEOUT }, |acks| !acks.is_empty());
+    if acks.first().map(|r| &r.ack) != Some(&ClientAck::Ack) {
+        // It may be about to take over, it must not
+        replica.kill("did not finish the handover");
+        return Err(format!("{} did not apply everything in time", target));
+    }
+
+    DEMOTED.store(true, Ordering::SeqCst);
+    *NEW_PRIMARY.lock().unwrap() = Some((url, last));
+    if let Err(e) = replica.send_msg(FsyncerMsg::Promoted(Ok(())), true) {
+        error!("Failed to confirm handover {}", e);
+    }
+    for client in list
+        .iter()
+        .filter(|c| c.id != replica.id && c.mode != ClientMode::MODE_CONTROL)
+    {
+        let msg = FsyncerMsg::Redirect(target.to_string());
+        if let Err(e) = client.send_msg(msg, true) {
+            error!(client = client.label(); "Failed to redirect client {}", e);
+        }
+    }
+    Ok(())
+}
+
+/* A replica that was promoted, path is the tree it kept in sync. The tree
+ * becomes the backing store the way a server's mount path would, and the
+ * filesystem is served on top of it. Replicas of its own stay connected. */
+#[cfg(target_family = "unix")]
+pub fn take_over(
+    path: &Path,
+    last: Option<u64>,
+) -> Result<(), Error<io::Error>> {
+    let backing_store = default_backing_store(path);
+    info!(
+        "Taking over as primary, backing store is {:?}",
+        backing_store
+    );
+    trace!(fs::rename(path, &backing_store));
+    trace!(fs::create_dir(path));
+    trace!(copy_security(&backing_store, path));
+    // Everyone that follows this server from now on refuses the old one
+    let epoch = trace!(epoch::newest(&backing_store)) + 1;
+    trace!(epoch::set_primary(&backing_store, epoch));
+    EPOCH.store(epoch, Ordering::SeqCst);
+    info!("Primary in epoch {}", epoch);
+    unsafe {
+        SERVER_PATH = Some(backing_store);
+        MOUNT_PATH = Some(path.to_path_buf());
+    }
+    if let Some(last) = last {
+        NEXT_OP_ID.fetch_max(last + 1, Ordering::SeqCst);
+    }
+    start_fuse(path, env::args().skip_while(|v| v != "--").skip(1));
+    Ok(())
 }
 
 pub struct OpRef {
     pub ret: Option<c_int>,
-    waits: Vec<Arc<ClientResponse<ClientAck>>>,
+    response: Arc<ClientResponse<Reply>>,
+    // Number of clients that will acknowledge the op
+    expected: usize,
+    // With the id each one was sent the op under
+    clients: Vec<(ClientWatch, u64)>,
+    // Acks from degraded clients, checked but not waited for
+    background: Arc<ClientResponse<Reply>>,
+    background_clients: Vec<(ClientWatch, u64)>,
+    // What acks are checked against, kept when there are any
+    op: Option<OpSummary>,
+    // Where the version of the changed path goes once the op succeeds
+    version: Option<(PathBuf, Version)>,
+    // Ops within excluded paths stay here, no quorum is needed for them
+    replicated: bool,
+}
+
+// Acknowledgements still due for ops that returned without them
+struct LateAcks {
+    response: Arc<ClientResponse<Reply>>,
+    // Clients that were sent the op, until they acknowledge it
+    clients: Vec<(ClientWatch, u64)>,
+    ret: i32,
+    op: Option<OpSummary>,
+    since: Instant,
+}
+
+// Writes the op to the journal, returns its id if the journal can replay it
+#[cfg(target_family = "unix")]
+fn journal_op(call: &VFSCall, opref: &mut OpRef) -> Option<u64> {
+    // This is safe, journal is only initialized once.
+    if unsafe { JOURNAL.is_none() } {
+        return None;
+    }
+
+    //eprintln!("writing journal event {:?}", call);
+
+    use std::convert::TryFrom;
+    match unsafe { JOURNAL_TYPE } {
+        JournalType::Bilog => {
+            let bilog = BilogEntry::try_from((call, unsafe {
+                &SERVER_PATH.as_ref().unwrap() as &Path
+            }))
+            .expect("Failed to generate journal entry from vfscall");
+            if is_variant!(bilog, BilogEntry::filestore, struct) {
+                // Bypass real unlink when using filestore
+                opref.ret = Some(0);
+            }
+            {
+                // Reduce the time journal lock is held
+                let mut j =
+                    unsafe { JOURNAL.as_ref().unwrap() }.lock().unwrap();
+                j.write_entry(&bilog)
+                    .expect("Failed to write journal entry");
+            }
+            None
+        }
+        JournalType::Forward => {
+            // Reduce the time journal lock is held
+            let mut j = unsafe { JOURNAL.as_ref().unwrap() }.lock().unwrap();
+            j.write_entry(call).expect("Failed to write journal entry");
+            Some(j.trans_ctr() - 1)
+        }
+        _ => panic!("Cannot generate entries of type {:?}", unsafe {
+            JOURNAL_TYPE
+        }),
+    }
+}
+
+// Where an op that goes through send_op was made
+#[derive(PartialEq, Clone, Copy)]
+enum Origin {
+    Local,
+    Peer,
+    // Applied by a replica serving downstream, with the upstream op id
+    Upstream(u64),
 }
 
 pub fn pre_op(call: &VFSCall) -> OpRef {
+    send_op(call, Origin::Local, None)
+}
+
+// A replica serving downstream passes on what it applies under the op ids
+// it got them with, so its replicas can resume from it after a promotion.
+pub fn forward_op(call: &VFSCall, op_id: u64) -> OpRef {
+    send_op(call, Origin::Upstream(op_id), None)
+}
+
+/* A replica serving downstream replays what its server resends after
+ * reconnecting, some of which it had passed on already. Its own replicas are
+ * told, so they apply those ops idempotently as they do when resuming. */
+pub fn set_replaying(replaying: bool) {
+    let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+    if REPLAYING.swap(replaying, Ordering::SeqCst) == replaying {
+        return;
+    }
+    for client in list.iter().filter(|c| c.mode != ClientMode::MODE_CONTROL) {
+        let msg = FsyncerMsg::Replaying(replaying);
+        if let Err(e) = client.send_msg(msg, false) {
+            error!(
+                client = client.label();
+                "Failed to tell client about replaying {}",
+                e
+            );
+        }
+    }
+}
+
+/* Journals the op and sends it to clients. An op from a peer is not sent back
+ * to peers, and comes with the version to store, for ops made here it is
+ * worked out when peering. */
+fn send_op(
+    call: &VFSCall,
+    origin: Origin,
+    version: Option<(PathBuf, Version)>,
+) -> OpRef {
+    let from_peer = origin == Origin::Peer;
+    let mut opref = OpRef {
+        ret: None,
+        response: Arc::new(ClientResponse::new()),
+        expected: 0,
+        clients: Vec::new(),
+        background: Arc::new(ClientResponse::new()),
+        background_clients: Vec::new(),
+        op: None,
+        version,
+        replicated: false,
+    };
+    // A replica serving downstream counted it when it applied it
+    match origin {
+        Origin::Upstream(_) => {}
+        _ => metrics::OPS.inc(call.name()),
+    }
+
     // THIS MAY NO LONGER BE CORRECT
     let mut corked = CORK.lock().unwrap();
     while *corked {
         corked = CORK_VAR.wait(corked).unwrap();
     }
-    let mut opref = OpRef {
-        ret: None,
-        waits: Vec::new(),
-    };
 
-    let tid =
-        unsafe { transmute::<thread::ThreadId, u64>(thread::current().id()) };
+    // Writes made after a replica took over would be lost
+    #[cfg(target_family = "unix")]
+    {
+        if origin == Origin::Local && DEMOTED.load(Ordering::SeqCst) {
+            opref.ret = Some(-libc::EROFS);
+            return opref;
+        }
+    }
+
+    /* Ops entirely within excluded paths stop here, the caller still applies
+     * them. Ones crossing into or out of them go ahead, the excludes are part
+     * of every client's filter, which takes care of those, live and when
+     * catching up from the journal. They wait for the cork all the same. */
+    if let Some(exclude) = unsafe { EXCLUDE.as_ref() } {
+        if exclude.route(call) == Route::Skip {
+            return opref;
+        }
+    }
+    opref.replicated = true;
+
+    // What peers are told the path's version is
+    let mut peer_version = Version::default();
+    #[cfg(target_family = "unix")]
+    {
+        if let (false, Some(config)) = (from_peer, unsafe { PEER.as_ref() }) {
+            let (version, path) = peer::local_version(config, call);
+            opref.version = path.map(|path| (path, version.clone()));
+            peer_version = version;
+        }
+    }
+
     let list = SYNC_LIST.read().expect("Failed to lock SYNC_LIST");
+
+    // Without enough clients to make the quorum the write could only fail,
+    // after it was made
+    if let (Origin::Local, Some(quorum)) = (origin, unsafe { QUORUM }) {
+        if list.iter().filter(|c| waits_for(c, call)).count() < quorum {
+            opref.ret = Some(QUORUM_FAILED);
+            opref.replicated = false;
+            return opref;
+        }
+    }
+
+    /* The op is journaled while SYNC_LIST is held, so a client catching up
+     * (see add_client) either finds it in the journal or receives it below */
+    #[cfg(target_family = "unix")]
+    let op_id = journal_op(call, &mut opref);
+    #[cfg(target_family = "unix")]
+    {
+        if let (Some(op_id), true) = (op_id, unsafe { PEER.is_some() }) {
+            peer::journaled(op_id, &peer_version, from_peer);
+        }
+    }
+    #[cfg(target_os = "windows")]
+    let op_id = None;
+    let op_id = op_id.unwrap_or_else(|| match origin {
+        Origin::Upstream(op_id) => {
+            NEXT_OP_ID.fetch_max(op_id + 1, Ordering::SeqCst);
+            op_id
+        }
+        _ => NEXT_OP_ID.fetch_add(1, Ordering::SeqCst),
+    });
+
     for client in list.deref() {
+        let peer = client.capabilities.contains(Capabilities::PEER);
         if client.mode == ClientMode::MODE_CONTROL
+            || (peer && from_peer)
             || (is_variant!(&*call, VFSCall::fsync, struct)
                 && (client.mode == ClientMode::MODE_ASYNC
                     || client.mode == ClientMode::MODE_SEMISYNC))
         {
-            // Don't send anything to control, don't send flushes to
-            // asynchronous client.
+            // Don't send anything to control, don't echo ops back to peers,
+            // don't send flushes to asynchronous client.
             continue;
         }
-        let (msg, sync) = if client.mode == ClientMode::MODE_SYNC
+        let expanded;
+        let call = match client.filter.route(call) {
+            Route::Send => call,
+            Route::Skip => continue,
+            route => {
+                match send_expansion(client, call, &route, op_id, &peer_version)
+                {
+                    Some(last) => {
+                        expanded = last;
+                        &expanded
+                    }
+                    None => continue,
+                }
+            }
+        };
+        let (msg, sync) = if peer {
+            (
+                async_msg(client, Cow::Borrowed(call), op_id, &peer_version),
+                false,
+            )
+        } else if client.mode == ClientMode::MODE_SYNC
             || client.mode == ClientMode::MODE_SEMISYNC
             || (client.mode == ClientMode::MODE_FLUSHSYNC
                 && is_variant!(&*call, VFSCall::fsync, struct))
         {
-            (FsyncerMsg::SyncOp(Cow::Borrowed(call), tid), true)
+            (FsyncerMsg::SyncOp(Cow::Borrowed(call), 0, op_id, 0), true)
         } else {
-            (FsyncerMsg::AsyncOp(Cow::Borrowed(call)), false)
+            (FsyncerMsg::AsyncOp(Cow::Borrowed(call), op_id, 0), false)
+        };
+        // A degraded client still gets the op synchronously, its ack is
+        // what tells when it has caught up, but nothing waits for it.
+        let degraded = sync && client.degraded();
+        let response = match (sync, degraded) {
+            (false, _) => None,
+            (true, false) => Some(&opref.response),
+            (true, true) => Some(&opref.background),
         };
-        match client.response_msg(msg, sync, sync) {
-            Ok(None) => {}
-            Ok(Some(response)) => opref.waits.push(response),
-            Err(e) => eprintln!("Failed sending message to client {}", e),
+        match client.response_msg(msg, sync, response) {
+            Ok(Some(id)) if degraded => {
+                opref.background_clients.push((client.watch(), id))
+            }
+            Ok(Some(id)) => {
+                opref.expected += 1;
+                opref.clients.push((client.watch(), id));
+            }
+            Ok(_) => {}
+            Err(e) => error!(
+                client = client.label(), op = call.name(), path = call.target();
+                "Failed sending op to client {}",
+                e
+            ),
         }
     }
 
+    if opref.expected + opref.background_clients.len() != 0 {
+        opref.op = Some(OpSummary::new(call));
+    }
+
     /* Cork lock is held until here, it is used to make sure that any pending
      * operations get sent over the network, the flush operation will force
      * them to the other side */
+    drop(list);
     drop(corked);
 
-    #[cfg(target_family = "unix")]
-    {
-        // This is safe, journal is only initialized once.
-        if unsafe { JOURNAL.is_none() } {
-            return opref;
-        }
+    opref
+}
 
-        //eprintln!("writing journal event {:?}", call);
+// Whether post_op waits for client to acknowledge call, like send_op decides
+fn waits_for(client: &Client, call: &VFSCall) -> bool {
+    let fsync = is_variant!(&*call, VFSCall::fsync, struct);
+    let sync = match client.mode {
+        ClientMode::MODE_SYNC => true,
+        ClientMode::MODE_SEMISYNC => !fsync,
+        ClientMode::MODE_FLUSHSYNC => fsync,
+        _ => false,
+    };
+    sync && !client.capabilities.contains(Capabilities::PEER)
+        && !client.degraded()
+        && client.filter.route(call) != Route::Skip
+}
 
-        use std::convert::TryFrom;
-        match unsafe { JOURNAL_TYPE } {
-            JournalType::Bilog => {
-                let bilog = BilogEntry::try_from((call, unsafe {
-                    &SERVER_PATH.as_ref().unwrap() as &Path
-                }))
-                .expect("Failed to generate journal entry from vfscall");
-                if is_variant!(bilog, BilogEntry::filestore, struct) {
-                    // Bypass real unlink when using filestore
-                    opref.ret = Some(0);
-                }
-                {
-                    // Reduce the time journal lock is held
-                    let mut j =
-                        unsafe { JOURNAL.as_ref().unwrap() }.lock().unwrap();
-                    j.write_entry(&bilog)
-                        .expect("Failed to write journal entry");
-                }
-            }
-            JournalType::Forward => {
-                {
-                    // Reduce the time journal lock is held
-                    let mut j =
-                        unsafe { JOURNAL.as_ref().unwrap() }.lock().unwrap();
-                    j.write_entry(call).expect("Failed to write journal entry");
-                }
+// Sends all but the last op of a rename or link across a client's filter,
+// the last one is returned to go out in place of the op. The ones before it
+// carry the previous op id, a replica that only got part of them must not
+// count the op as applied.
+#[cfg(target_family = "unix")]
+fn send_expansion(
+    client: &Client,
+    call: &VFSCall,
+    route: &Route,
+    op_id: u64,
+    version: &Version,
+) -> Option<VFSCall<'static>> {
+    let root = unsafe { SERVER_PATH.as_ref().unwrap() };
+    let mut pending: Option<VFSCall<'static>> = None;
+    let res = boundary::expand(call, route, &client.filter, root, |next| {
+        if let Some(prev) = pending.replace(next) {
+            let msg = async_msg(
+                client,
+                Cow::Owned(prev),
+                op_id.wrapping_sub(1),
+                version,
+            );
+            if let Err(e) = client.response_msg(msg, false, None) {
+                error!(client = client.label(); "Failed sending op to client {}", e);
             }
-            _ => panic!("Cannot generate entries of type {:?}", unsafe {
-                JOURNAL_TYPE
-            }),
         }
+    });
+    if let Err(e) = res {
+        error!(
+            client = client.label(), op = call.name(), path = call.target();
+            "Failed to expand op for a filtered client {}",
+            e
+        );
     }
-    opref
+    pending
 }
 
-pub fn post_op(opref: OpRef, ret: i32) -> i32 {
-    for wait in opref.waits {
-        let client_ret = wait.wait();
-        if client_ret.is_none() {
-            eprintln!("Client did not respond");
-            continue;
+#[cfg(target_os = "windows")]
+fn send_expansion(
+    _client: &Client,
+    call: &VFSCall,
+    _route: &Route,
+    _op_id: u64,
+    _version: &Version,
+) -> Option<VFSCall<'static>> {
+    warn!("Not sending {:?} across a client's filter", call);
+    None
+}
+
+// Applies an op a peer sent, on top of what was changed here
+#[cfg(target_family = "unix")]
+pub fn apply_peer_op(call: &VFSCall, version: &Version) -> i32 {
+    match unsafe { PEER.as_ref() } {
+        Some(config) => peer::apply(config, call, version),
+        None => {
+            warn!("Got an op from a peer, but this node has no peers");
+            -libc::EPERM
         }
-        let client_ret = client_ret.unwrap();
-        match client_ret {
-            ClientAck::Dead => {
-                eprintln!("Client died before acknowledging write")
+    }
+}
+
+// Peers get ops along with the version of the path they change
+fn async_msg<'a>(
+    client: &Client,
+    call: Cow<'a, VFSCall<'a>>,
+    op_id: u64,
+    version: &Version,
+) -> FsyncerMsg<'a> {
+    if client.capabilities.contains(Capabilities::PEER) {
+        FsyncerMsg::PeerOp(call, op_id, version.clone(), 0)
+    } else {
+        FsyncerMsg::AsyncOp(call, op_id, 0)
+    }
+}
+
+fn check_acks(acks: &[Reply], ret: i32, op: Option<&OpSummary>) {
+    for reply in acks {
+        match (&reply.ack, op) {
+            (ClientAck::Dead, _) => warn!(
+                client = reply.client, op = op.map(|op| op.name);
+                "Client died before acknowledging write"
+            ),
+            (ClientAck::RetCode(code), Some(op)) if *code != ret => {
+                divergence::mismatch(reply.client, op, ret, *code)
             }
-            ClientAck::RetCode(code) if code != ret => eprintln!(
+            (ClientAck::RetCode(code), None) if *code != ret => warn!(
+                client = reply.client;
                 "Response from client {} does not match server {}",
-                code, ret
+                code,
+                ret
             ),
             _ => {}
         }
     }
+}
+
+fn is_ack(reply: &Reply) -> bool {
+    reply.ack != ClientAck::Dead
+}
+
+fn check_late(
+    response: Arc<ClientResponse<Reply>>,
+    clients: Vec<(ClientWatch, u64)>,
+    ret: i32,
+    op: Option<OpSummary>,
+) {
+    LATE_ACKS.lock().unwrap().push(LateAcks {
+        response,
+        clients,
+        ret,
+        op,
+        since: Instant::now(),
+    });
+}
+
+pub fn post_op(opref: OpRef, ret: i32) -> i32 {
+    #[cfg(target_family = "unix")]
+    {
+        if let (Some((path, version)), true) = (&opref.version, ret >= 0) {
+            peer::write_version(path, version);
+        }
+    }
+    if !opref.background_clients.is_empty() {
+        check_late(
+            opref.background,
+            opref.background_clients,
+            ret,
+            opref.op.clone(),
+        );
+    }
+    let expected = opref.expected;
+    // With a quorum only that many need to acknowledge, dead clients never
+    // count towards it, and the write fails when fewer do.
+    let replicated = opref.replicated;
+    let quorum = unsafe { QUORUM }.filter(|_| replicated);
+    let needed = quorum.unwrap_or(expected);
+    if needed == 0 {
+        return ret;
+    }
+    let fallback = unsafe { FALLBACK_TIMEOUT };
+    let acks = if expected < needed {
+        // Not enough clients were sent the op to make the quorum
+        opref.response.take()
+    } else {
+        let waiting = Instant::now();
+        let acks = opref.response.wait_until(
+            fallback.unwrap_or(unsafe { RESPONSE_TIMEOUT }),
+            |acks| {
+                acks.len() == expected
+                    || acks.iter().filter(|a| is_ack(a)).count() >= needed
+            },
+        );
+        metrics::POST_OP_WAIT.observe(waiting.elapsed());
+        acks
+    };
+    check_acks(&acks, ret, opref.op.as_ref());
+    let acked = acks.iter().filter(|a| is_ack(a)).count();
+    if acked < needed && (quorum.is_some() || fallback.is_none()) {
+        warn!(
+            op = opref.op.as_ref().map(|op| op.name),
+            path = opref.op.as_ref().map(|op| &op.paths);
+            "Only {} of {} clients acknowledged the write in time",
+            acked,
+            needed
+        );
+    }
+    if acks.len() < expected {
+        // Otherwise a quorum answered and the rest weren't waited for, or
+        // there were too few to wait for
+        if expected >= needed && acked < needed {
+            metrics::ACK_TIMEOUTS.add((expected - acks.len()) as u64);
+            if fallback.is_some() {
+                for (client, id) in &opref.clients {
+                    client.missed(*id);
+                }
+            }
+        }
+        check_late(opref.response, opref.clients, ret, opref.op);
+    }
+    if quorum.is_some() && acked < needed {
+        return QUORUM_FAILED;
+    }
     ret
 }
 
+// Checks the acknowledgements that arrive after an op returned
+fn late_ack_thread() {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let mut late_acks = LATE_ACKS.lock().unwrap();
+        for late in late_acks.iter_mut() {
+            // Before taking the acks, one that comes in between is left
+            // for the next round rather than missed
+            late.clients.retain(|(client, id)| client.waiting(*id));
+            let acks = late.response.take();
+            check_acks(&acks, late.ret, late.op.as_ref());
+            if late.since.elapsed() > unsafe { RESPONSE_TIMEOUT } {
+                for (client, _) in late.clients.drain(..) {
+                    error!(
+                        client = client.label(),
+                        op = late.op.as_ref().map(|op| op.name),
+                        path = late.op.as_ref().map(|op| &op.paths);
+                        "Client did not respond"
+                    );
+                }
+            }
+        }
+        late_acks.retain(|late| !late.clients.is_empty());
+    }
+}
+
 fn check_mount(path: &str) -> Result<bool, Error<io::Error>> {
     Ok(
         trace!(trace!(Command::new("mountpoint").arg(path).spawn()).wait())
@@ -238,6 +1202,17 @@ fn check_mount(path: &str) -> Result<bool, Error<io::Error>> {
     )
 }
 
+fn default_backing_store(mount_path: &Path) -> PathBuf {
+    mount_path.with_file_name(format!(
+        ".fsyncer-{}",
+        mount_path
+            .file_name()
+            .expect("You specified a weird file path")
+            .to_str()
+            .unwrap()
+    ))
+}
+
 fn figure_out_paths(
     matches: &ArgMatches,
 ) -> Result<(PathBuf, PathBuf), Error<io::Error>> {
@@ -284,14 +1259,7 @@ fn figure_out_paths(
             .canonicalize())
     } else {
         // Implictly inferring backing store
-        mount_path.with_file_name(format!(
-            ".fsyncer-{}",
-            mount_path
-                .file_name()
-                .expect("You specified a weird file path")
-                .to_str()
-                .unwrap()
-        ))
+        default_backing_store(&mount_path)
     };
 
     if !backing_store.exists() && mount_exists {
@@ -348,7 +1316,7 @@ fn figure_out_paths(
                     == winapi::shared::winerror::ERROR_SHARING_VIOLATION
             {
                 if !recreated {
-                    eprintln!("Mount path is busy, attempting to recreate it");
+                    info!("Mount path is busy, attempting to recreate it");
                     trace!(fs::remove_dir(&mount_path));
                     trace!(fs::create_dir(&mount_path));
                     trace!(copy_security(&backing_store, &mount_path));
@@ -396,9 +1364,32 @@ fn open_journal(
     }
 }
 
+// Patterns from --exclude and every --exclude-from file, one per line, blank
+// lines and ones starting with # are skipped
+fn read_excludes(
+    matches: &ArgMatches,
+) -> Result<Vec<String>, Error<io::Error>> {
+    let mut exclude: Vec<String> = matches
+        .values_of("exclude")
+        .into_iter()
+        .flatten()
+        .map(String::from)
+        .collect();
+    for path in matches.values_of("exclude-from").into_iter().flatten() {
+        let patterns = trace!(fs::read_to_string(path));
+        exclude.extend(
+            patterns
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from),
+        );
+    }
+    Ok(exclude)
+}
+
 pub fn server_main(matches: ArgMatches) -> Result<(), Error<io::Error>> {
     let server_matches = matches.subcommand_matches("server").unwrap();
-    use url::Url;
     // Parse args
     //debug!(server_matches.value_of("url").unwrap());
     let url = Url::parse(server_matches.value_of("url").unwrap())
@@ -417,6 +1408,28 @@ pub fn server_main(matches: ArgMatches) -> Result<(), Error<io::Error>> {
     unsafe {
         SERVER_PATH = Some(backing_store.clone());
     }
+    let mut primary_epoch = trace!(epoch::primary(&backing_store));
+    let newest_epoch = trace!(epoch::newest(&backing_store));
+    if server_matches.is_present("new-epoch") {
+        // Like take_over, whoever it followed is refused from now on
+        primary_epoch = newest_epoch + 1;
+        trace!(epoch::set_primary(&backing_store, primary_epoch));
+        info!("Primary in epoch {}", primary_epoch);
+    } else if newest_epoch > primary_epoch {
+        // It was failed over, the writes it would take would be lost
+        warn!(
+            "this tree followed a primary in epoch {}, but is \
+             primary in {}, refusing writes. Start it with --new-epoch if \
+             that primary is gone for good",
+            newest_epoch, primary_epoch
+        );
+        DEMOTED.store(true, Ordering::SeqCst);
+    }
+    EPOCH.store(primary_epoch, Ordering::SeqCst);
+    #[cfg(target_family = "unix")]
+    unsafe {
+        MOUNT_PATH = Some(mount_path.clone());
+    }
 
     let interval = server_matches
         .value_of("flush-interval")
@@ -429,61 +1442,79 @@ pub fn server_main(matches: ArgMatches) -> Result<(), Error<io::Error>> {
         }
     }
 
-    let dont_check = server_matches.is_present("dont-check");
-    let buffer_size =
-        parse_human_size(server_matches.value_of("buffer").unwrap())
-            .expect("Buffer format incorrect");
-
-    // Network
-
-    if interval != 0 {
-        thread::spawn(move || flush_thread(interval));
+    unsafe {
+        AUTH_KEYS = trace!(AuthKeys::from_matches(server_matches));
+        if AUTH_KEYS.is_none() {
+            warn!(
+                "authentication is disabled, anyone who can connect \
+                 can replicate from and cork this server"
+            );
+        }
     }
 
-    thread::spawn(harvester_thread);
+    trace!(divergence::configure(server_matches));
+    trace!(queue::configure(server_matches));
+    if let Some(addr) = server_matches.value_of("metrics") {
+        trace!(metrics::serve(addr));
+    }
 
-    use self::net::Listener;
+    let exclude = FilterSpec {
+        include: Vec::new(),
+        exclude: trace!(read_excludes(server_matches)),
+    };
+    if !exclude.exclude.is_empty() {
+        let filter = trace!(PathFilter::new(&exclude));
+        unsafe {
+            EXCLUDE = Some(filter);
+        }
+    }
 
-    match url.scheme() {
-        "tcp" | "unix" => {
-            let listener: Box<dyn Listener> = if url.scheme() == "tcp" {
-                let mut url = url.clone();
-                if url.port().is_none() {
-                    url.set_port(Some(2323)).unwrap();
-                }
-                Box::new(trace!(TcpListener::bind(url))) as _
-            } else {
-                if Path::new(url.path()).exists() {
-                    trace!(fs::remove_file(url.path()));
-                }
-                Box::new(trace!(UnixListener::bind(url.path()))) as _
-            };
-            thread::spawn(move || {
-                while let Ok((netin, netout, addr)) =
-                    listener.accept(buffer_size)
-                {
-                    eprintln!("Received connection from client {:?}", addr);
-                    let client = Client::from_stream(netin, netout, dont_check);
-                    match client {
-                        Ok(client) => SYNC_LIST.write().unwrap().push(client),
-                        Err(e) => eprintln!("Failed handling client {:?}", e),
-                    }
-                }
-            });
+    #[cfg(target_family = "unix")]
+    {
+        if let Some(node) = server_matches.value_of("node-id") {
+            let policy = server_matches.value_of("conflict").unwrap();
+            let policy =
+                ConflictPolicy::parse(policy).expect("Invalid conflict policy");
+            unsafe {
+                PEER = Some(PeerConfig {
+                    node: node.to_string(),
+                    policy,
+                });
+            }
         }
-        "stdio" => {
-            use std::fs::File;
-            use std::os::unix::io::FromRawFd;
-            thread::spawn(move || {
-                Client::from_stream(
-                    Box::new(unsafe { File::from_raw_fd(0) }) as _,
-                    Box::new(unsafe { File::from_raw_fd(1) }) as _,
-                    dont_check,
+    }
+
+    unsafe {
+        HEARTBEAT = HeartbeatConfig::from_matches(server_matches);
+        QUORUM = server_matches.value_of("quorum").map(|v| match v.parse() {
+            Ok(0) | Err(_) => panic!("Invalid quorum size"),
+            Ok(n) => n,
+        });
+        FALLBACK_TIMEOUT =
+            server_matches.value_of("fallback-timeout").map(|v| {
+                Duration::from_millis(
+                    v.parse().expect("Invalid format for fallback timeout"),
                 )
             });
-        }
-        otherwise => panic!("Scheme {} is not supported", otherwise),
-    };
+        RESPONSE_TIMEOUT = Duration::from_secs(
+            server_matches
+                .value_of("response-timeout")
+                .map(|v| {
+                    v.parse().expect("Invalid format for response timeout")
+                })
+                .unwrap(),
+        );
+    }
+
+    let dont_check = server_matches.is_present("dont-check");
+    let buffer_size =
+        parse_human_size(server_matches.value_of("buffer").unwrap())
+            .expect("Buffer format incorrect");
+
+    // Network
+
+    let tls = TlsConfig::from_matches(server_matches);
+    trace!(serve(&url, tls.as_ref(), interval, buffer_size, dont_check));
 
     // Journal
 
@@ -525,11 +1556,61 @@ pub fn server_main(matches: ArgMatches) -> Result<(), Error<io::Error>> {
         }
     }
 
+    // Ops peers send are journaled like any other, so only once there is a
+    // journal
+    #[cfg(target_family = "unix")]
+    {
+        for replica in server_matches.values_of("replica").into_iter().flatten()
+        {
+            let replica = Url::parse(replica).expect("Invalid replica url");
+            let tls = tls.clone();
+            thread::spawn(move || {
+                replica_thread(replica, tls, buffer_size, dont_check)
+            });
+        }
+        for peer in server_matches.values_of("peer").into_iter().flatten() {
+            let peer = Url::parse(peer).expect("Invalid peer url");
+            let tls = tls.clone();
+            let auth = trace!(AuthConfig::from_matches(server_matches));
+            let heartbeat = unsafe { HEARTBEAT };
+            let backing_store = backing_store.clone();
+            let exclude = exclude.clone();
+            thread::spawn(move || {
+                follow_peer(
+                    peer,
+                    tls,
+                    auth,
+                    buffer_size,
+                    heartbeat,
+                    backing_store,
+                    exclude,
+                )
+            });
+        }
+    }
+
     // Fs proxying
 
     #[cfg(target_family = "unix")]
     {
         start_fuse(&mount_path, env::args().skip_while(|v| v != "--").skip(1));
+
+        // Unmounted because a replica took over, follow it from here on
+        let new_primary = NEW_PRIMARY.lock().unwrap().take();
+        if let Some((url, last)) = new_primary {
+            let position = trace!(Position::load(&backing_store));
+            position.caught_up(last);
+            trace!(position.persist(&backing_store));
+            info!("Following the new primary {}", url);
+            follow_primary(
+                url,
+                tls,
+                trace!(AuthConfig::from_matches(server_matches)),
+                buffer_size,
+                unsafe { HEARTBEAT },
+                backing_store,
+            );
+        }
         Ok(())
     }
     #[cfg(target_os = "windows")]
@@ -553,7 +1634,7 @@ pub fn server_main(matches: ArgMatches) -> Result<(), Error<io::Error>> {
         let res = unsafe { dokan_main(options, DOKAN_OPS_PTR) };
         match res {
             Ok(DokanResult::Success) => {
-                eprintln!("Dokan exited {:?}", res);
+                info!("Dokan exited {:?}", res);
                 Ok(())
             }
             e => panic!("Dokan error {:?}", e),
@@ -0,0 +1,274 @@
+use bincode::{deserialize, serialize};
+use client::dispatch;
+use common::version::Version;
+use common::*;
+use server::{post_op, send_op, Origin, SERVER_PATH};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const VERSION_XATTR: &str = "user.fsyncer.version\0";
+// Largest version that is read back, a vector has an entry per node
+const MAX_VERSION_SIZE: usize = 4096;
+// Journaled ops whose versions are kept for catching up peers
+const MAX_JOURNALED: usize = 256 * 1024;
+
+lazy_static! {
+    // Op id, the version the op was sent to peers with and whether it came
+    // from a peer, in the order journaled
+    static ref JOURNALED: Mutex<VecDeque<(u64, Version, bool)>> =
+        Mutex::new(VecDeque::new());
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConflictPolicy {
+    // The change made last is kept, the other one is dropped
+    LastWriterWins,
+    // The peer's change goes to a copy next to the path instead
+    KeepBoth,
+    // The peer's change is dropped
+    Reject,
+}
+
+impl ConflictPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "lww" => Some(ConflictPolicy::LastWriterWins),
+            "keep-both" => Some(ConflictPolicy::KeepBoth),
+            "reject" => Some(ConflictPolicy::Reject),
+            _ => None,
+        }
+    }
+}
+
+pub struct PeerConfig {
+    // Name of this node in version vectors, must differ between peers
+    pub node: String,
+    pub policy: ConflictPolicy,
+}
+
+/*
+    Both peers accept writes and replicate them to each other. Every path
+    carries a version, kept in an xattr so it moves along with renames and
+    survives restarts. An op from a peer is applied when its version has
+    seen everything the local one has, otherwise both sides changed the path
+    independently and the conflict policy decides. Either way, once the
+    conflict is resolved the versions are merged, later changes build on it.
+*/
+
+// Ops that don't change what a path holds, they are applied as they are
+fn versioned(call: &VFSCall) -> bool {
+    !(is_variant!(call, VFSCall::mkdir, struct)
+        || is_variant!(call, VFSCall::rmdir, struct)
+        || is_variant!(call, VFSCall::fsync, struct))
+}
+
+fn read_version(path: &Path) -> Version {
+    let cpath = path.to_path_buf().into_cstring();
+    let mut buf = vec![0u8; MAX_VERSION_SIZE];
+    let res = unsafe {
+        libc::lgetxattr(
+            cpath.as_ptr(),
+            VERSION_XATTR.as_ptr() as *const _,
+            buf.as_mut_ptr() as *mut _,
+            buf.len(),
+        )
+    };
+    // Paths that don't exist yet, symlinks and anything written before
+    // peering was enabled have no version
+    if res < 0 {
+        return Version::default();
+    }
+    deserialize(&buf[..res as usize]).unwrap_or_default()
+}
+
+pub fn write_version(path: &Path, version: &Version) {
+    let cpath = path.to_path_buf().into_cstring();
+    let buf = serialize(version).expect("Failed to serialize version");
+    let res = unsafe {
+        libc::lsetxattr(
+            cpath.as_ptr(),
+            VERSION_XATTR.as_ptr() as *const _,
+            buf.as_ptr() as *const _,
+            buf.len(),
+            0,
+        )
+    };
+    if res == -1 {
+        let e = io::Error::last_os_error();
+        // User xattrs aren't allowed on symlinks, and the op may have failed
+        if e.raw_os_error() != Some(libc::EPERM)
+            && e.raw_os_error() != Some(libc::ENOENT)
+        {
+            error!("Failed to store version of {:?} {}", path, e);
+        }
+    }
+}
+
+// The version the op builds on, a rename or link carries the source's along
+fn current(call: &VFSCall, root: &Path) -> Version {
+    let mut version = read_version(&translate_path(call.target(), root));
+    match call {
+        VFSCall::rename { from, .. } | VFSCall::link { from, .. } => {
+            version.merge(&read_version(&translate_path(from, root)))
+        }
+        _ => {}
+    }
+    version
+}
+
+// Version for an op made on this node, and where to store it once applied
+pub fn local_version(
+    config: &PeerConfig,
+    call: &VFSCall,
+) -> (Version, Option<PathBuf>) {
+    let root = unsafe { SERVER_PATH.as_ref().unwrap() };
+    if !versioned(call) {
+        return (Version::default(), None);
+    }
+    (
+        current(call, root).next(&config.node),
+        stored_at(call, root),
+    )
+}
+
+fn stored_at(call: &VFSCall, root: &Path) -> Option<PathBuf> {
+    if is_variant!(call, VFSCall::unlink, struct) {
+        return None;
+    }
+    Some(translate_path(call.target(), root))
+}
+
+fn conflict_path(path: &Path, node: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".conflict-{}", node));
+    path.with_file_name(name)
+}
+
+// What is left of a conflicting op from the peer, if anything
+fn resolve<'a>(
+    config: &PeerConfig,
+    call: &VFSCall<'a>,
+    local: &Version,
+    theirs: &Version,
+    root: &Path,
+) -> Option<VFSCall<'a>> {
+    match config.policy {
+        ConflictPolicy::LastWriterWins if theirs.is_newer_than(local) => {
+            Some(call.clone())
+        }
+        ConflictPolicy::LastWriterWins | ConflictPolicy::Reject => None,
+        ConflictPolicy::KeepBoth => {
+            if is_variant!(call, VFSCall::unlink, struct) {
+                // Nothing to keep of a removal
+                return None;
+            }
+            let copy = conflict_path(call.target(), &theirs.node);
+            let real = translate_path(call.target(), root);
+            let real_copy = translate_path(&copy, root);
+            let replaces = is_variant!(call, VFSCall::rename, struct)
+                || is_variant!(call, VFSCall::link, struct)
+                || is_variant!(call, VFSCall::symlink, struct)
+                || is_variant!(call, VFSCall::mknod, struct)
+                || is_variant!(call, VFSCall::create, struct);
+            // Changes to what is there need a copy of it to apply to
+            if !replaces && !real_copy.exists() {
+                if let Err(e) = fs::copy(&real, &real_copy) {
+                    error!("Failed to copy {:?} for a conflict {}", real, e);
+                    return None;
+                }
+            }
+            let mut call = call.clone();
+            *call.target_mut() = Cow::Owned(copy);
+            Some(call)
+        }
+    }
+}
+
+// Applies an op received from a peer to the backing store
+pub fn apply(config: &PeerConfig, call: &VFSCall, theirs: &Version) -> i32 {
+    let root = unsafe { SERVER_PATH.as_ref().unwrap() };
+    if !versioned(call) {
+        let opref = send_op(call, Origin::Peer, None);
+        return post_op(opref, unsafe { dispatch(call, root) });
+    }
+
+    let mut local = current(call, root);
+    let call = match local.vector.partial_cmp(&theirs.vector) {
+        Some(Ordering::Less) => Cow::Borrowed(call),
+        // Already seen, replayed after reconnecting, nothing to do
+        Some(Ordering::Equal) | Some(Ordering::Greater) => return 0,
+        None => {
+            info!(
+                "Conflict on {:?}, changed here by {} and by {} ({:?})",
+                call.target(),
+                local.node,
+                theirs.node,
+                config.policy
+            );
+            let resolved = resolve(config, call, &local, theirs, root);
+            // Settled either way, later changes on either side build on it
+            local.merge(theirs);
+            if let Some(path) = stored_at(call, root) {
+                write_version(&path, &local);
+            }
+            match resolved {
+                Some(resolved) => Cow::Owned(resolved),
+                None => return 0,
+            }
+        }
+    };
+    local.merge(theirs);
+
+    let version = stored_at(&call, root).map(|path| (path, local));
+    let opref = send_op(&call, Origin::Peer, version);
+    post_op(opref, unsafe { dispatch(&call, root) })
+}
+
+/* A peer catching up from the journal gets each op with the version it had
+ * when it was made, and never one that came from a peer, same as live. The
+ * journal only has the ops, that is kept here, in memory. A peer resuming from
+ * before the oldest one kept has to compare trees again. */
+pub fn journaled(op_id: u64, version: &Version, from_peer: bool) {
+    let mut journaled = JOURNALED.lock().unwrap();
+    if journaled.len() == MAX_JOURNALED {
+        journaled.pop_front();
+    }
+    // send_ops journal in parallel, one may get here before an earlier one
+    let at = journaled
+        .iter()
+        .rposition(|&(id, ..)| id < op_id)
+        .map_or(0, |i| i + 1);
+    journaled.insert(at, (op_id, version.clone(), from_peer));
+}
+
+// Whether every op journaled after op_id is known
+pub fn knows_after(op_id: u64) -> bool {
+    let journaled = JOURNALED.lock().unwrap();
+    journaled
+        .front()
+        .map_or(false, |&(first, ..)| first <= op_id.wrapping_add(1))
+}
+
+// The version op_id is sent to a peer with, None if it came from a peer
+pub fn journaled_version(op_id: u64) -> Result<Option<Version>, io::Error> {
+    let journaled = JOURNALED.lock().unwrap();
+    // Ids are consecutive, being those of the journal
+    let entry = journaled.front().and_then(|&(first, ..)| {
+        journaled.get(op_id.checked_sub(first)? as usize)
+    });
+    match entry {
+        Some(&(id, _, true)) if id == op_id => Ok(None),
+        Some(&(id, ref version, false)) if id == op_id => {
+            Ok(Some(version.clone()))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Version of op {} is no longer kept", op_id),
+        )),
+    }
+}
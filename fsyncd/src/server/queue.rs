@@ -0,0 +1,877 @@
+use bincode::{deserialize, serialize, serialized_size};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use clap::ArgMatches;
+use common::metrics;
+use common::{parse_human_size, FsyncerMsg, Timespec, VFSCall};
+use error::{Error, FromError};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/*
+    Asynchronous clients are sent messages through a SendQueue, the thread
+    making the op only moves the message into it and a sender thread per
+    client writes it out. A slow link or an iolimit then holds up the queue
+    rather than the filesystem, up to the queue's limit, after which the
+    policy decides what gives.
+
+    Ops still in the queue can be merged with or cancelled by later ones,
+    nothing waits on them. Writes following on from the last queued one are
+    merged into it, a chmod replaces the one before it on the same path, a
+    utimens takes in the times the one before it set and it omits, and writes are dropped when their file is unlinked or truncated to
+    nothing. The client only ever sees the outcome, under the later op's id.
+    What is queued on each path is indexed, so finding what an op makes
+    redundant doesn't go through the whole queue.
+*/
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QueuePolicy {
+    // The op waits for the queue to drain
+    Block,
+    // What doesn't fit goes to a file, sent once the queue is through
+    Spill,
+    // The client is dropped, it resumes from the journal when it reconnects
+    Disconnect,
+}
+
+impl QueuePolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "block" => Some(QueuePolicy::Block),
+            "spill" => Some(QueuePolicy::Spill),
+            "disconnect" => Some(QueuePolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+// Writes aren't merged past this size, a replica reads each op whole
+const MAX_MERGED_WRITE: usize = 1024 * 1024;
+
+static mut POLICY: QueuePolicy = QueuePolicy::Block;
+// Bytes a queue holds in memory, 0 for no queue
+static mut LIMIT: usize = 0;
+static mut SPILL_DIR: Option<PathBuf> = None;
+static NEXT_SPILL: AtomicU64 = AtomicU64::new(0);
+
+pub fn configure(matches: &ArgMatches) -> Result<(), Error<io::Error>> {
+    let limit = parse_human_size(matches.value_of("send-queue").unwrap())
+        .expect("Invalid format for send-queue");
+    let policy = matches.value_of("queue-full").unwrap();
+    let policy = QueuePolicy::parse(policy).expect("Invalid queue policy");
+    let spill_dir = matches
+        .value_of("spill-dir")
+        .map_or_else(env::temp_dir, PathBuf::from);
+    if policy == QueuePolicy::Spill && !spill_dir.is_dir() {
+        return Err(trace_err!(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Spill directory {:?} does not exist", spill_dir)
+        )));
+    }
+    unsafe {
+        LIMIT = limit;
+        POLICY = policy;
+        SPILL_DIR = Some(spill_dir);
+    }
+    Ok(())
+}
+
+// Limit of the send queue, None if clients are written to directly
+pub fn limit() -> Option<usize> {
+    match unsafe { LIMIT } {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+pub struct Entry {
+    pub msg: FsyncerMsg<'static>,
+    // The writer is flushed after it
+    pub flush: bool,
+    // Serialized, what counts towards the limit
+    size: usize,
+}
+
+impl Entry {
+    fn new(msg: FsyncerMsg<'static>, flush: bool) -> Result<Self, io::Error> {
+        let size = serialized_size(&msg)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(Entry {
+            msg,
+            flush,
+            size: size as usize,
+        })
+    }
+
+    // The op, if this is one that can be coalesced
+    fn op(&self) -> Option<&VFSCall<'static>> {
+        match self.msg {
+            FsyncerMsg::AsyncOp(ref call, ..) => Some(call),
+            _ => None,
+        }
+    }
+}
+
+// What went over the limit, in the order it was queued
+struct Spill {
+    file: File,
+    written: u64,
+    read: u64,
+}
+
+impl Spill {
+    fn create() -> Result<Self, io::Error> {
+        let dir = unsafe { SPILL_DIR.as_ref().unwrap() };
+        let path = dir.join(format!(
+            "fsyncd-spill-{}-{}",
+            std::process::id(),
+            NEXT_SPILL.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(target_os = "windows")]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            options
+                .custom_flags(winapi::um::winbase::FILE_FLAG_DELETE_ON_CLOSE);
+        }
+        let file = options.open(&path)?;
+        // Nobody else needs to find it, it goes away with the queue
+        #[cfg(target_family = "unix")]
+        std::fs::remove_file(&path)?;
+        Ok(Spill {
+            file,
+            written: 0,
+            read: 0,
+        })
+    }
+
+    fn write(&mut self, entry: &Entry) -> Result<(), io::Error> {
+        let buf = serialize(&entry.msg)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        self.file.seek(SeekFrom::Start(self.written))?;
+        self.file.write_u32::<BigEndian>(buf.len() as u32)?;
+        self.file.write_all(&buf)?;
+        self.file.write_u8(entry.flush as u8)?;
+        self.written += 4 + buf.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Entry, io::Error> {
+        self.file.seek(SeekFrom::Start(self.read))?;
+        let mut buf = vec![0; self.file.read_u32::<BigEndian>()? as usize];
+        self.file.read_exact(&mut buf)?;
+        let flush = self.file.read_u8()? != 0;
+        self.read += 4 + buf.len() as u64 + 1;
+        let msg = deserialize(&buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Entry {
+            msg,
+            flush,
+            size: buf.len(),
+        })
+    }
+}
+
+struct QueueState {
+    // Ones removed from the middle leave None, so the rest keep their place
+    entries: VecDeque<Option<Entry>>,
+    // Sequence number of the first of entries, the rest count up from it
+    first: u64,
+    // Bytes in entries
+    bytes: usize,
+    // What queued ops are on each path
+    index: HashMap<PathBuf, PathIndex>,
+    // Last entry that isn't an op, nothing is coalesced across it
+    barrier: Option<u64>,
+    // Once spilling, everything goes to the file until it is sent
+    spill: Option<Spill>,
+    // Past catching up, ops are made by the filesystem as they are queued
+    live: bool,
+    closed: bool,
+}
+
+// Last entries queued on a path, by sequence number. They may have been
+// sent or removed since.
+#[derive(Default)]
+struct PathIndex {
+    // Any op on the path
+    any: Option<u64>,
+    // Any op on the path other than a write
+    other: Option<u64>,
+    // Any op on something under the path
+    below: Option<u64>,
+    // Writes to the path since other
+    writes: Vec<u64>,
+}
+
+pub struct SendQueue {
+    state: Mutex<QueueState>,
+    cvar: Condvar,
+    limit: usize,
+}
+
+impl SendQueue {
+    pub fn new(limit: usize) -> Self {
+        SendQueue {
+            state: Mutex::new(QueueState {
+                entries: VecDeque::new(),
+                first: 0,
+                bytes: 0,
+                index: HashMap::new(),
+                barrier: None,
+                spill: None,
+                live: false,
+                closed: false,
+            }),
+            cvar: Condvar::new(),
+            limit,
+        }
+    }
+
+    // Catching up from the journal is held up however full the queue is,
+    // the policy only applies after.
+    pub fn caught_up(&self) {
+        self.state.lock().unwrap().live = true;
+    }
+
+    pub fn push(
+        &self,
+        msg: FsyncerMsg<'static>,
+        flush: bool,
+    ) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        let entry = match state.coalesce(msg, flush) {
+            Some(entry) => entry?,
+            None => {
+                self.cvar.notify_all();
+                return Ok(());
+            }
+        };
+        loop {
+            if state.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Client is dead",
+                ));
+            }
+            // Anything fits in an empty queue
+            if state.spill.is_none()
+                && (state.bytes == 0 || state.bytes + entry.size <= self.limit)
+            {
+                state.push_back(entry);
+                self.cvar.notify_all();
+                return Ok(());
+            }
+            let policy = if state.live {
+                unsafe { POLICY }
+            } else {
+                QueuePolicy::Block
+            };
+            match policy {
+                QueuePolicy::Block => {
+                    state = self.cvar.wait(state).unwrap();
+                }
+                QueuePolicy::Spill => {
+                    if state.spill.is_none() {
+                        warn!("Send queue is full, spilling to {:?}", unsafe {
+                            SPILL_DIR.as_ref().unwrap()
+                        });
+                        state.spill = Some(Spill::create()?);
+                    }
+                    state.spill.as_mut().unwrap().write(&entry)?;
+                    self.cvar.notify_all();
+                    return Ok(());
+                }
+                QueuePolicy::Disconnect => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "Send queue is full",
+                    ));
+                }
+            }
+        }
+    }
+
+    // Next message to send, waits for one. None once the queue is closed.
+    pub fn pop(&self) -> Result<Option<Entry>, io::Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return Ok(None);
+            }
+            while let Some(entry) = state.entries.pop_front() {
+                let seq = state.first;
+                state.first += 1;
+                if let Some(entry) = entry {
+                    state.bytes -= entry.size;
+                    state.forget(seq, &entry);
+                    self.cvar.notify_all();
+                    return Ok(Some(entry));
+                }
+            }
+            // The spill only ever has what was queued after the entries
+            if let Some(mut spill) = state.spill.take() {
+                let mut entry = spill.read()?;
+                if spill.read != spill.written {
+                    state.spill = Some(spill);
+                } else {
+                    info!("Send queue is no longer spilling");
+                    entry.flush = true;
+                    self.cvar.notify_all();
+                }
+                return Ok(Some(entry));
+            }
+            state = self.cvar.wait(state).unwrap();
+        }
+    }
+
+    // Whatever is still queued is dropped, writers and the sender return
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.entries.clear();
+        state.bytes = 0;
+        state.index.clear();
+        state.spill = None;
+        self.cvar.notify_all();
+    }
+
+    // Bytes queued but not sent yet, spilled ones included
+    pub fn queued(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.bytes as u64
+            + state.spill.as_ref().map_or(0, |s| s.written - s.read)
+    }
+}
+
+impl QueueState {
+    // Merges msg into what is queued or cancels what it makes redundant.
+    // Returns what is left to queue, None if it was merged.
+    fn coalesce(
+        &mut self,
+        msg: FsyncerMsg<'static>,
+        mut flush: bool,
+    ) -> Option<Result<Entry, io::Error>> {
+        // Spilled ops are out of reach, and have to go first
+        if self.spill.is_some() || self.closed {
+            return Some(Entry::new(msg, flush));
+        }
+        let (mut call, op_id) = match msg {
+            FsyncerMsg::AsyncOp(call, op_id, _) => (call, op_id),
+            msg => return Some(Entry::new(msg, flush)),
+        };
+        let mut merged = None;
+        match *call {
+            VFSCall::write {
+                ref path,
+                offset,
+                ref buf,
+            } => {
+                if let Some(res) = self.merge_write(path, offset, buf, op_id) {
+                    self.last_flushes(flush);
+                    return match res {
+                        Ok(()) => None,
+                        Err(e) => Some(Err(e)),
+                    };
+                }
+            }
+            VFSCall::chmod { ref path, .. } => {
+                flush |= self.supersede(&call, path)
+            }
+            VFSCall::utimens {
+                ref path,
+                ref timespec,
+            } => {
+                if let Some((utimens, flushed)) =
+                    self.merge_utimens(&call, path, timespec)
+                {
+                    merged = Some(utimens);
+                    flush |= flushed;
+                }
+            }
+            VFSCall::truncate { ref path, size: 0 } => {
+                flush |= self.cancel_writes(path, false)
+            }
+            VFSCall::unlink { ref path } if self.live => {
+                flush |= self.cancel_writes(path, true)
+            }
+            _ => {}
+        }
+        if let Some(merged) = merged {
+            call = Cow::Owned(merged);
+        }
+        Some(Entry::new(FsyncerMsg::AsyncOp(call, op_id, 0), flush))
+    }
+
+    // Merges a write into the last one queued if it is to the same file and
+    // the two meet. None if they can't be merged.
+    fn merge_write(
+        &mut self,
+        path: &Path,
+        offset: i64,
+        buf: &[u8],
+        op_id: u64,
+    ) -> Option<Result<(), io::Error>> {
+        let last = self.entries.back_mut()?.as_mut()?;
+        let (last_path, last_offset, last_buf) = match last.op() {
+            Some(VFSCall::write { path, offset, buf }) => (path, *offset, buf),
+            _ => return None,
+        };
+        let start = offset.min(last_offset);
+        let end = (offset + buf.len() as i64)
+            .max(last_offset + last_buf.len() as i64);
+        if **last_path != *path
+            || offset > last_offset + last_buf.len() as i64
+            || offset + (buf.len() as i64) < last_offset
+            || (end - start) as usize > MAX_MERGED_WRITE
+        {
+            return None;
+        }
+        // The later write wins where they overlap
+        let mut merged = vec![0; (end - start) as usize];
+        let at = (last_offset - start) as usize;
+        merged[at..at + last_buf.len()].copy_from_slice(last_buf);
+        let at = (offset - start) as usize;
+        merged[at..at + buf.len()].copy_from_slice(buf);
+        let call = VFSCall::write {
+            path: Cow::Owned(path.to_path_buf()),
+            offset: start,
+            buf: Cow::Owned(merged),
+        };
+        let msg = FsyncerMsg::AsyncOp(Cow::Owned(call), op_id, 0);
+        let entry = match Entry::new(msg, last.flush) {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        self.bytes = self.bytes - last.size + entry.size;
+        *last = entry;
+        metrics::COALESCED_OPS.inc();
+        Some(Ok(()))
+    }
+
+    // A merged op is flushed if either of the two was
+    fn last_flushes(&mut self, flush: bool) {
+        if let Some(Some(last)) = self.entries.back_mut() {
+            last.flush |= flush;
+        }
+    }
+
+    fn push_back(&mut self, entry: Entry) {
+        let seq = self.first + self.entries.len() as u64;
+        match entry.msg {
+            FsyncerMsg::AsyncOp(ref call, ..) => {
+                let write = is_write(call);
+                for path in paths(call) {
+                    let node =
+                        self.index.entry(path.to_path_buf()).or_default();
+                    node.any = Some(seq);
+                    if write {
+                        node.writes.push(seq);
+                    } else {
+                        node.other = Some(seq);
+                        node.writes.clear();
+                    }
+                    for parent in path.ancestors().skip(1) {
+                        let node =
+                            self.index.entry(parent.to_path_buf()).or_default();
+                        node.below = Some(seq);
+                    }
+                }
+            }
+            FsyncerMsg::NOP => {}
+            _ => self.barrier = Some(seq),
+        }
+        self.bytes += entry.size;
+        self.entries.push_back(Some(entry));
+    }
+
+    // The entry was sent, paths nothing queued is on anymore are dropped
+    fn forget(&mut self, seq: u64, entry: &Entry) {
+        let op = match entry.op() {
+            Some(op) => op,
+            None => return,
+        };
+        for path in paths(op) {
+            for path in path.ancestors() {
+                let done = match self.index.get_mut(path) {
+                    Some(node) => {
+                        let sent = node
+                            .writes
+                            .iter()
+                            .take_while(|&&w| w <= seq)
+                            .count();
+                        node.writes.drain(..sent);
+                        node.any.max(node.below) <= Some(seq)
+                    }
+                    None => continue,
+                };
+                if done {
+                    self.index.remove(path);
+                }
+            }
+        }
+    }
+
+    fn get(&self, seq: u64) -> Option<&Entry> {
+        let i = seq.checked_sub(self.first)? as usize;
+        self.entries.get(i)?.as_ref()
+    }
+
+    // Last entry that anything done to path has to stay behind, the last one
+    // that isn't an op or the last op on something above path
+    fn above(&self, path: &Path) -> Option<u64> {
+        path.ancestors()
+            .skip(1)
+            .filter_map(|p| self.index.get(p).and_then(|node| node.any))
+            .max()
+            .max(self.barrier)
+    }
+
+    // Last op on path, if it is of the same kind as call and nothing after
+    // it could depend on it
+    fn last_like(&self, call: &VFSCall, path: &Path) -> Option<u64> {
+        let last = match self.index.get(path) {
+            Some(node) if node.any > node.below => node.any.unwrap(),
+            _ => return None,
+        };
+        if self.above(path) > Some(last) {
+            return None;
+        }
+        match self.get(last).and_then(Entry::op) {
+            Some(op) if op.name() == call.name() && op.target() == path => {
+                Some(last)
+            }
+            _ => None,
+        }
+    }
+
+    // Drops the last op of the same kind on path, if nothing after it could
+    // depend on it. True if its flush is left to the op being queued.
+    fn supersede(&mut self, call: &VFSCall, path: &Path) -> bool {
+        match self.last_like(call, path) {
+            Some(last) => self.remove(last),
+            None => false,
+        }
+    }
+
+    // Drops the last utimens on path like supersede, the times this one
+    // omits are set to what that one had. Returns the utimens to queue in
+    // place of this one and whether the flush is left to it.
+    fn merge_utimens(
+        &mut self,
+        call: &VFSCall,
+        path: &Path,
+        timespec: &[Timespec; 3],
+    ) -> Option<(VFSCall<'static>, bool)> {
+        let last = self.last_like(call, path)?;
+        let mut merged = *timespec;
+        if let Some(VFSCall::utimens { timespec, .. }) =
+            self.get(last).and_then(Entry::op)
+        {
+            for (time, earlier) in merged.iter_mut().zip(timespec.iter()) {
+                if time.omitted() {
+                    *time = *earlier;
+                }
+            }
+        }
+        let flush = self.remove(last);
+        let call = VFSCall::utimens {
+            path: Cow::Owned(path.to_path_buf()),
+            timespec: merged,
+        };
+        Some((call, flush))
+    }
+
+    // Drops the writes to path still queued, back to the last op that did
+    // anything else to it. Once unlinked, the data is gone as well, unless
+    // the file has other links. True if a flush is left to the op being
+    // queued.
+    fn cancel_writes(&mut self, path: &Path, unlinked: bool) -> bool {
+        let writes: Vec<u64> = match self.index.get(path) {
+            Some(node) => {
+                let after = node.other.max(node.below).max(self.above(path));
+                node.writes
+                    .iter()
+                    .cloned()
+                    .filter(|&w| Some(w) > after && self.get(w).is_some())
+                    .collect()
+            }
+            None => return false,
+        };
+        if writes.is_empty() || (unlinked && !only_link(path)) {
+            return false;
+        }
+        let mut flush = false;
+        for seq in writes {
+            flush |= self.remove(seq);
+        }
+        flush
+    }
+
+    // True if the entry was flushed and nothing queued after it takes that
+    fn remove(&mut self, seq: u64) -> bool {
+        let i = (seq - self.first) as usize;
+        let entry = self.entries[i].take().unwrap();
+        self.bytes -= entry.size;
+        metrics::COALESCED_OPS.inc();
+        if !entry.flush {
+            return false;
+        }
+        // Flushes still happen, with what comes after
+        match self.entries.iter_mut().skip(i + 1).flatten().next() {
+            Some(next) => {
+                next.flush = true;
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+fn is_write(call: &VFSCall) -> bool {
+    match call {
+        VFSCall::write { .. }
+        | VFSCall::diff_write { .. }
+        | VFSCall::truncating_write { .. } => true,
+        _ => false,
+    }
+}
+
+// Paths call changes, along with what is under them
+fn paths<'a>(call: &'a VFSCall) -> Vec<&'a Path> {
+    match call {
+        VFSCall::rename { from, .. } | VFSCall::link { from, .. } => {
+            vec![call.target(), &**from]
+        }
+        _ => vec![call.target()],
+    }
+}
+
+// The op is queued before it is made, the backing store still has the file
+#[cfg(target_family = "unix")]
+fn only_link(path: &Path) -> bool {
+    use common::translate_path;
+    use server::SERVER_PATH;
+    use std::os::unix::fs::MetadataExt;
+    let root = unsafe { SERVER_PATH.as_ref().unwrap() };
+    match translate_path(path, root).symlink_metadata() {
+        Ok(meta) => meta.nlink() == 1,
+        Err(_) => false,
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn only_link(_: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> SendQueue {
+        SendQueue::new(1024 * 1024)
+    }
+
+    fn push(queue: &SendQueue, call: VFSCall<'static>, op_id: u64) {
+        let msg = FsyncerMsg::AsyncOp(Cow::Owned(call), op_id, 0);
+        queue.push(msg, false).unwrap();
+    }
+
+    fn path(p: &str) -> Cow<'static, Path> {
+        Cow::Owned(PathBuf::from(p))
+    }
+
+    fn write(p: &str, offset: i64, buf: &[u8]) -> VFSCall<'static> {
+        VFSCall::write {
+            path: path(p),
+            offset,
+            buf: Cow::Owned(buf.to_vec()),
+        }
+    }
+
+    fn chmod(p: &str, mode: u32) -> VFSCall<'static> {
+        VFSCall::chmod {
+            path: path(p),
+            mode,
+        }
+    }
+
+    fn truncate(p: &str) -> VFSCall<'static> {
+        VFSCall::truncate {
+            path: path(p),
+            size: 0,
+        }
+    }
+
+    fn utimens(p: &str, atime: i64, mtime: i64) -> VFSCall<'static> {
+        let time = |nsec| Timespec { high: 0, low: nsec };
+        VFSCall::utimens {
+            path: path(p),
+            timespec: [time(atime), time(mtime), time(0)],
+        }
+    }
+
+    fn rename(from: &str, to: &str) -> VFSCall<'static> {
+        VFSCall::rename {
+            from: path(from),
+            to: path(to),
+            flags: 0,
+        }
+    }
+
+    // Everything queued, as op id and op
+    fn drain(queue: &SendQueue) -> Vec<(u64, VFSCall<'static>)> {
+        let mut ops = Vec::new();
+        while queue.queued() > 0 {
+            match queue.pop().unwrap().unwrap().msg {
+                FsyncerMsg::AsyncOp(call, op_id, _) => {
+                    ops.push((op_id, call.into_owned()))
+                }
+                msg => panic!("Unexpected {:?}", msg),
+            }
+        }
+        ops
+    }
+
+    #[test]
+    fn writes_that_meet_are_merged() {
+        let q = queue();
+        push(&q, write("/f", 2, b"cd"), 1);
+        push(&q, write("/f", 0, b"ab"), 2);
+        push(&q, write("/f", 3, b"XY"), 3);
+        // Leaves a gap
+        push(&q, write("/f", 8, b"z"), 4);
+        push(&q, write("/g", 9, b"z"), 5);
+        assert_eq!(
+            drain(&q),
+            vec![
+                (3, write("/f", 0, b"abcXY")),
+                (4, write("/f", 8, b"z")),
+                (5, write("/g", 9, b"z")),
+            ]
+        );
+    }
+
+    #[test]
+    fn chmod_supersedes_the_last_one() {
+        let q = queue();
+        push(&q, chmod("/f", 0o600), 1);
+        push(&q, chmod("/g", 0o600), 2);
+        push(&q, chmod("/f", 0o644), 3);
+        push(&q, chmod("/f", 0o640), 4);
+        assert_eq!(
+            drain(&q),
+            vec![(2, chmod("/g", 0o600)), (4, chmod("/f", 0o640))]
+        );
+    }
+
+    #[test]
+    fn supersede_stops_at_related_ops() {
+        let q = queue();
+        push(&q, chmod("/d/f", 0o600), 1);
+        push(&q, rename("/d", "/e"), 2);
+        push(&q, chmod("/d/f", 0o644), 3);
+        push(&q, chmod("/e", 0o700), 4);
+        push(&q, chmod("/e/f", 0o600), 5);
+        push(&q, chmod("/e", 0o755), 6);
+        assert_eq!(
+            drain(&q),
+            vec![
+                (1, chmod("/d/f", 0o600)),
+                (2, rename("/d", "/e")),
+                (3, chmod("/d/f", 0o644)),
+                (4, chmod("/e", 0o700)),
+                (5, chmod("/e/f", 0o600)),
+                (6, chmod("/e", 0o755)),
+            ]
+        );
+    }
+
+    #[test]
+    fn utimens_keeps_times_the_later_one_omits() {
+        use libc::UTIME_OMIT;
+        let q = queue();
+        // touch -a, then touch -m
+        push(&q, utimens("/f", 1, UTIME_OMIT), 1);
+        push(&q, utimens("/f", UTIME_OMIT, 2), 2);
+        push(&q, utimens("/g", 3, UTIME_OMIT), 3);
+        push(&q, utimens("/g", 4, 5), 4);
+        assert_eq!(
+            drain(&q),
+            vec![(2, utimens("/f", 1, 2)), (4, utimens("/g", 4, 5))]
+        );
+    }
+
+    #[test]
+    fn nothing_is_coalesced_across_other_messages() {
+        let q = queue();
+        push(&q, chmod("/f", 0o600), 1);
+        q.push(FsyncerMsg::Uncork, false).unwrap();
+        push(&q, chmod("/f", 0o644), 2);
+        assert!(q.pop().unwrap().unwrap().msg != FsyncerMsg::Uncork);
+        assert_eq!(q.pop().unwrap().unwrap().msg, FsyncerMsg::Uncork);
+        assert_eq!(drain(&q), vec![(2, chmod("/f", 0o644))]);
+    }
+
+    #[test]
+    fn truncate_cancels_writes() {
+        let q = queue();
+        push(&q, write("/f", 0, b"a"), 1);
+        push(&q, write("/g", 0, b"b"), 2);
+        push(&q, write("/f", 10, b"c"), 3);
+        push(&q, truncate("/f"), 4);
+        assert_eq!(
+            drain(&q),
+            vec![(2, write("/g", 0, b"b")), (4, truncate("/f"))]
+        );
+    }
+
+    #[test]
+    fn cancel_stops_at_other_ops() {
+        let q = queue();
+        push(&q, write("/f", 0, b"a"), 1);
+        push(&q, chmod("/f", 0o600), 2);
+        push(&q, write("/f", 10, b"b"), 3);
+        push(&q, rename("/f", "/h"), 4);
+        push(&q, write("/f", 20, b"c"), 5);
+        push(&q, truncate("/f"), 6);
+        assert_eq!(
+            drain(&q),
+            vec![
+                (1, write("/f", 0, b"a")),
+                (2, chmod("/f", 0o600)),
+                (3, write("/f", 10, b"b")),
+                (4, rename("/f", "/h")),
+                (6, truncate("/f")),
+            ]
+        );
+    }
+
+    #[test]
+    fn flushes_outlive_what_is_cancelled() {
+        let q = queue();
+        let msg = FsyncerMsg::AsyncOp(Cow::Owned(chmod("/f", 0o600)), 1, 0);
+        q.push(msg, true).unwrap();
+        push(&q, chmod("/f", 0o644), 2);
+        let entry = q.pop().unwrap().unwrap();
+        assert!(entry.flush);
+        assert_eq!(q.queued(), 0);
+    }
+
+    #[test]
+    fn sent_ops_are_not_coalesced() {
+        let q = queue();
+        push(&q, chmod("/f", 0o600), 1);
+        q.pop().unwrap().unwrap();
+        push(&q, chmod("/f", 0o644), 2);
+        assert_eq!(drain(&q), vec![(2, chmod("/f", 0o644))]);
+        assert!(q.state.lock().unwrap().index.is_empty());
+    }
+}
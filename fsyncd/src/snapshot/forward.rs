@@ -700,7 +700,7 @@ impl Snapshot {
                     );
                     //debug!(block.location, new_loc);
                     if new_block.location > block.location {
-                        eprintln!("Entry would move up!");
+                        warn!("Entry would move up!");
                         // Don't let blocks move up
                         Snapshot::deallocate(&mut self.free_list, new_block);
                         merged_blocks.0.insert(offset, block);
@@ -752,7 +752,7 @@ impl Snapshot {
         //debug!(self.files);
 
         for file in self.files.iter() {
-            eprintln!(
+            info!(
                 "{:?} {} blocks",
                 file.0,
                 file.1.data.as_ref().map_or(0, |d| d.0.iter().count())
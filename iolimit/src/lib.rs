@@ -1,12 +1,14 @@
 use std::cmp::{max, min};
 use std::io::{Error, ErrorKind, Result, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 pub struct LimitWriter<W> {
     inner: W,
     bytes_left: usize,
-    bps: usize,
+    bps: Arc<AtomicUsize>,
     last_updated: Instant,
     blocking: bool,
     partial_writes: bool,
@@ -17,7 +19,7 @@ impl<W> LimitWriter<W> {
         LimitWriter {
             inner: writer,
             bytes_left: bps,
-            bps,
+            bps: Arc::new(AtomicUsize::new(bps)),
             last_updated: Instant::now(),
             partial_writes: false,
             blocking: true,
@@ -29,6 +31,10 @@ impl<W> LimitWriter<W> {
     pub fn set_blocking(&mut self, blocking: bool) {
         self.blocking = blocking;
     }
+    // Changes the limit while the writer is in use, 0 for unlimited
+    pub fn limit(&self) -> Arc<AtomicUsize> {
+        self.bps.clone()
+    }
 }
 
 // Guarantees wait
@@ -49,7 +55,8 @@ impl<W: Write> Write for LimitWriter<W> {
         #[allow(clippy::never_loop)]
         // Loop is used as a goto
         loop {
-            if self.bps == 0 {
+            let bps = self.bps.load(Ordering::Relaxed);
+            if bps == 0 {
                 // Unlimited
                 break;
             }
@@ -68,8 +75,8 @@ impl<W: Write> Write for LimitWriter<W> {
             // Check the time
             self.bytes_left += min(
                 now.duration_since(self.last_updated).as_millis() as usize
-                    * (self.bps / 1000),
-                max(buf.len() - self.bytes_left, self.bps),
+                    * (bps / 1000),
+                max(buf.len() - self.bytes_left, bps),
             );
             self.last_updated = now;
 
@@ -81,7 +88,7 @@ impl<W: Write> Write for LimitWriter<W> {
             if self.blocking {
                 // Block for the time neccessary to fit the quota
                 let wait_for = Duration::from_millis(
-                    ((buf.len() - self.bytes_left) / (self.bps / 1000)) as u64,
+                    ((buf.len() - self.bytes_left) / (bps / 1000)) as u64,
                 );
                 self.last_updated = sleep_until(now + wait_for);
                 break;